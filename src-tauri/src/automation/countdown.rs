@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use tokio::sync::RwLock;
+use tokio::task::AbortHandle;
+
+use crate::error::AppError;
+use crate::tuya::{
+    DeviceBackend, DeviceSpecification, FunctionSchema, TuyaDeviceStatus, TuyaValue,
+};
+
+/// Durations offered in the "Turn off in…" submenu.
+pub const COUNTDOWN_MINUTES: [u32; 4] = [15, 30, 60, 120];
+
+pub struct AppTimer {
+    /// Unix seconds.
+    pub ends_at: i64,
+    handle: AbortHandle,
+}
+
+/// Timers the app runs itself for switches without a countdown DP, keyed
+/// `{device}:{code}`. They live outside the menu, so rebuilds don't lose them.
+pub type CountdownTimers = Arc<RwLock<HashMap<String, AppTimer>>>;
+
+pub fn create_countdown_timers() -> CountdownTimers {
+    Arc::new(RwLock::new(HashMap::new()))
+}
+
+pub fn timer_key(device_id: &str, switch_code: &str) -> String {
+    format!("{}:{}", device_id, switch_code)
+}
+
+/// Switch DPs (`switch`, `switch_1`, `switch_led`, ...) get a "Turn off in…" submenu.
+pub fn is_switch(code: &str) -> bool {
+    code.starts_with("switch")
+}
+
+/// The countdown DP paired with a switch: `switch_2` counts down with `countdown_2`.
+/// Single-channel devices often pair `switch` or `switch_led` with `countdown_1`.
+pub fn countdown_code(switch_code: &str, has_code: impl Fn(&str) -> bool) -> Option<String> {
+    let suffix = switch_code.strip_prefix("switch")?;
+    let mut candidates = vec![format!("countdown{}", suffix)];
+    if suffix.is_empty() || suffix == "_led" {
+        candidates.push("countdown_1".to_string());
+        candidates.push("countdown".to_string());
+    }
+    candidates.into_iter().find(|code| has_code(code))
+}
+
+/// The countdown DP to use for a timer of `seconds`, if the device has one that can
+/// hold it. Countdown DPs count in seconds.
+pub fn native_countdown(
+    switch_code: &str,
+    spec: Option<&DeviceSpecification>,
+    statuses: &[TuyaDeviceStatus],
+    seconds: i64,
+) -> Option<String> {
+    let code = countdown_code(switch_code, |code| {
+        spec.is_some_and(|spec| spec.function(code).is_some())
+            || statuses.iter().any(|s| s.code == code)
+    })?;
+    match spec.and_then(|spec| spec.function(&code)) {
+        Some(FunctionSchema::Integer { max, .. }) if *max < seconds => None,
+        Some(FunctionSchema::Integer { .. }) | None => Some(code),
+        Some(_) => None,
+    }
+}
+
+/// Seconds left before a switch turns off, from the device's countdown DP or the
+/// app's own timer. `timers` maps timer keys to their end (Unix seconds).
+pub fn remaining_secs(
+    device_id: &str,
+    switch_code: &str,
+    statuses: &[TuyaDeviceStatus],
+    timers: &HashMap<String, i64>,
+    now: i64,
+) -> Option<i64> {
+    let native = countdown_code(switch_code, |code| statuses.iter().any(|s| s.code == code))
+        .and_then(|code| statuses.iter().find(|s| s.code == code))
+        .and_then(|s| s.value.as_i64())
+        .filter(|secs| *secs > 0);
+    native.or_else(|| {
+        timers
+            .get(&timer_key(device_id, switch_code))
+            .map(|ends_at| ends_at - now)
+            .filter(|secs| *secs > 0)
+    })
+}
+
+pub fn duration_label(minutes: u32) -> String {
+    match minutes {
+        60 => "1 hour".to_string(),
+        m if m % 60 == 0 => format!("{} hours", m / 60),
+        m => format!("{} minutes", m),
+    }
+}
+
+pub fn countdown_label(remaining: Option<i64>) -> String {
+    match remaining {
+        Some(secs) if secs > 0 => {
+            let minutes = (secs + 59) / 60;
+            let left = if minutes >= 60 {
+                format!("{}h {:02}m", minutes / 60, minutes % 60)
+            } else {
+                format!("{}m", minutes)
+            };
+            format!("Turn off in… ({} left)", left)
+        }
+        _ => "Turn off in…".to_string(),
+    }
+}
+
+pub async fn timer_ends(timers: &CountdownTimers) -> HashMap<String, i64> {
+    timers
+        .read()
+        .await
+        .iter()
+        .map(|(key, timer)| (key.clone(), timer.ends_at))
+        .collect()
+}
+
+/// Starts a countdown of `minutes`, or cancels one with 0. `native` is the device's
+/// countdown DP (see `native_countdown`); without one the app turns the switch off
+/// itself when the timer ends. Any running app timer for the switch is replaced.
+pub async fn set_countdown(
+    backend: Arc<dyn DeviceBackend>,
+    timers: &CountdownTimers,
+    device_id: &str,
+    switch_code: &str,
+    native: Option<&str>,
+    minutes: u32,
+) -> Result<(), AppError> {
+    let key = timer_key(device_id, switch_code);
+    if let Some(timer) = timers.write().await.remove(&key) {
+        timer.handle.abort();
+    }
+    let seconds = i64::from(minutes) * 60;
+
+    if let Some(countdown) = native {
+        backend
+            .send_command(device_id, countdown, TuyaValue::Integer(seconds))
+            .await?;
+        return Ok(());
+    }
+    if minutes == 0 {
+        return Ok(());
+    }
+
+    let task = tokio::spawn({
+        let timers = timers.clone();
+        let key = key.clone();
+        let device_id = device_id.to_string();
+        let switch_code = switch_code.to_string();
+        async move {
+            tokio::time::sleep(Duration::from_secs(seconds as u64)).await;
+            timers.write().await.remove(&key);
+
+            match backend
+                .send_command(&device_id, &switch_code, TuyaValue::Boolean(false))
+                .await
+            {
+                Ok(_) => tracing::info!("Timer turned off {}", key),
+                Err(e) => tracing::error!("Timer failed to turn off {}: {}", key, e),
+            }
+        }
+    });
+
+    timers.write().await.insert(
+        key,
+        AppTimer {
+            ends_at: Utc::now().timestamp() + seconds,
+            handle: task.abort_handle(),
+        },
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tuya::backend::fake::status;
+
+    fn countdown_spec(max: i64) -> DeviceSpecification {
+        serde_json::from_value(serde_json::json!({
+            "category": "cz",
+            "functions": [
+                { "code": "switch_1", "type": "Boolean", "values": "{}" },
+                {
+                    "code": "countdown_1",
+                    "type": "Integer",
+                    "values": format!("{{\"min\":0,\"max\":{},\"scale\":0,\"step\":1}}", max)
+                }
+            ],
+            "status": []
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_pairs_switches_with_their_countdown() {
+        let spec = countdown_spec(86400);
+        assert_eq!(
+            native_countdown("switch_1", Some(&spec), &[], 3600),
+            Some("countdown_1".to_string())
+        );
+        assert_eq!(
+            native_countdown("switch", Some(&spec), &[], 3600),
+            Some("countdown_1".to_string())
+        );
+        assert_eq!(native_countdown("switch_2", Some(&spec), &[], 3600), None);
+        // A countdown that can't hold two hours falls back to the app timer
+        assert_eq!(
+            native_countdown("switch_1", Some(&countdown_spec(3600)), &[], 7200),
+            None
+        );
+    }
+
+    #[test]
+    fn test_remaining_time_prefers_the_device() {
+        let statuses = vec![
+            status("switch_1", TuyaValue::Boolean(true)),
+            status("countdown_1", TuyaValue::Integer(900)),
+        ];
+        let timers = HashMap::from([("plug:switch_2".to_string(), 1_000)]);
+
+        assert_eq!(
+            remaining_secs("plug", "switch_1", &statuses, &timers, 0),
+            Some(900)
+        );
+        assert_eq!(
+            remaining_secs("plug", "switch_2", &statuses, &timers, 400),
+            Some(600)
+        );
+        assert_eq!(
+            remaining_secs("plug", "switch_2", &statuses, &timers, 1_000),
+            None
+        );
+    }
+
+    #[test]
+    fn test_labels_show_time_left() {
+        assert_eq!(countdown_label(None), "Turn off in…");
+        assert_eq!(countdown_label(Some(61)), "Turn off in… (2m left)");
+        assert_eq!(countdown_label(Some(3900)), "Turn off in… (1h 05m left)");
+        assert_eq!(duration_label(120), "2 hours");
+    }
+}
@@ -0,0 +1,262 @@
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+use serde::{Deserialize, Serialize};
+
+use crate::error::AppError;
+
+const MONTH_NAMES: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// How far ahead a match is searched for. Five years covers a 29 February rule.
+const SEARCH_DAYS: i64 = 366 * 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Day {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Day {
+    pub fn matches(self, weekday: chrono::Weekday) -> bool {
+        self.num_days_from_sunday() == weekday.num_days_from_sunday()
+    }
+
+    fn num_days_from_sunday(self) -> u32 {
+        match self {
+            Day::Sun => 0,
+            Day::Mon => 1,
+            Day::Tue => 2,
+            Day::Wed => 3,
+            Day::Thu => 4,
+            Day::Fri => 5,
+            Day::Sat => 6,
+        }
+    }
+}
+
+/// A five-field cron expression (minute, hour, day of month, month, day of week),
+/// stored as one bit per allowed value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronSpec {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn bit(set: u64, value: u32) -> bool {
+    set & (1 << value) != 0
+}
+
+fn parse_value(value: &str, min: u32, names: &[&str]) -> Option<u32> {
+    if let Ok(number) = value.parse() {
+        return Some(number);
+    }
+    let lower = value.to_ascii_lowercase();
+    names
+        .iter()
+        .position(|name| *name == lower)
+        .map(|index| index as u32 + min)
+}
+
+/// Parses one field: `*`, values, `a-b` ranges and `/n` steps, separated by commas.
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, AppError> {
+    let invalid = || AppError::Config(format!("Invalid cron field: {}", field));
+    let mut set = 0u64;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(invalid());
+        }
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (
+                parse_value(start, min, names).ok_or_else(invalid)?,
+                parse_value(end, min, names).ok_or_else(invalid)?,
+            )
+        } else {
+            let start = parse_value(range, min, names).ok_or_else(invalid)?;
+            // "5/15" means every 15 starting at 5
+            (start, if part.contains('/') { max } else { start })
+        };
+        if start < min || end > max || start > end {
+            return Err(invalid());
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+impl CronSpec {
+    pub fn parse(expression: &str) -> Result<Self, AppError> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(AppError::Config(format!(
+                "Cron expression needs 5 fields: {}",
+                expression
+            )));
+        };
+
+        let mut weekdays = parse_field(weekday, 0, 7, &DAY_NAMES)?;
+        // Both 0 and 7 mean Sunday
+        if bit(weekdays, 7) {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59, &[])?,
+            hours: parse_field(hour, 0, 23, &[])?,
+            days: parse_field(day, 1, 31, &[])?,
+            months: parse_field(month, 1, 12, &MONTH_NAMES)?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+
+    /// Once a day at `time` on the given days, or every day when `days` is empty.
+    pub fn weekly(days: &[Day], time: NaiveTime) -> Self {
+        let weekdays = if days.is_empty() {
+            0x7f
+        } else {
+            days.iter()
+                .fold(0, |set, day| set | 1 << day.num_days_from_sunday())
+        };
+        Self {
+            minutes: 1 << time.minute(),
+            hours: 1 << time.hour(),
+            days: !0,
+            months: !0,
+            weekdays,
+            any_day: true,
+            any_weekday: days.is_empty(),
+        }
+    }
+
+    /// Follows cron: when both day fields are restricted, either one matching is enough.
+    pub fn matches_date(&self, date: NaiveDate) -> bool {
+        if !bit(self.months, date.month()) {
+            return false;
+        }
+        let day = bit(self.days, date.day());
+        let weekday = bit(self.weekdays, date.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+
+    /// The first matching minute strictly after `after`.
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut time =
+            after.date().and_hms_opt(after.hour(), after.minute(), 0)? + Duration::minutes(1);
+        let limit = after + Duration::days(SEARCH_DAYS);
+
+        while time <= limit {
+            if !self.matches_date(time.date()) {
+                time = time.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !bit(self.hours, time.hour()) {
+                time = time.date().and_hms_opt(time.hour(), 0, 0)? + Duration::hours(1);
+            } else if !bit(self.minutes, time.minute()) {
+                time += Duration::minutes(1);
+            } else {
+                return Some(time);
+            }
+        }
+        None
+    }
+}
+
+/// Parses "HH:MM".
+pub fn parse_time(time: &str) -> Result<NaiveTime, AppError> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|_| AppError::Config(format!("Invalid time (expected HH:MM): {}", time)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn next(expression: &str, after: &str) -> NaiveDateTime {
+        CronSpec::parse(expression)
+            .unwrap()
+            .next_after(at(after))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_finds_next_matching_minute() {
+        assert_eq!(
+            next("*/15 * * * *", "2024-03-01 10:07"),
+            at("2024-03-01 10:15")
+        );
+        assert_eq!(
+            next("30 7 * * *", "2024-03-01 07:30"),
+            at("2024-03-02 07:30")
+        );
+        assert_eq!(
+            next("0 22 * * fri", "2024-03-01 23:00"),
+            at("2024-03-08 22:00")
+        );
+        assert_eq!(
+            next("0 0 29 2 *", "2024-03-01 00:00"),
+            at("2028-02-29 00:00")
+        );
+        assert_eq!(
+            next("0 9 1-7 * 7", "2024-03-02 10:00"),
+            at("2024-03-03 09:00")
+        );
+    }
+
+    #[test]
+    fn test_weekly_rules_match_their_days() {
+        let spec = CronSpec::weekly(&[Day::Mon, Day::Wed], parse_time("06:45").unwrap());
+        // 2024-03-01 is a Friday
+        assert_eq!(
+            spec.next_after(at("2024-03-01 12:00")),
+            Some(at("2024-03-04 06:45"))
+        );
+        assert_eq!(
+            spec.next_after(at("2024-03-04 06:45")),
+            Some(at("2024-03-06 06:45"))
+        );
+    }
+
+    #[test]
+    fn test_rejects_invalid_expressions() {
+        for expression in [
+            "* * * *",
+            "60 * * * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "* * * foo *",
+        ] {
+            assert!(CronSpec::parse(expression).is_err(), "{}", expression);
+        }
+    }
+}
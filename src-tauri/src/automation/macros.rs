@@ -0,0 +1,206 @@
+use std::ops::Range;
+use std::time::Duration;
+
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+
+use crate::tuya::{DeviceBackend, TuyaCommand};
+
+/// A named, ordered list of device commands kept in the app config rather than the
+/// cloud.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Macro {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub steps: Vec<MacroStep>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MacroStep {
+    pub device_id: String,
+    pub command: TuyaCommand,
+    /// Pause before this step. Steps without a delay are sent together with the ones
+    /// before them, in one request per device.
+    #[serde(default)]
+    pub delay_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StepResult {
+    pub index: usize,
+    pub device_id: String,
+    pub code: String,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MacroReport {
+    pub macro_id: String,
+    pub steps: Vec<StepResult>,
+}
+
+impl MacroReport {
+    pub fn failures(&self) -> impl Iterator<Item = &StepResult> {
+        self.steps.iter().filter(|step| step.error.is_some())
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.failures().next().is_none()
+    }
+}
+
+/// Splits the steps into runs that can be sent at once; every delay starts a new run.
+fn batches(steps: &[MacroStep]) -> Vec<Range<usize>> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    for (index, step) in steps.iter().enumerate().skip(1) {
+        if step.delay_ms > 0 {
+            batches.push(start..index);
+            start = index;
+        }
+    }
+    if start < steps.len() {
+        batches.push(start..steps.len());
+    }
+    batches
+}
+
+/// Runs a macro to the end. A failed request doesn't stop later steps; it is recorded
+/// against every step that was part of it.
+pub async fn run_macro(backend: &dyn DeviceBackend, definition: &Macro) -> MacroReport {
+    let mut results: Vec<StepResult> = definition
+        .steps
+        .iter()
+        .enumerate()
+        .map(|(index, step)| StepResult {
+            index,
+            device_id: step.device_id.clone(),
+            code: step.command.code.clone(),
+            error: None,
+        })
+        .collect();
+
+    for batch in batches(&definition.steps) {
+        let delay = definition.steps[batch.start].delay_ms;
+        if delay > 0 {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+
+        // Commands for the same device go out in one request, in step order
+        let mut per_device: Vec<(&str, Vec<usize>)> = Vec::new();
+        for index in batch {
+            let device_id = definition.steps[index].device_id.as_str();
+            match per_device.iter_mut().find(|(id, _)| *id == device_id) {
+                Some((_, indices)) => indices.push(index),
+                None => per_device.push((device_id, vec![index])),
+            }
+        }
+
+        let requests = per_device.iter().map(|(device_id, indices)| {
+            let commands = indices
+                .iter()
+                .map(|&index| definition.steps[index].command.clone())
+                .collect();
+            backend.send_commands(device_id, commands)
+        });
+        let responses = join_all(requests).await;
+
+        for ((device_id, indices), response) in per_device.iter().zip(responses) {
+            let error = match response {
+                Ok(true) => None,
+                Ok(false) => Some("Command was not accepted".to_string()),
+                Err(e) => Some(e.to_string()),
+            };
+            if let Some(ref error) = error {
+                tracing::warn!(
+                    "Macro {} failed on device {}: {}",
+                    definition.id,
+                    device_id,
+                    error
+                );
+            }
+            for &index in indices {
+                results[index].error = error.clone();
+            }
+        }
+    }
+
+    MacroReport {
+        macro_id: definition.id.clone(),
+        steps: results,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tuya::backend::fake::FakeBackend;
+    use crate::tuya::TuyaValue;
+
+    fn step(device_id: &str, code: &str, value: TuyaValue, delay_ms: u64) -> MacroStep {
+        MacroStep {
+            device_id: device_id.to_string(),
+            command: TuyaCommand {
+                code: code.to_string(),
+                value,
+            },
+            delay_ms,
+        }
+    }
+
+    fn desk_on() -> Macro {
+        Macro {
+            id: "desk".to_string(),
+            name: "Desk on".to_string(),
+            steps: vec![
+                step("lamp", "switch_1", TuyaValue::Boolean(true), 0),
+                step("fan", "fan_speed", TuyaValue::String("2".to_string()), 0),
+                step("lamp", "bright_value", TuyaValue::Integer(500), 0),
+                step("ac", "temp_set", TuyaValue::Integer(24), 1),
+            ],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_steps_are_grouped_per_device_between_delays() {
+        let backend = FakeBackend::default();
+
+        let report = run_macro(&backend, &desk_on()).await;
+
+        assert!(report.succeeded());
+        let sent: Vec<(String, Vec<String>)> = backend
+            .sent()
+            .into_iter()
+            .map(|(id, commands)| (id, commands.into_iter().map(|c| c.code).collect()))
+            .collect();
+        assert_eq!(
+            sent,
+            vec![
+                (
+                    "lamp".to_string(),
+                    vec!["switch_1".to_string(), "bright_value".to_string()]
+                ),
+                ("fan".to_string(), vec!["fan_speed".to_string()]),
+                ("ac".to_string(), vec!["temp_set".to_string()]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_reported_per_step() {
+        let mut backend = FakeBackend::default();
+        backend.failing_devices.insert("lamp".to_string());
+
+        let report = run_macro(&backend, &desk_on()).await;
+
+        let failed: Vec<usize> = report.failures().map(|step| step.index).collect();
+        assert_eq!(failed, vec![0, 2]);
+        // The AC step still ran after the lamp failed
+        assert_eq!(backend.sent().len(), 2);
+    }
+}
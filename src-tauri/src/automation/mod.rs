@@ -0,0 +1,16 @@
+pub mod countdown;
+pub mod cron;
+pub mod macros;
+pub mod rules;
+pub mod schedule;
+pub mod solar;
+
+pub use countdown::{create_countdown_timers, CountdownTimers};
+pub use cron::{CronSpec, Day};
+pub use macros::{run_macro, Macro, MacroReport, MacroStep, StepResult};
+pub use rules::{create_rule_engine, Condition, Rule, RuleEngine, RuleFiring, SharedRuleEngine};
+pub use schedule::{
+    run_scheduler, runs_between, upcoming_runs, DeviceZones, Schedule, ScheduleAction,
+    ScheduleTrigger, UpcomingRun,
+};
+pub use solar::{Location, SolarEvent};
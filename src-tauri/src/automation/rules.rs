@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use super::schedule::ScheduleAction;
+use crate::tuya::{DeviceSpecification, TuyaDeviceStatus, TuyaValue};
+
+/// A reaction to a device's status: `action` runs when the condition starts to hold and
+/// `release_action` when it stops, so one rule can turn a dehumidifier on and off.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Rule {
+    pub id: String,
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub device_id: String,
+    pub code: String,
+    pub condition: Condition,
+    pub action: ScheduleAction,
+    #[serde(default)]
+    pub release_action: Option<ScheduleAction>,
+    /// Minimum time between two actions of the rule. A change during the cooldown is
+    /// acted on once it ends, if it still holds.
+    #[serde(default = "default_cooldown_secs")]
+    pub cooldown_secs: i64,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_cooldown_secs() -> i64 {
+    300
+}
+
+/// Thresholds are in display units, so a temperature DP reporting 235 with scale 1 is
+/// compared as 23.5.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Condition {
+    /// Holds once the value rises above `value`, until it drops below
+    /// `value - hysteresis`.
+    Above {
+        value: f64,
+        #[serde(default)]
+        hysteresis: f64,
+    },
+    /// Holds once the value drops below `value`, until it rises above
+    /// `value + hysteresis`.
+    Below {
+        value: f64,
+        #[serde(default)]
+        hysteresis: f64,
+    },
+    Equals {
+        value: TuyaValue,
+    },
+}
+
+impl Condition {
+    /// Whether the condition holds for `current` (`number` is its value in display
+    /// units), given whether it held before. None when the value isn't comparable,
+    /// such as text compared against a threshold.
+    fn holds(&self, current: &TuyaValue, number: Option<f64>, active: bool) -> Option<bool> {
+        match self {
+            Condition::Above { value, hysteresis } => {
+                let current = number?;
+                Some(if active {
+                    current >= value - hysteresis
+                } else {
+                    current > *value
+                })
+            }
+            Condition::Below { value, hysteresis } => {
+                let current = number?;
+                Some(if active {
+                    current <= value + hysteresis
+                } else {
+                    current < *value
+                })
+            }
+            Condition::Equals { value } => Some(current == value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct RuleState {
+    active: bool,
+    last_fired: Option<i64>,
+}
+
+/// A rule whose condition started (`active`) or stopped holding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleFiring {
+    pub rule_id: String,
+    pub active: bool,
+}
+
+impl RuleFiring {
+    pub fn action<'a>(&self, rule: &'a Rule) -> Option<&'a ScheduleAction> {
+        if self.active {
+            Some(&rule.action)
+        } else {
+            rule.release_action.as_ref()
+        }
+    }
+}
+
+/// Remembers which rules currently hold, so actions run on transitions rather than on
+/// every snapshot. A rule starts out not holding, so one that already holds when first
+/// seen fires right away.
+#[derive(Debug, Default)]
+pub struct RuleEngine {
+    states: HashMap<String, RuleState>,
+}
+
+pub type SharedRuleEngine = Arc<Mutex<RuleEngine>>;
+
+pub fn create_rule_engine() -> SharedRuleEngine {
+    Arc::new(Mutex::new(RuleEngine::default()))
+}
+
+impl RuleEngine {
+    /// Checks enabled rules against a status snapshot. Rules whose device or DP is
+    /// missing, for instance while the device is offline, keep their state.
+    pub fn evaluate(
+        &mut self,
+        rules: &[Rule],
+        statuses: &HashMap<String, Vec<TuyaDeviceStatus>>,
+        specs: &HashMap<String, DeviceSpecification>,
+        now: i64,
+    ) -> Vec<RuleFiring> {
+        // Disabling a rule forgets its state, so it starts over when re-enabled
+        self.states
+            .retain(|id, _| rules.iter().any(|r| r.enabled && &r.id == id));
+
+        let mut firings = Vec::new();
+        for rule in rules.iter().filter(|r| r.enabled) {
+            let Some(current) = statuses
+                .get(&rule.device_id)
+                .and_then(|list| list.iter().find(|s| s.code == rule.code))
+            else {
+                continue;
+            };
+            let number = specs
+                .get(&rule.device_id)
+                .and_then(|spec| spec.schema(&rule.code))
+                .and_then(|schema| current.value.as_scaled(schema))
+                .map(|scaled| scaled.value())
+                .or_else(|| current.value.as_f64());
+
+            let state = self.states.entry(rule.id.clone()).or_default();
+            let Some(holds) = rule.condition.holds(&current.value, number, state.active) else {
+                continue;
+            };
+            if holds == state.active {
+                continue;
+            }
+            if state
+                .last_fired
+                .is_some_and(|fired| now - fired < rule.cooldown_secs)
+            {
+                continue;
+            }
+
+            state.active = holds;
+            state.last_fired = Some(now);
+            firings.push(RuleFiring {
+                rule_id: rule.id.clone(),
+                active: holds,
+            });
+        }
+        firings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tuya::backend::fake::status;
+    use crate::tuya::TuyaCommand;
+
+    fn plug(on: bool) -> ScheduleAction {
+        ScheduleAction::Commands {
+            device_id: "plug".to_string(),
+            commands: vec![TuyaCommand {
+                code: "switch_1".to_string(),
+                value: TuyaValue::Boolean(on),
+            }],
+        }
+    }
+
+    fn dehumidify() -> Rule {
+        serde_json::from_value(serde_json::json!({
+            "id": "dehumidify",
+            "name": "Dehumidify",
+            "deviceId": "sensor",
+            "code": "va_humidity",
+            "condition": { "type": "above", "value": 65, "hysteresis": 10 },
+            "action": {
+                "type": "commands",
+                "deviceId": "plug",
+                "commands": [{ "code": "switch_1", "value": true }]
+            },
+            "releaseAction": {
+                "type": "commands",
+                "deviceId": "plug",
+                "commands": [{ "code": "switch_1", "value": false }]
+            },
+            "cooldownSecs": 60
+        }))
+        .unwrap()
+    }
+
+    fn humidity(value: i64) -> HashMap<String, Vec<TuyaDeviceStatus>> {
+        HashMap::from([(
+            "sensor".to_string(),
+            vec![status("va_humidity", TuyaValue::Integer(value))],
+        )])
+    }
+
+    fn run(engine: &mut RuleEngine, rules: &[Rule], value: i64, now: i64) -> Vec<bool> {
+        engine
+            .evaluate(rules, &humidity(value), &HashMap::new(), now)
+            .into_iter()
+            .map(|firing| firing.active)
+            .collect()
+    }
+
+    #[test]
+    fn test_fires_on_transitions_with_hysteresis() {
+        let rules = [dehumidify()];
+        let mut engine = RuleEngine::default();
+
+        assert!(run(&mut engine, &rules, 60, 0).is_empty());
+        assert_eq!(run(&mut engine, &rules, 66, 100), [true]);
+        assert!(run(&mut engine, &rules, 70, 200).is_empty());
+        // Between the thresholds nothing changes in either direction
+        assert!(run(&mut engine, &rules, 58, 300).is_empty());
+        assert_eq!(run(&mut engine, &rules, 54, 400), [false]);
+        assert!(run(&mut engine, &rules, 60, 500).is_empty());
+
+        let firing = RuleFiring {
+            rule_id: "dehumidify".to_string(),
+            active: false,
+        };
+        assert_eq!(firing.action(&rules[0]), Some(&plug(false)));
+    }
+
+    #[test]
+    fn test_cooldown_defers_changes() {
+        let rules = [dehumidify()];
+        let mut engine = RuleEngine::default();
+
+        assert_eq!(run(&mut engine, &rules, 70, 0), [true]);
+        assert!(run(&mut engine, &rules, 50, 30).is_empty());
+        assert_eq!(run(&mut engine, &rules, 50, 60), [false]);
+    }
+
+    #[test]
+    fn test_disabled_rules_start_over() {
+        let mut rules = [dehumidify()];
+        let mut engine = RuleEngine::default();
+
+        assert_eq!(run(&mut engine, &rules, 70, 0), [true]);
+        rules[0].enabled = false;
+        assert!(run(&mut engine, &rules, 70, 100).is_empty());
+        rules[0].enabled = true;
+        assert_eq!(run(&mut engine, &rules, 70, 200), [true]);
+    }
+
+    #[test]
+    fn test_thresholds_use_the_spec_scale() {
+        let rule = Rule {
+            code: "va_temperature".to_string(),
+            condition: Condition::Below {
+                value: 18.0,
+                hysteresis: 1.0,
+            },
+            ..dehumidify()
+        };
+        let spec: DeviceSpecification = serde_json::from_value(serde_json::json!({
+            "category": "wsdcg",
+            "functions": [],
+            "status": [{
+                "code": "va_temperature",
+                "type": "Integer",
+                "values": "{\"unit\":\"℃\",\"min\":-200,\"max\":600,\"scale\":1,\"step\":1}"
+            }]
+        }))
+        .unwrap();
+        let specs = HashMap::from([("sensor".to_string(), spec)]);
+        let statuses = HashMap::from([(
+            "sensor".to_string(),
+            vec![status("va_temperature", TuyaValue::Integer(175))],
+        )]);
+
+        let firings = RuleEngine::default().evaluate(&[rule], &statuses, &specs, 0);
+        assert_eq!(
+            firings,
+            [RuleFiring {
+                rule_id: "dehumidify".to_string(),
+                active: true,
+            }]
+        );
+    }
+}
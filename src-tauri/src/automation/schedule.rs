@@ -0,0 +1,626 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use chrono::{
+    DateTime, Datelike, Duration as ChronoDuration, FixedOffset, Local, Offset, TimeZone, Utc,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::MissedTickBehavior;
+
+use super::cron::{parse_time, CronSpec, Day};
+use super::macros::{run_macro, Macro};
+use super::solar::{self, Location, SolarEvent};
+use crate::config::AppConfig;
+use crate::error::AppError;
+use crate::tuya::{current_client, DeviceBackend, SharedTuyaClient, TuyaCommand, TuyaDevice};
+
+/// How often the scheduler looks for due runs.
+const SCHEDULER_TICK_SECS: u64 = 30;
+
+/// A run this late is still considered on time rather than missed.
+const ON_TIME_SECS: i64 = 120;
+
+/// Device time zones only change when a device is moved, so they are refetched hourly.
+const ZONE_REFRESH_SECS: i64 = 3600;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Schedule {
+    pub id: String,
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub trigger: ScheduleTrigger,
+    pub action: ScheduleAction,
+    /// Runs missed while the computer was asleep are made up once on wake if they are
+    /// at most this old. Zero skips missed runs.
+    #[serde(default = "default_catch_up_minutes")]
+    pub catch_up_minutes: u32,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_catch_up_minutes() -> u32 {
+    60
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum ScheduleTrigger {
+    /// Five-field cron expression.
+    Cron { expression: String },
+    /// Once a day at `time` (HH:MM), on the given days or every day.
+    Weekly {
+        #[serde(default)]
+        days: Vec<Day>,
+        time: String,
+    },
+    /// At a sun event plus `offset_minutes` (negative for before), on the given days or
+    /// every day. Needs a configured location.
+    Solar {
+        event: SolarEvent,
+        #[serde(default)]
+        offset_minutes: i32,
+        #[serde(default)]
+        days: Vec<Day>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum ScheduleAction {
+    Commands {
+        device_id: String,
+        commands: Vec<TuyaCommand>,
+    },
+    Macro {
+        macro_id: String,
+    },
+}
+
+impl ScheduleTrigger {
+    /// The first run strictly after `after`, with the rule read in `zone`.
+    pub fn next_after(
+        &self,
+        after: DateTime<Utc>,
+        zone: FixedOffset,
+        location: Option<Location>,
+    ) -> Result<Option<DateTime<Utc>>, AppError> {
+        let spec = match self {
+            ScheduleTrigger::Cron { expression } => CronSpec::parse(expression)?,
+            ScheduleTrigger::Weekly { days, time } => CronSpec::weekly(days, parse_time(time)?),
+            ScheduleTrigger::Solar {
+                event,
+                offset_minutes,
+                days,
+            } => {
+                let location = location.ok_or_else(|| {
+                    AppError::Config("Sun-relative schedules need a location".to_string())
+                })?;
+                return Ok(next_solar_run(
+                    after,
+                    zone,
+                    location,
+                    *event,
+                    *offset_minutes,
+                    days,
+                ));
+            }
+        };
+
+        let local = after.with_timezone(&zone).naive_local();
+        Ok(spec.next_after(local).and_then(|next| {
+            zone.from_local_datetime(&next)
+                .single()
+                .map(|next| next.with_timezone(&Utc))
+        }))
+    }
+}
+
+/// Walks forward day by day; a year covers every weekday and the end of polar nights.
+fn next_solar_run(
+    after: DateTime<Utc>,
+    zone: FixedOffset,
+    location: Location,
+    event: SolarEvent,
+    offset_minutes: i32,
+    days: &[Day],
+) -> Option<DateTime<Utc>> {
+    // Start a day early: a negative offset can pull tomorrow's run into today
+    let first = after.with_timezone(&zone).date_naive().pred_opt()?;
+    first
+        .iter_days()
+        .take(368)
+        .filter(|date| days.is_empty() || days.iter().any(|day| day.matches(date.weekday())))
+        .filter_map(|date| solar::event_time(date, location, event))
+        .map(|time| time + ChronoDuration::minutes(i64::from(offset_minutes)))
+        .find(|time| *time > after)
+}
+
+impl ScheduleAction {
+    /// The device whose time zone the schedule follows. Macros use their first step's.
+    fn zone_device<'a>(&'a self, macros: &'a [Macro]) -> Option<&'a str> {
+        match self {
+            ScheduleAction::Commands { device_id, .. } => Some(device_id),
+            ScheduleAction::Macro { macro_id } => macros
+                .iter()
+                .find(|m| &m.id == macro_id)
+                .and_then(|m| m.steps.first())
+                .map(|step| step.device_id.as_str()),
+        }
+    }
+}
+
+/// Parses Tuya's "+08:00" style time zones.
+pub fn parse_time_zone(zone: &str) -> Option<FixedOffset> {
+    let (sign, rest) = match zone.as_bytes().first()? {
+        b'+' => (1, &zone[1..]),
+        b'-' => (-1, &zone[1..]),
+        _ => return None,
+    };
+    let (hours, minutes) = rest.split_once(':').unwrap_or((rest, "0"));
+    let seconds = hours.parse::<i32>().ok()? * 3600 + minutes.parse::<i32>().ok()? * 60;
+    FixedOffset::east_opt(sign * seconds)
+}
+
+/// Time zones of the devices schedules refer to, keyed by device id.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceZones {
+    fetched_at: i64,
+    zones: HashMap<String, FixedOffset>,
+}
+
+impl DeviceZones {
+    pub fn from_devices(devices: &[TuyaDevice]) -> Self {
+        Self {
+            fetched_at: Utc::now().timestamp(),
+            zones: devices
+                .iter()
+                .filter_map(|d| Some((d.id.clone(), parse_time_zone(&d.time_zone)?)))
+                .collect(),
+        }
+    }
+
+    pub async fn fetch(backend: &dyn DeviceBackend, user_id: &str) -> Result<Self, AppError> {
+        let devices = backend.list_devices(user_id).await?;
+        Ok(Self::from_devices(&devices))
+    }
+
+    fn is_stale(&self) -> bool {
+        Utc::now().timestamp() - self.fetched_at >= ZONE_REFRESH_SECS
+    }
+
+    /// Unknown devices fall back to the computer's own time zone.
+    pub fn for_schedule(&self, schedule: &Schedule, macros: &[Macro]) -> FixedOffset {
+        schedule
+            .action
+            .zone_device(macros)
+            .and_then(|device_id| self.zones.get(device_id))
+            .copied()
+            .unwrap_or_else(|| Local::now().offset().fix())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DueRun {
+    pub schedule_id: String,
+    pub scheduled_for: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpcomingRun {
+    pub schedule_id: String,
+    pub name: String,
+    /// RFC 3339, in the time zone the schedule is evaluated in.
+    pub at: String,
+}
+
+/// Tracks what has been checked so each occurrence runs at most once.
+pub struct Scheduler {
+    last_check: DateTime<Utc>,
+    reported_invalid: HashSet<String>,
+}
+
+impl Scheduler {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            last_check: now,
+            reported_invalid: HashSet::new(),
+        }
+    }
+
+    /// Moves on without running anything, e.g. while no client is configured.
+    pub fn skip_to(&mut self, now: DateTime<Utc>) {
+        self.last_check = now;
+    }
+
+    /// Schedules that fell due since the last check. A schedule that fell due several
+    /// times (the computer slept through them) runs once, for its latest occurrence,
+    /// and only if that is within its catch-up window.
+    pub fn due(
+        &mut self,
+        schedules: &[Schedule],
+        zone_for: impl Fn(&Schedule) -> FixedOffset,
+        location: Option<Location>,
+        now: DateTime<Utc>,
+    ) -> Vec<DueRun> {
+        let since = std::mem::replace(&mut self.last_check, now);
+        let mut due = Vec::new();
+
+        for schedule in schedules.iter().filter(|s| s.enabled) {
+            let zone = zone_for(schedule);
+            let mut latest = None;
+            let mut after = since;
+            loop {
+                match schedule.trigger.next_after(after, zone, location) {
+                    Ok(Some(next)) if next <= now => {
+                        latest = Some(next);
+                        after = next;
+                    }
+                    Ok(_) => break,
+                    Err(e) => {
+                        if self.reported_invalid.insert(schedule.id.clone()) {
+                            tracing::warn!("Schedule {} is invalid: {}", schedule.id, e);
+                        }
+                        break;
+                    }
+                }
+            }
+
+            let Some(scheduled_for) = latest else {
+                continue;
+            };
+            let late = (now - scheduled_for).num_seconds();
+            if late > ON_TIME_SECS && late > i64::from(schedule.catch_up_minutes) * 60 {
+                tracing::info!(
+                    "Skipping missed run of {} scheduled for {}",
+                    schedule.name,
+                    scheduled_for
+                );
+                continue;
+            }
+            due.push(DueRun {
+                schedule_id: schedule.id.clone(),
+                scheduled_for,
+            });
+        }
+        due
+    }
+}
+
+/// The next `count` runs across all enabled schedules, soonest first.
+pub fn upcoming_runs(
+    schedules: &[Schedule],
+    zone_for: impl Fn(&Schedule) -> FixedOffset,
+    location: Option<Location>,
+    now: DateTime<Utc>,
+    count: usize,
+) -> Vec<UpcomingRun> {
+    let mut runs: Vec<(DateTime<Utc>, UpcomingRun)> = Vec::new();
+
+    for schedule in schedules.iter().filter(|s| s.enabled) {
+        let zone = zone_for(schedule);
+        let mut after = now;
+        for _ in 0..count {
+            let Ok(Some(next)) = schedule.trigger.next_after(after, zone, location) else {
+                break;
+            };
+            runs.push((
+                next,
+                UpcomingRun {
+                    schedule_id: schedule.id.clone(),
+                    name: schedule.name.clone(),
+                    at: next.with_timezone(&zone).to_rfc3339(),
+                },
+            ));
+            after = next;
+        }
+    }
+
+    runs.sort_by_key(|(at, _)| *at);
+    runs.into_iter().take(count).map(|(_, run)| run).collect()
+}
+
+/// Every run of the enabled schedules in `[start, end)`, in order.
+pub fn runs_between(
+    schedules: &[Schedule],
+    zone_for: impl Fn(&Schedule) -> FixedOffset,
+    location: Option<Location>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Vec<(DateTime<Utc>, &Schedule)> {
+    let mut runs = Vec::new();
+    for schedule in schedules.iter().filter(|s| s.enabled) {
+        let zone = zone_for(schedule);
+        let mut after = start - ChronoDuration::seconds(1);
+        while let Ok(Some(next)) = schedule.trigger.next_after(after, zone, location) {
+            if next >= end {
+                break;
+            }
+            runs.push((next, schedule));
+            after = next;
+        }
+    }
+    runs.sort_by_key(|(at, _)| *at);
+    runs
+}
+
+pub async fn run_action(
+    backend: &dyn DeviceBackend,
+    action: &ScheduleAction,
+    macros: &[Macro],
+) -> Result<(), AppError> {
+    match action {
+        ScheduleAction::Commands {
+            device_id,
+            commands,
+        } => match backend.send_commands(device_id, commands.clone()).await? {
+            true => Ok(()),
+            false => Err(AppError::Automation(format!(
+                "Device {} did not accept the commands",
+                device_id
+            ))),
+        },
+        ScheduleAction::Macro { macro_id } => {
+            let definition = macros
+                .iter()
+                .find(|m| &m.id == macro_id)
+                .ok_or_else(|| AppError::Config(format!("Unknown macro: {}", macro_id)))?;
+            let report = run_macro(backend, definition).await;
+            match report.failures().count() {
+                0 => Ok(()),
+                failed => Err(AppError::Automation(format!(
+                    "{} of {} macro step(s) failed",
+                    failed,
+                    report.steps.len()
+                ))),
+            }
+        }
+    }
+}
+
+/// Checks schedules every `SCHEDULER_TICK_SECS` and runs whatever fell due. The config
+/// is reread each tick, so edited schedules take effect without a restart.
+pub async fn run_scheduler<F>(client: SharedTuyaClient, settings: F)
+where
+    F: Fn() -> AppConfig,
+{
+    let mut scheduler = Scheduler::new(Utc::now());
+    let mut zones = DeviceZones::default();
+    let mut interval = tokio::time::interval(Duration::from_secs(SCHEDULER_TICK_SECS));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+        let config = settings();
+        if !config.schedules.iter().any(|s| s.enabled) {
+            scheduler.skip_to(Utc::now());
+            continue;
+        }
+
+        let Some(backend) = current_client(&client).await else {
+            scheduler.skip_to(Utc::now());
+            continue;
+        };
+
+        if zones.is_stale() {
+            match DeviceZones::fetch(backend.as_ref(), &config.user_id).await {
+                Ok(fetched) => zones = fetched,
+                Err(e) => {
+                    tracing::warn!("Failed to fetch device time zones: {}", e);
+                    // Retry on the next refresh rather than every tick
+                    zones.fetched_at = Utc::now().timestamp();
+                }
+            }
+        }
+
+        let due = scheduler.due(
+            &config.schedules,
+            |schedule| zones.for_schedule(schedule, &config.macros),
+            config.location,
+            Utc::now(),
+        );
+        // Each run gets its own task so a long macro doesn't hold up the others or the
+        // next tick
+        for run in due {
+            let Some(schedule) = config.schedules.iter().find(|s| s.id == run.schedule_id) else {
+                continue;
+            };
+            let schedule = schedule.clone();
+            let macros = config.macros.clone();
+            let backend = backend.clone();
+            tokio::spawn(async move {
+                match run_action(backend.as_ref(), &schedule.action, &macros).await {
+                    Ok(()) => {
+                        tracing::info!("Ran schedule {} (due {})", schedule.name, run.scheduled_for)
+                    }
+                    Err(e) => tracing::error!("Schedule {} failed: {}", schedule.name, e),
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tuya::TuyaValue;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn schedule(id: &str, trigger: ScheduleTrigger, catch_up_minutes: u32) -> Schedule {
+        Schedule {
+            id: id.to_string(),
+            name: id.to_string(),
+            enabled: true,
+            trigger,
+            action: ScheduleAction::Commands {
+                device_id: "lamp".to_string(),
+                commands: vec![TuyaCommand {
+                    code: "switch_1".to_string(),
+                    value: TuyaValue::Boolean(true),
+                }],
+            },
+            catch_up_minutes,
+        }
+    }
+
+    fn daily(time: &str) -> ScheduleTrigger {
+        ScheduleTrigger::Weekly {
+            days: Vec::new(),
+            time: time.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parses_tuya_time_zones() {
+        assert_eq!(parse_time_zone("+08:00"), FixedOffset::east_opt(8 * 3600));
+        assert_eq!(
+            parse_time_zone("-05:30"),
+            FixedOffset::east_opt(-(5 * 3600 + 1800))
+        );
+        assert_eq!(parse_time_zone("+00:00"), FixedOffset::east_opt(0));
+        assert_eq!(parse_time_zone("Europe/Berlin"), None);
+    }
+
+    #[test]
+    fn test_runs_are_evaluated_in_the_device_time_zone() {
+        let plus_two = FixedOffset::east_opt(2 * 3600).unwrap();
+        let mut scheduler = Scheduler::new(utc("2024-03-01T04:59:00Z"));
+        let schedules = [schedule("wake", daily("07:00"), 0)];
+
+        let due = scheduler.due(&schedules, |_| plus_two, None, utc("2024-03-01T05:00:30Z"));
+        assert_eq!(
+            due,
+            vec![DueRun {
+                schedule_id: "wake".to_string(),
+                scheduled_for: utc("2024-03-01T05:00:00Z"),
+            }]
+        );
+        // The same occurrence doesn't run twice
+        assert!(scheduler
+            .due(&schedules, |_| plus_two, None, utc("2024-03-01T05:01:00Z"))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_missed_runs_catch_up_once_within_window() {
+        let zone = FixedOffset::east_opt(0).unwrap();
+        let schedules = [
+            schedule(
+                "hourly",
+                ScheduleTrigger::Cron {
+                    expression: "0 * * * *".to_string(),
+                },
+                30,
+            ),
+            schedule("morning", daily("06:00"), 0),
+        ];
+        // Asleep from midnight until 08:10
+        let mut scheduler = Scheduler::new(utc("2024-03-01T00:00:00Z"));
+
+        let due = scheduler.due(&schedules, |_| zone, None, utc("2024-03-01T08:10:00Z"));
+        assert_eq!(
+            due,
+            vec![DueRun {
+                schedule_id: "hourly".to_string(),
+                scheduled_for: utc("2024-03-01T08:00:00Z"),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_upcoming_runs_are_merged_in_order() {
+        let zone = FixedOffset::east_opt(0).unwrap();
+        let schedules = [
+            schedule("a", daily("09:00"), 0),
+            schedule("b", daily("08:00"), 0),
+        ];
+
+        let runs = upcoming_runs(&schedules, |_| zone, None, utc("2024-03-01T07:00:00Z"), 3);
+        let order: Vec<(&str, &str)> = runs
+            .iter()
+            .map(|r| (r.schedule_id.as_str(), r.at.as_str()))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                ("b", "2024-03-01T08:00:00+00:00"),
+                ("a", "2024-03-01T09:00:00+00:00"),
+                ("b", "2024-03-02T08:00:00+00:00"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_solar_runs_follow_the_sun() {
+        let london = Location {
+            latitude: 51.5074,
+            longitude: -0.1278,
+        };
+        let porch = ScheduleTrigger::Solar {
+            event: SolarEvent::Sunset,
+            offset_minutes: -15,
+            days: Vec::new(),
+        };
+        let zone = FixedOffset::east_opt(3600).unwrap();
+
+        // Sunset on 2024-06-21 is around 20:21 UTC
+        let next = porch
+            .next_after(utc("2024-06-21T12:00:00Z"), zone, Some(london))
+            .unwrap()
+            .unwrap();
+        assert!((next - utc("2024-06-21T20:06:00Z")).num_seconds().abs() <= 120);
+
+        let after_sunset = porch
+            .next_after(utc("2024-06-21T20:30:00Z"), zone, Some(london))
+            .unwrap()
+            .unwrap();
+        assert_eq!(after_sunset.date_naive().to_string(), "2024-06-22");
+
+        assert!(porch
+            .next_after(utc("2024-06-21T12:00:00Z"), zone, None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_runs_between_lists_a_day() {
+        let zone = FixedOffset::east_opt(0).unwrap();
+        let schedules = [
+            schedule("evening", daily("21:00"), 0),
+            schedule(
+                "quarter",
+                ScheduleTrigger::Cron {
+                    expression: "0 */6 * * *".to_string(),
+                },
+                0,
+            ),
+        ];
+
+        let runs = runs_between(
+            &schedules,
+            |_| zone,
+            None,
+            utc("2024-03-01T00:00:00Z"),
+            utc("2024-03-02T00:00:00Z"),
+        );
+        let ids: Vec<&str> = runs.iter().map(|(_, s)| s.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            vec!["quarter", "quarter", "quarter", "quarter", "evening"]
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Julian date of 2000-01-01 12:00 UTC.
+const J2000: f64 = 2_451_545.0;
+/// Julian date of the Unix epoch.
+const UNIX_EPOCH_JD: f64 = 2_440_587.5;
+const OBLIQUITY_DEG: f64 = 23.4397;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Location {
+    pub latitude: f64,
+    /// East positive.
+    pub longitude: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SolarEvent {
+    CivilDawn,
+    Sunrise,
+    Sunset,
+    CivilDusk,
+}
+
+impl SolarEvent {
+    pub const ALL: [SolarEvent; 4] = [
+        SolarEvent::CivilDawn,
+        SolarEvent::Sunrise,
+        SolarEvent::Sunset,
+        SolarEvent::CivilDusk,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SolarEvent::CivilDawn => "Dawn",
+            SolarEvent::Sunrise => "Sunrise",
+            SolarEvent::Sunset => "Sunset",
+            SolarEvent::CivilDusk => "Dusk",
+        }
+    }
+
+    /// Sun altitude at the event. Sunrise and sunset allow for refraction and the
+    /// sun's radius; civil twilight ends with the sun six degrees below the horizon.
+    fn altitude_deg(self) -> f64 {
+        match self {
+            SolarEvent::Sunrise | SolarEvent::Sunset => -0.833,
+            SolarEvent::CivilDawn | SolarEvent::CivilDusk => -6.0,
+        }
+    }
+
+    fn is_morning(self) -> bool {
+        matches!(self, SolarEvent::CivilDawn | SolarEvent::Sunrise)
+    }
+}
+
+fn julian_to_utc(julian: f64) -> Option<DateTime<Utc>> {
+    let millis = ((julian - UNIX_EPOCH_JD) * 86_400_000.0).round() as i64;
+    DateTime::from_timestamp_millis(millis)
+}
+
+/// When `event` happens on `date` (the calendar day at the location), using the
+/// sunrise equation. None when the sun doesn't cross that altitude on the day, as in
+/// polar summer or winter. Accurate to a couple of minutes.
+pub fn event_time(date: NaiveDate, location: Location, event: SolarEvent) -> Option<DateTime<Utc>> {
+    let epoch = NaiveDate::from_ymd_opt(2000, 1, 1)?;
+    let day = (date - epoch).num_days() as f64;
+
+    // Mean solar noon at the longitude
+    let mean_noon = day + 0.0009 - location.longitude / 360.0;
+    let anomaly = (357.5291 + 0.985_600_28 * mean_noon)
+        .rem_euclid(360.0)
+        .to_radians();
+    let center =
+        1.9148 * anomaly.sin() + 0.0200 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+    let ecliptic_longitude = (anomaly.to_degrees() + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+    let transit =
+        J2000 + mean_noon + 0.0053 * anomaly.sin() - 0.0069 * (2.0 * ecliptic_longitude).sin();
+
+    let declination = (ecliptic_longitude.sin() * OBLIQUITY_DEG.to_radians().sin()).asin();
+    let latitude = location.latitude.to_radians();
+    let cos_hour_angle = (event.altitude_deg().to_radians().sin()
+        - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+
+    let hour_angle = cos_hour_angle.acos().to_degrees() / 360.0;
+    let julian = if event.is_morning() {
+        transit - hour_angle
+    } else {
+        transit + hour_angle
+    };
+    julian_to_utc(julian)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(actual: Option<DateTime<Utc>>, expected: &str) {
+        let expected = DateTime::parse_from_rfc3339(expected).unwrap();
+        let actual = actual.expect("event should happen");
+        let error = (actual - expected.with_timezone(&Utc)).num_seconds().abs();
+        assert!(error <= 120, "{} is {}s off {}", actual, error, expected);
+    }
+
+    #[test]
+    fn test_london_midsummer() {
+        let london = Location {
+            latitude: 51.5074,
+            longitude: -0.1278,
+        };
+        let date = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+
+        assert_near(
+            event_time(date, london, SolarEvent::Sunrise),
+            "2024-06-21T03:43:00Z",
+        );
+        assert_near(
+            event_time(date, london, SolarEvent::Sunset),
+            "2024-06-21T20:21:00Z",
+        );
+        assert_near(
+            event_time(date, london, SolarEvent::CivilDawn),
+            "2024-06-21T02:55:00Z",
+        );
+        assert_near(
+            event_time(date, london, SolarEvent::CivilDusk),
+            "2024-06-21T21:09:00Z",
+        );
+    }
+
+    #[test]
+    fn test_east_of_greenwich() {
+        let sydney = Location {
+            latitude: -33.8688,
+            longitude: 151.2093,
+        };
+        let date = NaiveDate::from_ymd_opt(2024, 12, 21).unwrap();
+
+        // 05:41 and 20:05 AEDT (UTC+11)
+        assert_near(
+            event_time(date, sydney, SolarEvent::Sunrise),
+            "2024-12-20T18:41:00Z",
+        );
+        assert_near(
+            event_time(date, sydney, SolarEvent::Sunset),
+            "2024-12-21T09:05:00Z",
+        );
+    }
+
+    #[test]
+    fn test_no_sunset_in_polar_summer() {
+        let tromso = Location {
+            latitude: 69.6492,
+            longitude: 18.9553,
+        };
+        let date = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+
+        assert_eq!(event_time(date, tromso, SolarEvent::Sunset), None);
+    }
+}
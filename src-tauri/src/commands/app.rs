@@ -1,6 +1,7 @@
-use tauri::AppHandle;
+use tauri::{AppHandle, State};
 
 use crate::error::CommandResult;
+use crate::tray::{PollState, SharedPollState};
 use crate::update::{self, UpdateInfo};
 
 #[tauri::command]
@@ -8,6 +9,12 @@ pub fn get_version(app: AppHandle) -> String {
     app.package_info().version.to_string()
 }
 
+/// The interval status polling currently runs at, which adapts to activity.
+#[tauri::command]
+pub async fn get_poll_state(poll_state: State<'_, SharedPollState>) -> CommandResult<PollState> {
+    Ok(*poll_state.read().await)
+}
+
 #[tauri::command]
 pub async fn check_for_update(app: AppHandle) -> CommandResult<UpdateInfo> {
     update::check_for_update(&app)
@@ -0,0 +1,75 @@
+use tauri::State;
+
+use crate::automation::{self, DeviceZones, MacroReport, UpcomingRun};
+use crate::config::ConfigManager;
+use crate::error::{AppError, CommandResult, SerializableError};
+use crate::tray::polling;
+use crate::tuya::{current_client, SharedTuyaClient};
+
+#[tauri::command]
+pub async fn run_macro(
+    macro_id: String,
+    client: State<'_, SharedTuyaClient>,
+    config: State<'_, ConfigManager>,
+) -> CommandResult<MacroReport> {
+    let definition = config
+        .get()
+        .macros
+        .into_iter()
+        .find(|m| m.id == macro_id)
+        .ok_or_else(|| {
+            SerializableError::from(AppError::Config(format!("Unknown macro: {}", macro_id)))
+        })?;
+
+    let tuya_client = current_client(&client)
+        .await
+        .ok_or_else(|| SerializableError::from(AppError::NotConfigured))?;
+
+    polling::note_interaction();
+    Ok(automation::run_macro(tuya_client.as_ref(), &definition).await)
+}
+
+/// Turns a rule on or off without resending the rest of the configuration.
+#[tauri::command]
+pub async fn set_rule_enabled(
+    rule_id: String,
+    enabled: bool,
+    config: State<'_, ConfigManager>,
+) -> CommandResult<()> {
+    let mut updated = config.get();
+    let rule = updated
+        .rules
+        .iter_mut()
+        .find(|r| r.id == rule_id)
+        .ok_or_else(|| {
+            SerializableError::from(AppError::Config(format!("Unknown rule: {}", rule_id)))
+        })?;
+    rule.enabled = enabled;
+
+    config.save(&updated).map_err(SerializableError::from)
+}
+
+/// Upcoming schedule runs for the config page, soonest first.
+#[tauri::command]
+pub async fn preview_schedules(
+    count: Option<usize>,
+    client: State<'_, SharedTuyaClient>,
+    config: State<'_, ConfigManager>,
+) -> CommandResult<Vec<UpcomingRun>> {
+    let config = config.get();
+
+    let tuya_client = current_client(&client)
+        .await
+        .ok_or_else(|| SerializableError::from(AppError::NotConfigured))?;
+    let zones = DeviceZones::fetch(tuya_client.as_ref(), &config.user_id)
+        .await
+        .map_err(SerializableError::from)?;
+
+    Ok(automation::upcoming_runs(
+        &config.schedules,
+        |schedule| zones.for_schedule(schedule, &config.macros),
+        config.location,
+        chrono::Utc::now(),
+        count.unwrap_or(10),
+    ))
+}
@@ -1,7 +1,13 @@
+use std::collections::HashMap;
+
 use tauri::State;
 
-use crate::config::{get_available_regions, set_auto_launch, AppConfig, ConfigManager, RegionInfo};
+use crate::config::{
+    get_available_regions, set_auto_launch, AppConfig, ConfigManager, DeviceOverride, RegionInfo,
+};
 use crate::error::{CommandResult, SerializableError};
+use crate::tray::DeviceListCache;
+use crate::tuya::local::DiscoveredDevices;
 use crate::tuya::{initialize_client, SharedTuyaClient};
 
 #[tauri::command]
@@ -9,10 +15,14 @@ pub async fn save_config(
     new_config: AppConfig,
     client: State<'_, SharedTuyaClient>,
     config_manager: State<'_, ConfigManager>,
+    device_list: State<'_, DeviceListCache>,
+    discovered: State<'_, DiscoveredDevices>,
 ) -> CommandResult<()> {
     config_manager
         .save(&new_config)
         .map_err(SerializableError::from)?;
+    // The account may have changed, so the next refresh lists devices again
+    *device_list.write().await = None;
 
     if let Err(e) = set_auto_launch(new_config.run_on_startup) {
         tracing::warn!("Failed to set auto-launch: {}", e);
@@ -21,6 +31,7 @@ pub async fn save_config(
     if new_config.is_configured() {
         initialize_client(
             &client,
+            &discovered,
             new_config.access_key.clone(),
             new_config.secret_key.clone(),
             new_config.base_url.clone(),
@@ -41,6 +52,36 @@ pub fn get_config(config_manager: State<'_, ConfigManager>) -> CommandResult<App
     Ok(config_manager.get())
 }
 
+#[tauri::command]
+pub fn get_device_overrides(
+    config_manager: State<'_, ConfigManager>,
+) -> CommandResult<HashMap<String, DeviceOverride>> {
+    Ok(config_manager.get().device_overrides)
+}
+
+/// Changes how one device appears in the tray. An override that changes nothing is
+/// removed.
+#[tauri::command]
+pub async fn set_device_override(
+    device_id: String,
+    device_override: DeviceOverride,
+    config_manager: State<'_, ConfigManager>,
+    device_list: State<'_, DeviceListCache>,
+) -> CommandResult<()> {
+    let mut updated = config_manager.get();
+    if device_override == DeviceOverride::default() {
+        updated.device_overrides.remove(&device_id);
+    } else {
+        updated.device_overrides.insert(device_id, device_override);
+    }
+    config_manager
+        .save(&updated)
+        .map_err(SerializableError::from)?;
+    // Names and order only change on a full rebuild, which a refetched list forces
+    *device_list.write().await = None;
+    Ok(())
+}
+
 #[tauri::command]
 pub fn is_configured(config_manager: State<'_, ConfigManager>) -> bool {
     config_manager.is_configured()
@@ -1,17 +1,23 @@
+use std::time::Duration;
+
 use tauri::State;
 
 use crate::config::ConfigManager;
 use crate::error::{AppError, CommandResult, SerializableError};
-use crate::tuya::{SharedTuyaClient, TuyaDevice, TuyaDeviceStatus, TuyaValue};
+use crate::tray::{polling, DeviceSpecCache};
+use crate::tuya::local::{self, LocalDeviceInfo};
+use crate::tuya::{
+    current_client, DeviceBackend, DeviceSpecification, FunctionSchema, ScaledValue,
+    SharedTuyaClient, TuyaDevice, TuyaDeviceStatus, TuyaValue,
+};
 
 #[tauri::command]
 pub async fn fetch_devices(
     client: State<'_, SharedTuyaClient>,
     config: State<'_, ConfigManager>,
 ) -> CommandResult<Vec<TuyaDevice>> {
-    let guard = client.read().await;
-    let tuya_client = guard
-        .as_ref()
+    let tuya_client = current_client(&client)
+        .await
         .ok_or_else(|| SerializableError::from(AppError::NotConfigured))?;
 
     let user_id = config.get_user_id().ok_or_else(|| {
@@ -19,23 +25,67 @@ pub async fn fetch_devices(
     })?;
 
     tuya_client
-        .fetch_devices(&user_id)
+        .list_devices(&user_id)
         .await
         .map_err(SerializableError::from)
 }
 
+/// Listens for LAN broadcasts, then refreshes the device list so discovered addresses
+/// and protocol versions are used for local control.
+#[tauri::command]
+pub async fn discover_local_devices(
+    client: State<'_, SharedTuyaClient>,
+    config: State<'_, ConfigManager>,
+) -> CommandResult<Vec<LocalDeviceInfo>> {
+    let tuya_client = current_client(&client)
+        .await
+        .ok_or_else(|| SerializableError::from(AppError::NotConfigured))?;
+
+    let user_id = config.get_user_id().ok_or_else(|| {
+        SerializableError::from(AppError::Config("User ID not configured".to_string()))
+    })?;
+
+    let discovered = local::discover(Duration::from_secs(local::DISCOVERY_WINDOW_SECS))
+        .await
+        .map_err(SerializableError::from)?;
+    tuya_client
+        .local_devices()
+        .merge_discovered(discovered)
+        .await;
+
+    let devices = tuya_client
+        .fetch_devices(&user_id)
+        .await
+        .map_err(SerializableError::from)?;
+    Ok(tuya_client.local_devices().describe(&devices).await)
+}
+
 #[tauri::command]
 pub async fn fetch_device_status(
     device_id: String,
     client: State<'_, SharedTuyaClient>,
 ) -> CommandResult<Vec<TuyaDeviceStatus>> {
-    let guard = client.read().await;
-    let tuya_client = guard
-        .as_ref()
+    let tuya_client = current_client(&client)
+        .await
         .ok_or_else(|| SerializableError::from(AppError::NotConfigured))?;
 
     tuya_client
-        .fetch_device_status(&device_id)
+        .status(&device_id)
+        .await
+        .map_err(SerializableError::from)
+}
+
+#[tauri::command]
+pub async fn fetch_device_specification(
+    device_id: String,
+    client: State<'_, SharedTuyaClient>,
+) -> CommandResult<DeviceSpecification> {
+    let tuya_client = current_client(&client)
+        .await
+        .ok_or_else(|| SerializableError::from(AppError::NotConfigured))?;
+
+    tuya_client
+        .specification(&device_id)
         .await
         .map_err(SerializableError::from)
 }
@@ -46,12 +96,24 @@ pub async fn send_device_command(
     code: String,
     value: serde_json::Value,
     client: State<'_, SharedTuyaClient>,
+    spec_cache: State<'_, DeviceSpecCache>,
 ) -> CommandResult<bool> {
-    let guard = client.read().await;
-    let tuya_client = guard
-        .as_ref()
+    let tuya_client = current_client(&client)
+        .await
         .ok_or_else(|| SerializableError::from(AppError::NotConfigured))?;
 
+    polling::note_interaction();
+    send_value(tuya_client.as_ref(), &spec_cache, &device_id, &code, value).await
+}
+
+/// Converts a value from the frontend to a DP value and sends it.
+pub(crate) async fn send_value(
+    backend: &dyn DeviceBackend,
+    spec_cache: &DeviceSpecCache,
+    device_id: &str,
+    code: &str,
+    value: serde_json::Value,
+) -> CommandResult<bool> {
     let tuya_value = match value {
         serde_json::Value::Bool(b) => TuyaValue::Boolean(b),
         serde_json::Value::String(s) => TuyaValue::String(s),
@@ -68,21 +130,70 @@ pub async fn send_device_command(
                 });
             }
         }
-        _ => {
+        serde_json::Value::Object(_) | serde_json::Value::Array(_) => TuyaValue::Json(value),
+        serde_json::Value::Null => {
             return Err(SerializableError {
                 error_type: "parse".to_string(),
-                message: "Unsupported value type".to_string(),
+                message: "Missing value".to_string(),
                 code: None,
             });
         }
     };
 
-    tuya_client
-        .send_device_command(&device_id, &code, tuya_value)
+    let tuya_value = match tuya_value {
+        TuyaValue::Integer(_) | TuyaValue::Float(_) => {
+            scale_display_value(backend, spec_cache, device_id, code, tuya_value).await
+        }
+        other => other,
+    };
+
+    backend
+        .send_command(device_id, code, tuya_value)
         .await
         .map_err(SerializableError::from)
 }
 
+/// Numbers from the frontend are display values (23.5°C, or 24°C sent as `24`);
+/// integer DPs expect the raw scaled integer, so convert using the DP's spec when one
+/// is available. Whole numbers for unscaled DPs are sent as they are.
+async fn scale_display_value(
+    backend: &dyn DeviceBackend,
+    spec_cache: &DeviceSpecCache,
+    device_id: &str,
+    code: &str,
+    value: TuyaValue,
+) -> TuyaValue {
+    let display = match value {
+        TuyaValue::Integer(i) => i as f64,
+        TuyaValue::Float(f) => f,
+        other => return other,
+    };
+
+    let cached = spec_cache
+        .read()
+        .await
+        .get(device_id)
+        .and_then(|spec| spec.function(code).cloned());
+
+    let schema = match cached {
+        Some(schema) => Some(schema),
+        None => backend
+            .specification(device_id)
+            .await
+            .ok()
+            .and_then(|spec| spec.function(code).cloned()),
+    };
+
+    let scaled = matches!(schema, Some(FunctionSchema::Integer { scale, .. }) if scale > 0);
+    if matches!(value, TuyaValue::Integer(_)) && !scaled {
+        return value;
+    }
+    schema
+        .and_then(|schema| ScaledValue::from_display(display, &schema))
+        .map(|scaled| TuyaValue::Integer(scaled.raw))
+        .unwrap_or(value)
+}
+
 #[tauri::command]
 pub async fn toggle_device_state(
     device_id: String,
@@ -90,13 +201,110 @@ pub async fn toggle_device_state(
     current_value: bool,
     client: State<'_, SharedTuyaClient>,
 ) -> CommandResult<bool> {
-    let guard = client.read().await;
-    let tuya_client = guard
-        .as_ref()
+    let tuya_client = current_client(&client)
+        .await
         .ok_or_else(|| SerializableError::from(AppError::NotConfigured))?;
 
+    polling::note_interaction();
     tuya_client
-        .toggle_device_state(&device_id, &code, current_value)
+        .send_command(&device_id, &code, TuyaValue::Boolean(!current_value))
         .await
         .map_err(SerializableError::from)
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::tray::create_spec_cache;
+    use crate::tuya::backend::fake::FakeBackend;
+
+    fn thermostat_spec() -> DeviceSpecification {
+        serde_json::from_value(json!({
+            "category": "wk",
+            "functions": [{
+                "code": "temp_set",
+                "type": "Integer",
+                "values": "{\"min\":50,\"max\":350,\"scale\":1,\"step\":5,\"unit\":\"℃\"}"
+            }],
+            "status": []
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_display_values_are_scaled_with_the_spec() {
+        let backend = FakeBackend::default().with_spec("dev1", thermostat_spec());
+        let spec_cache = create_spec_cache();
+
+        send_value(&backend, &spec_cache, "dev1", "temp_set", json!(23.5))
+            .await
+            .unwrap();
+
+        let sent = backend.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, "dev1");
+        assert_eq!(sent[0].1[0].value, TuyaValue::Integer(235));
+    }
+
+    #[tokio::test]
+    async fn test_cached_specs_are_not_refetched() {
+        let backend = FakeBackend::default();
+        let spec_cache = create_spec_cache();
+        spec_cache
+            .write()
+            .await
+            .insert("dev1".to_string(), thermostat_spec());
+
+        send_value(&backend, &spec_cache, "dev1", "temp_set", json!(21.0))
+            .await
+            .unwrap();
+
+        assert!(backend.spec_requests.lock().unwrap().is_empty());
+        assert_eq!(backend.sent()[0].1[0].value, TuyaValue::Integer(210));
+    }
+
+    #[tokio::test]
+    async fn test_whole_numbers_are_scaled_too() {
+        let backend = FakeBackend::default().with_spec("dev1", thermostat_spec());
+        let spec_cache = create_spec_cache();
+
+        send_value(&backend, &spec_cache, "dev1", "temp_set", json!(24))
+            .await
+            .unwrap();
+        // Without a scaled spec the number is already raw
+        send_value(&backend, &spec_cache, "dev2", "countdown_1", json!(3600))
+            .await
+            .unwrap();
+
+        let sent = backend.sent();
+        assert_eq!(sent[0].1[0].value, TuyaValue::Integer(240));
+        assert_eq!(sent[1].1[0].value, TuyaValue::Integer(3600));
+    }
+
+    #[tokio::test]
+    async fn test_structured_values_are_sent_and_null_is_rejected() {
+        let backend = FakeBackend::default();
+        let spec_cache = create_spec_cache();
+
+        let colour = json!({ "h": 120, "s": 1000, "v": 1000 });
+        send_value(
+            &backend,
+            &spec_cache,
+            "dev1",
+            "colour_data_v2",
+            colour.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(backend.sent()[0].1[0].value, TuyaValue::Json(colour));
+
+        let err = send_value(&backend, &spec_cache, "dev1", "switch_1", json!(null))
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.error_type, "parse");
+        assert_eq!(backend.sent().len(), 1);
+    }
+}
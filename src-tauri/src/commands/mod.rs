@@ -1,3 +1,4 @@
 pub mod app;
+pub mod automation;
 pub mod config;
 pub mod devices;
@@ -1,9 +1,12 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
 use std::sync::RwLock;
 
+use crate::automation::{Location, Macro, Rule, Schedule};
 use crate::error::AppError;
+use crate::notifications::NotificationConfig;
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
@@ -18,12 +21,73 @@ pub struct AppConfig {
     pub user_id: String,
     #[serde(default = "default_true")]
     pub run_on_startup: bool,
+    /// Subscribe to the cloud project's message queue for instant status updates.
+    #[serde(default)]
+    pub realtime_events: bool,
+    /// Keep offline devices in the menu, greyed out, instead of leaving them out.
+    #[serde(default = "default_true")]
+    pub show_offline_devices: bool,
+    #[serde(default)]
+    pub menu_layout: MenuLayout,
+    /// Keyed by device id.
+    #[serde(default)]
+    pub device_overrides: HashMap<String, DeviceOverride>,
+    /// How often the device list (names, online flags) is refetched; 0 refetches it on
+    /// every poll. Statuses are polled far more often.
+    #[serde(default = "default_device_list_refresh_minutes")]
+    pub device_list_refresh_minutes: u32,
+    #[serde(default)]
+    pub macros: Vec<Macro>,
+    #[serde(default)]
+    pub schedules: Vec<Schedule>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub notifications: NotificationConfig,
+    /// Where sun-relative schedules compute sunrise and sunset for.
+    #[serde(default)]
+    pub location: Option<Location>,
+}
+
+/// How device submenus are arranged in the tray menu.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MenuLayout {
+    #[default]
+    Flat,
+    ByRoom,
+    ByCategory,
+}
+
+/// Local changes to how a device appears in the tray. Devices without one are shown
+/// as the cloud reports them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceOverride {
+    /// Shown instead of the cloud name.
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub hidden: bool,
+    /// Lower orders come first. Devices without one follow in cloud order.
+    #[serde(default)]
+    pub sort_order: Option<i32>,
+    /// Shown at the top of the menu, outside any room or category submenu.
+    #[serde(default)]
+    pub pinned: bool,
+    /// DP codes left out of the device's submenu.
+    #[serde(default)]
+    pub hidden_codes: Vec<String>,
 }
 
 fn default_true() -> bool {
     true
 }
 
+fn default_device_list_refresh_minutes() -> u32 {
+    15
+}
+
 impl AppConfig {
     pub fn is_configured(&self) -> bool {
         !self.base_url.is_empty()
@@ -64,6 +128,11 @@ impl ConfigManager {
         }
     }
 
+    /// Where the last seen devices are kept between runs, next to `config.json`.
+    pub fn cache_path(&self) -> PathBuf {
+        self.config_path.with_file_name("device_cache.json")
+    }
+
     fn load_from_path(path: &PathBuf) -> Option<AppConfig> {
         let content = fs::read_to_string(path).ok()?;
         serde_json::from_str(&content).ok()
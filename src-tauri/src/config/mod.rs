@@ -1,3 +1,6 @@
 pub mod manager;
 
-pub use manager::{get_available_regions, set_auto_launch, AppConfig, ConfigManager, RegionInfo};
+pub use manager::{
+    get_available_regions, set_auto_launch, AppConfig, ConfigManager, DeviceOverride, MenuLayout,
+    RegionInfo,
+};
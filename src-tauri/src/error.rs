@@ -26,6 +26,12 @@ pub enum AppError {
 
     #[error("Tray error: {0}")]
     Tray(String),
+
+    #[error("Local device error: {0}")]
+    Local(String),
+
+    #[error("Automation error: {0}")]
+    Automation(String),
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -1,6 +1,8 @@
+pub mod automation;
 pub mod commands;
 pub mod config;
 pub mod error;
+pub mod notifications;
 pub mod tray;
 pub mod tuya;
 pub mod update;
@@ -4,37 +4,58 @@
 )]
 
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU8, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use chrono::Datelike;
 use tauri::{
-    image::Image, menu::MenuEvent, tray::TrayIconBuilder, AppHandle, Manager, RunEvent, WindowEvent,
+    image::Image,
+    menu::{Menu, MenuEvent},
+    tray::TrayIconBuilder,
+    AppHandle, Manager, RunEvent, WindowEvent, Wry,
 };
 use tauri_plugin_notification::NotificationExt;
 use tokio::sync::{Mutex, RwLock};
 
 use tuya_smart_taskbar::{
+    automation::{self, countdown, CountdownTimers, SharedRuleEngine},
     commands,
     config::{set_auto_launch, ConfigManager},
-    tray::{self, MenuItemRegistry},
+    error::AppError,
+    notifications::{self, create_notifier, SharedNotifier},
+    tray::{
+        self, actions, DeviceCacheFile, DeviceListCache, DeviceSpecCache, DeviceStatusCache,
+        HomeCache, LabelRegistry, MenuItemRegistry, PollOutcome, PollPlanner, RemoteCache,
+        SharedPollState,
+    },
     tuya::{
-        create_shared_client, initialize_client, SharedTuyaClient, TuyaDeviceStatus, TuyaValue,
+        create_shared_client, current_client,
+        events::{self, MessageQueueConfig, QueueEvent},
+        initialize_client, local, DeviceBackend, SharedTuyaClient, TuyaDevice, TuyaDeviceStatus,
     },
     update::{self, create_update_state, SharedUpdateState},
 };
 
 static RUNNING: AtomicBool = AtomicBool::new(false);
-static MENU_INTERACTION_TIME: AtomicI64 = AtomicI64::new(0);
-static UPDATE_CHECK_COUNTER: AtomicU64 = AtomicU64::new(0);
+static REALTIME_CONNECTED: AtomicBool = AtomicBool::new(false);
+/// Layout of the menu currently in the tray, so a changed setting forces a rebuild.
+static APPLIED_LAYOUT: AtomicU8 = AtomicU8::new(u8::MAX);
+/// Day the menu was built for; "Today's Schedule" needs a rebuild after midnight.
+static APPLIED_DAY: AtomicI64 = AtomicI64::new(0);
+/// Whether the tray shows saved devices rather than live ones.
+static APPLIED_SAVED: AtomicBool = AtomicBool::new(false);
 
-type DeviceStatusCache = Arc<RwLock<HashMap<String, Vec<TuyaDeviceStatus>>>>;
 type MenuUpdateLock = Arc<Mutex<()>>;
 
 const ICON_BYTES: &[u8] = include_bytes!("../icons/icon.ico");
 const LOADING_ICON_BYTES: &[u8] = include_bytes!("../icons/loading.ico");
 const UPDATE_ICON_BYTES: &[u8] = include_bytes!("../icons/update.ico");
-const UPDATE_CHECK_INTERVAL: u64 = 360;
+const UPDATE_CHECK_INTERVAL_SECS: u64 = 3600;
+/// While the message queue is connected, polling only reconciles this often.
+const RECONCILE_SECS: u64 = 60;
+/// "Turn off in…" labels count down in minutes, so this keeps them close enough.
+const TIMER_LABEL_REFRESH_SECS: u64 = 30;
 
 async fn update_tray_menu(
     app: &AppHandle,
@@ -43,13 +64,13 @@ async fn update_tray_menu(
     menu_lock: &MenuUpdateLock,
     update_state: &SharedUpdateState,
     menu_registry: &MenuItemRegistry,
-) {
+) -> PollOutcome {
     if is_auto_refresh {
-        let last_interaction = MENU_INTERACTION_TIME.load(Ordering::SeqCst);
+        let last_interaction = tray::polling::last_interaction_ms();
         let now = chrono::Utc::now().timestamp_millis();
         if now - last_interaction < 2000 {
-            tracing::debug!("Skipping auto-refresh: recent menu interaction");
-            return;
+            tracing::debug!("Skipping auto-refresh: recent interaction");
+            return PollOutcome::Skipped;
         }
     }
 
@@ -58,7 +79,7 @@ async fn update_tray_menu(
             Ok(guard) => guard,
             Err(_) => {
                 tracing::debug!("Skipping auto-refresh: menu update in progress");
-                return;
+                return PollOutcome::Skipped;
             }
         }
     } else {
@@ -67,6 +88,13 @@ async fn update_tray_menu(
 
     let config_manager = app.state::<ConfigManager>();
     let client = app.state::<SharedTuyaClient>();
+    let spec_cache = app.state::<DeviceSpecCache>();
+    let home_cache = app.state::<HomeCache>();
+    let remote_cache = app.state::<RemoteCache>();
+    let label_registry = app.state::<LabelRegistry>();
+    let device_cache = app.state::<DeviceCacheFile>();
+    let device_list = app.state::<DeviceListCache>();
+    let timers = countdown::timer_ends(&app.state::<CountdownTimers>()).await;
 
     if !is_auto_refresh {
         if let Some(tray) = app.tray_by_id("main") {
@@ -87,29 +115,161 @@ async fn update_tray_menu(
         if !is_auto_refresh {
             restore_tray_icon(app, update_state).await;
         }
-        return;
+        return PollOutcome::Skipped;
+    }
+
+    // A manual refresh also picks up newly created scenes, remotes and learned keys
+    if !is_auto_refresh {
+        if let Some(data) = home_cache.write().await.as_mut() {
+            data.fetched_at = 0;
+        }
+        if let Some(data) = remote_cache.write().await.as_mut() {
+            data.fetched_at = 0;
+        }
     }
 
-    // Configured path - build device menu (returns 3-tuple)
-    match tray::build_device_menu_with_cache(app, &client, &config_manager, update_state).await {
-        Ok((menu, new_statuses, new_registry_entries)) => {
+    // Newly fetched specifications, changed homes or a new layout need a full rebuild
+    let app_config = config_manager.get();
+    let layout = app_config.menu_layout as u8;
+    let today = chrono::Local::now().date_naive().num_days_from_ce() as i64;
+    let known_specs = spec_cache.read().await.len();
+    let known_homes = home_cache
+        .read()
+        .await
+        .as_ref()
+        .map(|data| data.homes.clone());
+    let known_remotes = remote_cache
+        .read()
+        .await
+        .as_ref()
+        .map(|data| data.remotes.clone());
+    let known_devices = device_list
+        .read()
+        .await
+        .as_ref()
+        .map(|list| list.devices.clone())
+        .unwrap_or_default();
+    // Polling only refetches the device list once it is due; anything else refetches it
+    let list_max_age = if is_auto_refresh {
+        i64::from(app_config.device_list_refresh_minutes) * 60
+    } else {
+        0
+    };
+
+    // Configured path - build device menu (returns 4-tuple). Without the cloud, the
+    // devices seen last are shown instead of an error.
+    let snapshot = match (current_client(&client).await, config_manager.get_user_id()) {
+        (Some(tuya_client), Some(user_id)) => {
+            tray::fetch_menu_snapshot(
+                tuya_client.as_ref(),
+                &user_id,
+                &spec_cache,
+                &home_cache,
+                &remote_cache,
+                &device_list,
+                list_max_age,
+            )
+            .await
+        }
+        (Some(_), None) => Err(AppError::Config("User ID not configured".to_string())),
+        (None, _) => Err(AppError::NotConfigured),
+    };
+    let (built, saved) = match snapshot {
+        Ok(snapshot) => {
+            let homes = home_cache
+                .read()
+                .await
+                .as_ref()
+                .map(|data| data.homes.clone())
+                .unwrap_or_default();
+            let specs = spec_cache.read().await.clone();
+            let now = chrono::Utc::now().timestamp();
+            if let Err(e) = device_cache.store(&snapshot, &specs, &homes, now).await {
+                tracing::warn!("Failed to save device cache: {}", e);
+            }
+            let built = tray::build_device_menu(
+                app,
+                &app_config,
+                update_state,
+                snapshot,
+                &spec_cache,
+                &home_cache,
+                &timers,
+            )
+            .await;
+            (built, false)
+        }
+        Err(e) => match device_cache.get().await {
+            Some(cached) => {
+                tracing::warn!("Failed to fetch devices, showing saved devices: {}", e);
+                let built = tray::build_device_menu(
+                    app,
+                    &app_config,
+                    update_state,
+                    cached.snapshot(),
+                    &cached.spec_cache(),
+                    &cached.home_cache(),
+                    &timers,
+                )
+                .await;
+                (built, true)
+            }
+            None => (Err(e), false),
+        },
+    };
+
+    let outcome = match built {
+        Ok((menu, new_statuses, new_registry_entries, new_labels)) => {
             let old_cache = status_cache.read().await.clone();
+            let new_devices = device_list
+                .read()
+                .await
+                .as_ref()
+                .map(|list| list.devices.clone())
+                .unwrap_or_default();
+            if !saved {
+                notify_device_changes(app, &known_devices, &new_devices, &old_cache, &new_statuses)
+                    .await;
+            }
+            let layout_changed = spec_cache.read().await.len() != known_specs
+                || home_cache.read().await.as_ref().map(|data| &data.homes) != known_homes.as_ref()
+                || remote_cache.read().await.as_ref().map(|data| &data.remotes)
+                    != known_remotes.as_ref()
+                || APPLIED_LAYOUT.swap(layout, Ordering::SeqCst) != layout
+                || APPLIED_DAY.swap(today, Ordering::SeqCst) != today
+                || APPLIED_SAVED.swap(saved, Ordering::SeqCst) != saved;
 
             // Two-path decision
-            if is_auto_refresh
+            let changed = if is_auto_refresh
                 && !old_cache.is_empty()
-                && !tray::is_structural_change(&old_cache, &new_statuses)
-            {
+                && !layout_changed
+                && !tray::is_structural_change(
+                    &known_devices,
+                    &new_devices,
+                    &old_cache,
+                    &new_statuses,
+                ) {
                 // In-place path: only update check states if values differ
-                if old_cache != new_statuses {
+                let changed = old_cache != new_statuses;
+                if changed {
                     let registry = menu_registry.read().await;
-                    let updated =
-                        tray::update_menu_items_in_place(&registry, &old_cache, &new_statuses);
+                    let labels = label_registry.read().await;
+                    let specs = spec_cache.read().await;
+                    let updated = tray::update_menu_items_in_place(
+                        &registry,
+                        &labels,
+                        &specs,
+                        &old_cache,
+                        &new_statuses,
+                    );
                     tracing::debug!("In-place update: {} items changed", updated);
                 }
                 // Update cache only - do NOT set_menu
-                let mut cache = status_cache.write().await;
-                *cache = new_statuses;
+                *status_cache.write().await = new_statuses.clone();
+                if !saved {
+                    run_rules(app, &new_statuses).await;
+                }
+                changed
             } else {
                 // Full rebuild path: set_menu and replace registry
                 if let Some(tray) = app.tray_by_id("main") {
@@ -119,8 +279,18 @@ async fn update_tray_menu(
                     let mut registry = menu_registry.write().await;
                     *registry = new_registry_entries;
                 }
-                let mut cache = status_cache.write().await;
-                *cache = new_statuses;
+                *label_registry.write().await = new_labels;
+                *status_cache.write().await = new_statuses.clone();
+                if !saved {
+                    run_rules(app, &new_statuses).await;
+                }
+                true
+            };
+
+            match (saved, changed) {
+                (true, _) => PollOutcome::Failed,
+                (false, true) => PollOutcome::Changed,
+                (false, false) => PollOutcome::Unchanged,
             }
         }
         Err(e) => {
@@ -134,12 +304,339 @@ async fn update_tray_menu(
             // Clear registry on error
             let mut registry = menu_registry.write().await;
             registry.clear();
+            label_registry.write().await.clear();
+            PollOutcome::Failed
         }
-    }
+    };
 
     if !is_auto_refresh {
         restore_tray_icon(app, update_state).await;
     }
+    outcome
+}
+
+/// Applies a pushed event. Status reports are merged into the cache and shown in place;
+/// online/offline changes alter the device set, which needs a rebuild from the caches.
+async fn handle_queue_event(
+    app: &AppHandle,
+    event: QueueEvent,
+    status_cache: &DeviceStatusCache,
+    menu_lock: &MenuUpdateLock,
+    update_state: &SharedUpdateState,
+    menu_registry: &MenuItemRegistry,
+) {
+    match event {
+        QueueEvent::Connected => REALTIME_CONNECTED.store(true, Ordering::Release),
+        QueueEvent::Disconnected => REALTIME_CONNECTED.store(false, Ordering::Release),
+        QueueEvent::Status { device_id, status } => {
+            let _guard = menu_lock.lock().await;
+            let (old, new, statuses) = {
+                let mut cache = status_cache.write().await;
+                // Devices not in the menu yet are picked up by the next reconciliation
+                let Some(current) = cache.get(&device_id) else {
+                    return;
+                };
+                let merged = events::merge_status(current, &status);
+                if merged == *current {
+                    return;
+                }
+                let old = HashMap::from([(device_id.clone(), current.clone())]);
+                let new = HashMap::from([(device_id.clone(), merged)]);
+                cache.extend(new.clone());
+                (old, new, cache.clone())
+            };
+
+            let updated = {
+                let registry = menu_registry.read().await;
+                let label_registry = app.state::<LabelRegistry>();
+                let labels = label_registry.read().await;
+                let spec_cache = app.state::<DeviceSpecCache>();
+                let specs = spec_cache.read().await;
+                tray::update_menu_items_in_place(&registry, &labels, &specs, &old, &new)
+            };
+            tracing::debug!("Pushed status for {}: {} items changed", device_id, updated);
+            let devices = app
+                .state::<DeviceListCache>()
+                .read()
+                .await
+                .as_ref()
+                .map(|list| list.devices.clone())
+                .unwrap_or_default();
+            notify_device_changes(app, &devices, &devices, &old, &new).await;
+            run_rules(app, &statuses).await;
+        }
+        QueueEvent::Online { device_id } => {
+            apply_online_change(
+                app,
+                &device_id,
+                true,
+                status_cache,
+                menu_lock,
+                update_state,
+                menu_registry,
+            )
+            .await;
+        }
+        QueueEvent::Offline { device_id } => {
+            apply_online_change(
+                app,
+                &device_id,
+                false,
+                status_cache,
+                menu_lock,
+                update_state,
+                menu_registry,
+            )
+            .await;
+        }
+    }
+}
+
+/// Follows a device going offline or coming back by flipping it in the cached list and
+/// rebuilding the menu from the caches. Only a device that came back is asked for its
+/// status; the list and everything else are left to the next poll.
+async fn apply_online_change(
+    app: &AppHandle,
+    device_id: &str,
+    online: bool,
+    status_cache: &DeviceStatusCache,
+    menu_lock: &MenuUpdateLock,
+    update_state: &SharedUpdateState,
+    menu_registry: &MenuItemRegistry,
+) {
+    let _guard = menu_lock.lock().await;
+    let device_list = app.state::<DeviceListCache>();
+    let old_devices = device_list
+        .read()
+        .await
+        .as_ref()
+        .map(|list| list.devices.clone())
+        .unwrap_or_default();
+    let now = chrono::Utc::now().timestamp();
+    if !tray::set_device_online(&device_list, device_id, online, now).await {
+        return;
+    }
+    tracing::info!(
+        "Device {} went {}, rebuilding menu",
+        device_id,
+        if online { "online" } else { "offline" }
+    );
+
+    let Some(tuya_client) = current_client(&app.state::<SharedTuyaClient>()).await else {
+        return;
+    };
+    let spec_cache = app.state::<DeviceSpecCache>();
+    let Some(snapshot) = tray::cached_snapshot(
+        tuya_client.as_ref(),
+        &device_list,
+        status_cache,
+        &spec_cache,
+        &app.state::<RemoteCache>(),
+    )
+    .await
+    else {
+        return;
+    };
+    let new_devices = snapshot.devices.clone();
+    let timers = countdown::timer_ends(&app.state::<CountdownTimers>()).await;
+
+    let built = tray::build_device_menu(
+        app,
+        &app.state::<ConfigManager>().get(),
+        update_state,
+        snapshot,
+        &spec_cache,
+        &app.state::<HomeCache>(),
+        &timers,
+    )
+    .await;
+    let (menu, new_statuses, registry, labels) = match built {
+        Ok(built) => built,
+        Err(e) => {
+            tracing::error!("Error rebuilding device menu: {}", e);
+            return;
+        }
+    };
+
+    if let Some(tray) = app.tray_by_id("main") {
+        let _ = tray.set_menu(Some(menu));
+    }
+    *menu_registry.write().await = registry;
+    *app.state::<LabelRegistry>().write().await = labels;
+    let old_statuses = std::mem::replace(&mut *status_cache.write().await, new_statuses.clone());
+    notify_device_changes(
+        app,
+        &old_devices,
+        &new_devices,
+        &old_statuses,
+        &new_statuses,
+    )
+    .await;
+    run_rules(app, &new_statuses).await;
+}
+
+/// The menu from the devices saved by the last run, so the tray is usable before the
+/// first cloud round trip finishes. None when unconfigured or nothing was saved.
+async fn build_saved_menu(
+    app: &AppHandle,
+    status_cache: &DeviceStatusCache,
+    update_state: &SharedUpdateState,
+    menu_registry: &MenuItemRegistry,
+) -> Option<Menu<Wry>> {
+    let config_manager = app.state::<ConfigManager>();
+    if !config_manager.is_configured() {
+        return None;
+    }
+    let cached = app.state::<DeviceCacheFile>().get().await?;
+    let timers = countdown::timer_ends(&app.state::<CountdownTimers>()).await;
+
+    match tray::build_device_menu(
+        app,
+        &config_manager.get(),
+        update_state,
+        cached.snapshot(),
+        &cached.spec_cache(),
+        &cached.home_cache(),
+        &timers,
+    )
+    .await
+    {
+        Ok((menu, statuses, registry, labels)) => {
+            *status_cache.write().await = statuses;
+            *menu_registry.write().await = registry;
+            *app.state::<LabelRegistry>().write().await = labels;
+            APPLIED_SAVED.store(true, Ordering::SeqCst);
+            Some(menu)
+        }
+        Err(e) => {
+            tracing::warn!("Failed to build menu from saved devices: {}", e);
+            None
+        }
+    }
+}
+
+/// Shows the notifications configured for the changes between two snapshots.
+async fn notify_device_changes(
+    app: &AppHandle,
+    old_devices: &[TuyaDevice],
+    new_devices: &[TuyaDevice],
+    old: &HashMap<String, Vec<TuyaDeviceStatus>>,
+    new: &HashMap<String, Vec<TuyaDeviceStatus>>,
+) {
+    let config = app.state::<ConfigManager>().get().notifications;
+    if config.devices.is_empty() {
+        return;
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let found = {
+        let spec_cache = app.state::<DeviceSpecCache>();
+        let specs = spec_cache.read().await;
+        let client = app.state::<SharedTuyaClient>();
+        let guard = client.read().await;
+        notifications::device_notifications(
+            &config,
+            old_devices,
+            new_devices,
+            old,
+            new,
+            &specs,
+            |device_id, code| {
+                guard
+                    .as_ref()
+                    .and_then(|c| c.last_command_at(device_id, code))
+                    .is_some_and(|sent| now - sent < notifications::OWN_CHANGE_SECS)
+            },
+        )
+    };
+    if found.is_empty() {
+        return;
+    }
+
+    let admitted = app.state::<SharedNotifier>().lock().await.admit(
+        &config,
+        found,
+        now,
+        chrono::Local::now().time(),
+    );
+    for notification in admitted {
+        tracing::info!("Device notification: {}", notification.title);
+        if let Err(e) = app
+            .notification()
+            .builder()
+            .title(&notification.title)
+            .body(&notification.body)
+            .show()
+        {
+            tracing::error!("Failed to send notification: {}", e);
+        }
+    }
+}
+
+/// Checks rules against the latest statuses and runs the actions of those that changed.
+/// Each action runs in its own background task so the menu isn't held up by slow
+/// devices. Callers pass a copy of the statuses rather than holding the cache lock.
+async fn run_rules(app: &AppHandle, statuses: &HashMap<String, Vec<TuyaDeviceStatus>>) {
+    let config = app.state::<ConfigManager>().get();
+    if config.rules.is_empty() {
+        return;
+    }
+
+    let firings = {
+        let spec_cache = app.state::<DeviceSpecCache>();
+        let specs = spec_cache.read().await;
+        let engine = app.state::<SharedRuleEngine>();
+        let mut engine = engine.lock().await;
+        engine.evaluate(
+            &config.rules,
+            statuses,
+            &specs,
+            chrono::Utc::now().timestamp(),
+        )
+    };
+    if firings.is_empty() {
+        return;
+    }
+
+    let Some(tuya_client) = current_client(&app.state::<SharedTuyaClient>()).await else {
+        tracing::error!("Client not initialized");
+        return;
+    };
+    let macros = Arc::new(config.macros);
+    for firing in firings {
+        let Some(rule) = config.rules.iter().find(|r| r.id == firing.rule_id) else {
+            continue;
+        };
+        let Some(action) = firing.action(rule).cloned() else {
+            continue;
+        };
+        let tuya_client = tuya_client.clone();
+        let macros = macros.clone();
+        let name = rule.name.clone();
+        tauri::async_runtime::spawn(async move {
+            match automation::schedule::run_action(tuya_client.as_ref(), &action, &macros).await {
+                Ok(()) => tracing::info!(
+                    "Rule {} {}",
+                    name,
+                    if firing.active {
+                        "triggered"
+                    } else {
+                        "released"
+                    }
+                ),
+                Err(e) => tracing::error!("Rule {} failed: {}", name, e),
+            }
+        });
+    }
+}
+
+/// Keeps "Turn off in…" labels counting down between menu refreshes.
+async fn refresh_timer_labels(app: &AppHandle, status_cache: &DeviceStatusCache) {
+    let timers = countdown::timer_ends(&app.state::<CountdownTimers>()).await;
+    let statuses = status_cache.read().await;
+    let label_registry = app.state::<LabelRegistry>();
+    let labels = label_registry.read().await;
+    tray::refresh_countdown_labels(&labels, &statuses, &timers, chrono::Utc::now().timestamp());
 }
 
 async fn restore_tray_icon(app: &AppHandle, update_state: &SharedUpdateState) {
@@ -245,7 +742,7 @@ fn handle_menu_event(
     update_state: SharedUpdateState,
     menu_registry: MenuItemRegistry,
 ) {
-    MENU_INTERACTION_TIME.store(chrono::Utc::now().timestamp_millis(), Ordering::SeqCst);
+    tray::polling::note_interaction();
 
     let id = event.id().as_ref();
     tracing::debug!("Menu event: {}", id);
@@ -282,57 +779,193 @@ fn handle_menu_event(
                 let registry = menu_registry.clone();
 
                 tauri::async_runtime::spawn(async move {
-                    // Look up current boolean value from cache
-                    let current = {
-                        let cache_guard = cache.read().await;
-                        cache_guard
-                            .get(&device_id)
-                            .and_then(|statuses| {
-                                statuses
-                                    .iter()
-                                    .find(|s| s.code == code)
-                                    .and_then(|s| s.value.as_bool())
-                            })
-                            .unwrap_or(false)
+                    let Some(tuya_client) =
+                        current_client(&app_handle.state::<SharedTuyaClient>()).await
+                    else {
+                        tracing::error!("Client not initialized");
+                        return;
                     };
 
-                    let result = {
-                        let client = app_handle.state::<SharedTuyaClient>();
-                        let guard = client.read().await;
-                        if let Some(tuya_client) = guard.as_ref() {
-                            Some(
-                                tuya_client
-                                    .toggle_device_state(&device_id, &code, current)
-                                    .await,
-                            )
-                        } else {
-                            None
-                        }
-                    };
-
-                    match result {
-                        Some(Ok(_)) => {
-                            tracing::info!("Toggled {}:{} (was {})", device_id, code, current);
-                            // Immediate in-place feedback: update check mark and cache
+                    match actions::toggle(tuya_client.as_ref(), &cache, &device_id, &code).await {
+                        Ok(on) => {
+                            tracing::info!("Toggled {}:{} to {}", device_id, code, on);
+                            // Immediate in-place feedback; the cache is already updated
                             let reg = registry.read().await;
                             let key = format!("{}:{}", device_id, code);
                             if let Some(item) = reg.get(&key) {
-                                let _ = item.set_checked(!current);
-                            }
-                            drop(reg);
-                            // Update cache to reflect new state
-                            let mut cache_guard = cache.write().await;
-                            if let Some(statuses) = cache_guard.get_mut(&device_id) {
-                                if let Some(s) = statuses.iter_mut().find(|s| s.code == code) {
-                                    s.value = TuyaValue::Boolean(!current);
-                                }
+                                let _ = item.set_checked(on);
                             }
                         }
-                        Some(Err(e)) => {
+                        Err(e) => {
                             tracing::error!("Failed to toggle: {}", e);
                         }
-                        None => {
-                            tracing::error!("Client not initialized");
+                    }
+                });
+            }
+        }
+        _ if id.starts_with("ir:") => {
+            if let Some((remote_id, field, value)) = tray::parse_remote_id(id) {
+                let app_handle = app.clone();
+
+                tauri::async_runtime::spawn(async move {
+                    let Some(tuya_client) =
+                        current_client(&app_handle.state::<SharedTuyaClient>()).await
+                    else {
+                        tracing::error!("Client not initialized");
+                        return;
+                    };
+
+                    let result = actions::send_remote(
+                        tuya_client.as_ref(),
+                        &app_handle.state::<RemoteCache>(),
+                        &remote_id,
+                        &field,
+                        &value,
+                    )
+                    .await;
+                    match result {
+                        Ok(true) => {
+                            tracing::info!(
+                                "Remote command sent: {}:{}:{}",
+                                remote_id,
+                                field,
+                                value
+                            );
+                        }
+                        Ok(false) => {}
+                        Err(e) => {
+                            tracing::error!("Failed to send remote command: {}", e);
+                        }
+                    }
+                });
+            }
+        }
+        _ if id.starts_with("colour:") => {
+            if let Some((device_id, code, hue)) = tray::parse_colour_id(id) {
+                let app_handle = app.clone();
+                let cache = status_cache.clone();
+
+                tauri::async_runtime::spawn(async move {
+                    let Some(tuya_client) =
+                        current_client(&app_handle.state::<SharedTuyaClient>()).await
+                    else {
+                        tracing::error!("Client not initialized");
+                        return;
+                    };
+
+                    match actions::set_colour(tuya_client.as_ref(), &cache, &device_id, &code, hue)
+                        .await
+                    {
+                        Ok(()) => {
+                            tracing::info!("Colour set: {}:{} to hue {}", device_id, code, hue);
+                        }
+                        Err(e) => {
+                            tracing::error!("Failed to set colour: {}", e);
+                        }
+                    }
+                });
+            }
+        }
+        _ if id.starts_with("scene:") => {
+            if let Some((home_id, scene_id)) = tray::parse_scene_id(id) {
+                let app_handle = app.clone();
+
+                tauri::async_runtime::spawn(async move {
+                    let Some(tuya_client) =
+                        current_client(&app_handle.state::<SharedTuyaClient>()).await
+                    else {
+                        tracing::error!("Client not initialized");
+                        return;
+                    };
+                    let backend: &dyn DeviceBackend = tuya_client.as_ref();
+
+                    match backend.trigger_scene(home_id, &scene_id).await {
+                        Ok(_) => {
+                            tracing::info!("Scene triggered: {}:{}", home_id, scene_id);
+                        }
+                        Err(e) => {
+                            tracing::error!("Failed to trigger scene: {}", e);
+                        }
+                    }
+                });
+            }
+        }
+        _ if id.starts_with("macro:") => {
+            if let Some(macro_id) = tray::parse_macro_id(id) {
+                let app_handle = app.clone();
+                let macro_id = macro_id.to_string();
+
+                tauri::async_runtime::spawn(async move {
+                    let config_manager = app_handle.state::<ConfigManager>();
+                    let Some(definition) = config_manager
+                        .get()
+                        .macros
+                        .into_iter()
+                        .find(|m| m.id == macro_id)
+                    else {
+                        tracing::error!("Unknown macro: {}", macro_id);
+                        return;
+                    };
+
+                    let Some(tuya_client) =
+                        current_client(&app_handle.state::<SharedTuyaClient>()).await
+                    else {
+                        tracing::error!("Client not initialized");
+                        return;
+                    };
+                    let report = automation::run_macro(tuya_client.as_ref(), &definition).await;
+
+                    let failed = report.failures().count();
+                    if failed == 0 {
+                        tracing::info!("Macro completed: {}", definition.name);
+                        return;
+                    }
+                    if let Err(e) = app_handle
+                        .notification()
+                        .builder()
+                        .title(format!("Macro \"{}\" incomplete", definition.name))
+                        .body(format!(
+                            "{} of {} step(s) failed",
+                            failed,
+                            report.steps.len()
+                        ))
+                        .show()
+                    {
+                        tracing::error!("Failed to send notification: {}", e);
+                    }
+                });
+            }
+        }
+        _ if id.starts_with("timer:") => {
+            if let Some((device_id, code, minutes)) = tray::parse_timer_id(id) {
+                let app_handle = app.clone();
+                let cache = status_cache.clone();
+
+                tauri::async_runtime::spawn(async move {
+                    let Some(tuya_client) =
+                        current_client(&app_handle.state::<SharedTuyaClient>()).await
+                    else {
+                        tracing::error!("Client not initialized");
+                        return;
+                    };
+
+                    let result = actions::set_timer(
+                        tuya_client,
+                        &app_handle.state::<CountdownTimers>(),
+                        &app_handle.state::<DeviceSpecCache>(),
+                        &cache,
+                        &device_id,
+                        &code,
+                        minutes,
+                    )
+                    .await;
+                    match result {
+                        Ok(()) => {
+                            tracing::info!("Timer set: {}:{} for {} min", device_id, code, minutes);
+                            refresh_timer_labels(&app_handle, &cache).await;
+                        }
+                        Err(e) => {
+                            tracing::error!("Failed to set timer: {}", e);
                         }
                     }
                 });
@@ -340,34 +973,31 @@ fn handle_menu_event(
         }
         _ if id.starts_with("set:") || id.starts_with("cmd:") => {
             if let Some((device_id, code, value_str)) = tray::parse_command_id(id) {
-                let value = tray::parse_value(&value_str);
                 let app_handle = app.clone();
 
                 tauri::async_runtime::spawn(async move {
-                    let result = {
-                        let client = app_handle.state::<SharedTuyaClient>();
-                        let guard = client.read().await;
-                        if let Some(tuya_client) = guard.as_ref() {
-                            Some(
-                                tuya_client
-                                    .send_device_command(&device_id, &code, value)
-                                    .await,
-                            )
-                        } else {
-                            None
-                        }
+                    let Some(tuya_client) =
+                        current_client(&app_handle.state::<SharedTuyaClient>()).await
+                    else {
+                        tracing::error!("Client not initialized");
+                        return;
                     };
 
+                    let result = actions::send_value(
+                        tuya_client.as_ref(),
+                        &app_handle.state::<DeviceSpecCache>(),
+                        &device_id,
+                        &code,
+                        &value_str,
+                    )
+                    .await;
                     match result {
-                        Some(Ok(_)) => {
+                        Ok(()) => {
                             tracing::info!("Command sent: {}:{}", code, value_str);
                         }
-                        Some(Err(e)) => {
+                        Err(e) => {
                             tracing::error!("Failed to send command: {}", e);
                         }
-                        None => {
-                            tracing::error!("Client not initialized");
-                        }
                     }
                 });
             }
@@ -434,6 +1064,7 @@ fn main() {
 
     let config_manager = ConfigManager::new();
     let shared_client = create_shared_client();
+    let discovered = local::create_discovered_devices();
 
     if config_manager.is_configured() {
         let cfg = config_manager.get();
@@ -442,6 +1073,7 @@ fn main() {
         tauri::async_runtime::block_on(async {
             initialize_client(
                 &client_clone,
+                &discovered,
                 cfg.access_key.clone(),
                 cfg.secret_key.clone(),
                 cfg.base_url.clone(),
@@ -461,6 +1093,9 @@ fn main() {
     let menu_update_lock: MenuUpdateLock = Arc::new(Mutex::new(()));
     let update_state: SharedUpdateState = create_update_state();
     let menu_registry: MenuItemRegistry = tray::create_menu_registry();
+    let spec_cache: DeviceSpecCache = tray::create_spec_cache();
+    let home_cache: HomeCache = tray::create_home_cache();
+    let device_cache = DeviceCacheFile::load(config_manager.cache_path());
 
     let app = tauri::Builder::default()
         .plugin(tauri_plugin_shell::init())
@@ -470,19 +1105,38 @@ fn main() {
             open_config_window(app);
         }))
         .manage(shared_client.clone())
+        .manage(discovered.clone())
         .manage(config_manager)
         .manage(status_cache.clone())
         .manage(update_state.clone())
+        .manage(spec_cache)
+        .manage(home_cache)
+        .manage(device_cache)
+        .manage(tray::create_device_list_cache())
+        .manage(tray::create_remote_cache())
+        .manage(tray::create_poll_state())
+        .manage(create_notifier())
+        .manage(automation::create_countdown_timers())
+        .manage(automation::create_rule_engine())
+        .manage(tray::create_label_registry())
         .invoke_handler(tauri::generate_handler![
             commands::config::save_config,
             commands::config::get_config,
+            commands::config::get_device_overrides,
+            commands::config::set_device_override,
             commands::config::is_configured,
             commands::config::get_regions,
             commands::devices::fetch_devices,
+            commands::devices::discover_local_devices,
             commands::devices::fetch_device_status,
+            commands::devices::fetch_device_specification,
             commands::devices::send_device_command,
             commands::devices::toggle_device_state,
+            commands::automation::run_macro,
+            commands::automation::preview_schedules,
+            commands::automation::set_rule_enabled,
             commands::app::get_version,
+            commands::app::get_poll_state,
             commands::app::check_for_update,
             commands::app::open_external,
         ])
@@ -491,7 +1145,19 @@ fn main() {
 
             let update_state_for_menu = update_state.clone();
             let initial_menu = tauri::async_runtime::block_on(async {
-                tray::build_unconfigured_menu(app.handle(), &update_state_for_menu).await
+                match build_saved_menu(
+                    app.handle(),
+                    &status_cache,
+                    &update_state_for_menu,
+                    &menu_registry,
+                )
+                .await
+                {
+                    Some(menu) => Ok(menu),
+                    None => {
+                        tray::build_unconfigured_menu(app.handle(), &update_state_for_menu).await
+                    }
+                }
             })
             .expect("Failed to create initial menu");
 
@@ -532,6 +1198,23 @@ fn main() {
                 .await;
             });
 
+            // The cloud rarely reports a usable LAN address; listen for device broadcasts
+            // once so the next device list fetch can switch them to local control. Clients
+            // created before or after share what was heard.
+            let discovered_for_startup = discovered.clone();
+            let list_for_discovery = app.state::<DeviceListCache>().inner().clone();
+            tauri::async_runtime::spawn(async move {
+                match local::discover(Duration::from_secs(local::DISCOVERY_WINDOW_SECS)).await {
+                    Ok(found) => {
+                        discovered_for_startup.write().await.extend(found);
+                        if let Some(list) = list_for_discovery.write().await.as_mut() {
+                            list.fetched_at = 0;
+                        }
+                    }
+                    Err(e) => tracing::warn!("LAN discovery failed: {}", e),
+                }
+            });
+
             let app_handle = app.handle().clone();
             let cache_for_startup = status_cache.clone();
             let lock_for_startup = menu_update_lock.clone();
@@ -549,6 +1232,65 @@ fn main() {
                 .await;
             });
 
+            let (event_sender, mut event_receiver) = tokio::sync::mpsc::unbounded_channel();
+            let settings_handle = app.handle().clone();
+            tauri::async_runtime::spawn(async move {
+                events::run_subscriber(
+                    move || {
+                        let cfg = settings_handle.state::<ConfigManager>().get();
+                        if !cfg.realtime_events || !cfg.is_configured() {
+                            return None;
+                        }
+                        MessageQueueConfig::for_region(
+                            &cfg.base_url,
+                            &cfg.access_key,
+                            &cfg.secret_key,
+                        )
+                    },
+                    event_sender,
+                )
+                .await;
+            });
+
+            let client_for_scheduler = app.state::<SharedTuyaClient>().inner().clone();
+            let scheduler_handle = app.handle().clone();
+            tauri::async_runtime::spawn(async move {
+                automation::schedule::run_scheduler(client_for_scheduler, move || {
+                    scheduler_handle.state::<ConfigManager>().get()
+                })
+                .await;
+            });
+
+            let app_handle = app.handle().clone();
+            let cache_for_events = status_cache.clone();
+            let lock_for_events = menu_update_lock.clone();
+            let update_state_for_events = update_state.clone();
+            let registry_for_events = menu_registry.clone();
+            tauri::async_runtime::spawn(async move {
+                while let Some(event) = event_receiver.recv().await {
+                    handle_queue_event(
+                        &app_handle,
+                        event,
+                        &cache_for_events,
+                        &lock_for_events,
+                        &update_state_for_events,
+                        &registry_for_events,
+                    )
+                    .await;
+                }
+            });
+
+            let app_handle = app.handle().clone();
+            let cache_for_labels = status_cache.clone();
+            tauri::async_runtime::spawn(async move {
+                let mut interval =
+                    tokio::time::interval(Duration::from_secs(TIMER_LABEL_REFRESH_SECS));
+                loop {
+                    interval.tick().await;
+                    refresh_timer_labels(&app_handle, &cache_for_labels).await;
+                }
+            });
+
             RUNNING.store(true, Ordering::Release);
             let app_handle = app.handle().clone();
             let cache_for_loop = status_cache.clone();
@@ -556,15 +1298,31 @@ fn main() {
             let update_state_for_loop = update_state.clone();
             let registry_for_loop = menu_registry.clone();
             tauri::async_runtime::spawn(async move {
-                let mut interval = tokio::time::interval(Duration::from_secs(10));
+                let poll_state = app_handle.state::<SharedPollState>().inner().clone();
+                let mut planner = PollPlanner::new(chrono::Utc::now().timestamp());
+                let mut last_update_check = Instant::now();
+                let mut last_reconcile = Instant::now();
                 loop {
-                    interval.tick().await;
+                    let state = planner.next(
+                        tray::polling::last_interaction_ms() / 1000,
+                        chrono::Utc::now().timestamp(),
+                        tray::polling::jitter(),
+                    );
+                    *poll_state.write().await = state;
+
+                    // An interaction cuts a long wait short so polling speeds up
+                    tokio::select! {
+                        _ = tokio::time::sleep(Duration::from_secs(state.interval_secs)) => {}
+                        _ = tray::polling::interaction() => continue,
+                    }
                     if !RUNNING.load(Ordering::Acquire) {
                         break;
                     }
 
-                    let counter = UPDATE_CHECK_COUNTER.fetch_add(1, Ordering::Relaxed);
-                    if counter > 0 && counter.is_multiple_of(UPDATE_CHECK_INTERVAL) {
+                    if last_update_check.elapsed()
+                        >= Duration::from_secs(UPDATE_CHECK_INTERVAL_SECS)
+                    {
+                        last_update_check = Instant::now();
                         check_and_notify_update(
                             &app_handle,
                             &update_state_for_loop,
@@ -575,6 +1333,14 @@ fn main() {
                         .await;
                     }
 
+                    // Pushed events keep the menu current; polling only reconciles
+                    if REALTIME_CONNECTED.load(Ordering::Acquire)
+                        && last_reconcile.elapsed() < Duration::from_secs(RECONCILE_SECS)
+                    {
+                        continue;
+                    }
+                    last_reconcile = Instant::now();
+
                     let result = tokio::time::timeout(
                         Duration::from_secs(15),
                         update_tray_menu(
@@ -588,9 +1354,11 @@ fn main() {
                     )
                     .await;
 
-                    if result.is_err() {
+                    let outcome = result.unwrap_or_else(|_| {
                         tracing::warn!("Auto-refresh timed out, will retry next cycle");
-                    }
+                        PollOutcome::Failed
+                    });
+                    planner.record(outcome, chrono::Utc::now().timestamp());
                 }
                 tracing::info!("Auto-refresh loop terminated");
             });
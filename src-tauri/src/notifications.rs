@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::NaiveTime;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::automation::countdown::is_switch;
+use crate::automation::cron::parse_time;
+use crate::tuya::{DeviceSpecification, TuyaDevice, TuyaDeviceStatus};
+
+/// The app's own commands show up in the next polls like any other change. A switch
+/// that changes this soon after the app sent it a command isn't reported.
+pub const OWN_CHANGE_SECS: i64 = 120;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationConfig {
+    #[serde(default)]
+    pub devices: Vec<DeviceNotifications>,
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+    /// Minimum time between two notifications about the same thing, so a device
+    /// with a flaky connection doesn't flood the notification center.
+    #[serde(default = "default_min_interval_secs")]
+    pub min_interval_secs: i64,
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        Self {
+            devices: Vec::new(),
+            quiet_hours: None,
+            min_interval_secs: default_min_interval_secs(),
+        }
+    }
+}
+
+fn default_min_interval_secs() -> i64 {
+    300
+}
+
+/// What to be notified about for one device. Everything is off by default.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceNotifications {
+    pub device_id: String,
+    /// The device going offline or coming back.
+    #[serde(default)]
+    pub connectivity: bool,
+    /// A switch changed from the phone app or a physical button.
+    #[serde(default)]
+    pub external_changes: bool,
+    #[serde(default)]
+    pub thresholds: Vec<Threshold>,
+}
+
+/// Limits are in display units, as for rules.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Threshold {
+    pub code: String,
+    #[serde(default)]
+    pub above: Option<f64>,
+    #[serde(default)]
+    pub below: Option<f64>,
+}
+
+/// Local times (HH:MM) between which notifications are dropped. The range may wrap
+/// past midnight, as in 22:00 to 07:00.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuietHours {
+    pub start: String,
+    pub end: String,
+}
+
+impl QuietHours {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        let (Ok(start), Ok(end)) = (parse_time(&self.start), parse_time(&self.end)) else {
+            return false;
+        };
+        if start <= end {
+            start <= time && time < end
+        } else {
+            time >= start || time < end
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceNotification {
+    /// What the notification is about, for rate limiting.
+    pub key: String,
+    pub title: String,
+    pub body: String,
+}
+
+impl DeviceNotification {
+    fn new(key: String, title: String, body: String) -> Self {
+        Self { key, title, body }
+    }
+}
+
+/// A numeric status in display units, with its unit when the spec has one.
+fn reading(status: &TuyaDeviceStatus, spec: Option<&DeviceSpecification>) -> Option<(f64, String)> {
+    let scaled = spec
+        .and_then(|spec| spec.schema(&status.code))
+        .and_then(|schema| status.value.as_scaled(schema));
+    match scaled {
+        Some(scaled) => Some((scaled.value(), scaled.to_string())),
+        None => {
+            let value = status.value.as_f64()?;
+            Some((value, value.to_string()))
+        }
+    }
+}
+
+/// Notifications for the configured devices from one status snapshot to the next.
+/// Online flags come from the device lists; switch changes and threshold crossings
+/// from the statuses, so a device has to be in both snapshots for those.
+/// `changed_by_app` tells whether the app itself recently sent a DP a command.
+pub fn device_notifications(
+    config: &NotificationConfig,
+    old_devices: &[TuyaDevice],
+    new_devices: &[TuyaDevice],
+    old: &HashMap<String, Vec<TuyaDeviceStatus>>,
+    new: &HashMap<String, Vec<TuyaDeviceStatus>>,
+    specs: &HashMap<String, DeviceSpecification>,
+    changed_by_app: impl Fn(&str, &str) -> bool,
+) -> Vec<DeviceNotification> {
+    let mut notifications = Vec::new();
+
+    for wanted in &config.devices {
+        let id = wanted.device_id.as_str();
+        let was = old_devices.iter().find(|d| d.id == id);
+        let is = new_devices.iter().find(|d| d.id == id);
+        let name = is
+            .or(was)
+            .map(|d| d.name.as_str())
+            .unwrap_or(id)
+            .to_string();
+
+        if let (true, Some(was), Some(is)) = (wanted.connectivity, was, is) {
+            if was.online != is.online {
+                let title = if is.online {
+                    format!("{} is back online", name)
+                } else {
+                    format!("{} went offline", name)
+                };
+                let body = if is.online {
+                    "The device is reachable again."
+                } else {
+                    "Its controls are unavailable until it reconnects."
+                };
+                notifications.push(DeviceNotification::new(
+                    format!("{}:connectivity", id),
+                    title,
+                    body.to_string(),
+                ));
+            }
+        }
+
+        let (Some(old_statuses), Some(new_statuses)) = (old.get(id), new.get(id)) else {
+            continue;
+        };
+        for status in new_statuses {
+            let Some(previous) = old_statuses.iter().find(|s| s.code == status.code) else {
+                continue;
+            };
+            if previous.value == status.value {
+                continue;
+            }
+
+            if wanted.external_changes && is_switch(&status.code) {
+                if let (Some(_), Some(on)) = (previous.value.as_bool(), status.value.as_bool()) {
+                    if !changed_by_app(id, &status.code) {
+                        notifications.push(DeviceNotification::new(
+                            format!("{}:{}", id, status.code),
+                            format!("{} turned {}", name, if on { "on" } else { "off" }),
+                            format!("{} was switched outside this app.", status.code),
+                        ));
+                    }
+                }
+            }
+
+            let spec = specs.get(id);
+            for threshold in wanted.thresholds.iter().filter(|t| t.code == status.code) {
+                let (Some((before, _)), Some((now, shown))) =
+                    (reading(previous, spec), reading(status, spec))
+                else {
+                    continue;
+                };
+                let crossings = [
+                    (
+                        "above",
+                        threshold.above.filter(|l| before <= *l && now > *l),
+                    ),
+                    (
+                        "below",
+                        threshold.below.filter(|l| before >= *l && now < *l),
+                    ),
+                ];
+                for (direction, limit) in crossings {
+                    let Some(limit) = limit else {
+                        continue;
+                    };
+                    notifications.push(DeviceNotification::new(
+                        format!("{}:{}:{}", id, status.code, direction),
+                        format!("{}: {} {} {}", name, status.code, direction, limit),
+                        format!("Now {}.", shown),
+                    ));
+                }
+            }
+        }
+    }
+    notifications
+}
+
+/// Applies quiet hours and rate limiting. Notifications dropped either way are not
+/// sent later.
+#[derive(Debug, Default)]
+pub struct Notifier {
+    last_sent: HashMap<String, i64>,
+}
+
+pub type SharedNotifier = Arc<Mutex<Notifier>>;
+
+pub fn create_notifier() -> SharedNotifier {
+    Arc::new(Mutex::new(Notifier::default()))
+}
+
+impl Notifier {
+    /// `now` is Unix seconds and `time` the local time of day.
+    pub fn admit(
+        &mut self,
+        config: &NotificationConfig,
+        notifications: Vec<DeviceNotification>,
+        now: i64,
+        time: NaiveTime,
+    ) -> Vec<DeviceNotification> {
+        if config
+            .quiet_hours
+            .as_ref()
+            .is_some_and(|quiet| quiet.contains(time))
+        {
+            return Vec::new();
+        }
+        notifications
+            .into_iter()
+            .filter(|notification| {
+                let recent = self
+                    .last_sent
+                    .get(&notification.key)
+                    .is_some_and(|sent| now - sent < config.min_interval_secs);
+                if !recent {
+                    self.last_sent.insert(notification.key.clone(), now);
+                }
+                !recent
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tuya::backend::fake::{device, status};
+    use crate::tuya::TuyaValue;
+
+    fn config() -> NotificationConfig {
+        serde_json::from_value(serde_json::json!({
+            "devices": [{
+                "deviceId": "plug",
+                "connectivity": true,
+                "externalChanges": true,
+                "thresholds": [{ "code": "cur_power", "above": 1000 }]
+            }],
+            "quietHours": { "start": "22:00", "end": "07:00" }
+        }))
+        .unwrap()
+    }
+
+    fn plug(on: bool, power: i64) -> HashMap<String, Vec<TuyaDeviceStatus>> {
+        HashMap::from([(
+            "plug".to_string(),
+            vec![
+                status("switch_1", TuyaValue::Boolean(on)),
+                status("cur_power", TuyaValue::Integer(power)),
+            ],
+        )])
+    }
+
+    fn keys(notifications: &[DeviceNotification]) -> Vec<&str> {
+        notifications.iter().map(|n| n.key.as_str()).collect()
+    }
+
+    #[test]
+    fn test_changes_become_notifications() {
+        let config = config();
+        let devices = [device("plug", true), device("lamp", true)];
+        let specs = HashMap::new();
+
+        let found = device_notifications(
+            &config,
+            &devices,
+            &devices,
+            &plug(false, 200),
+            &plug(true, 1500),
+            &specs,
+            |_, _| false,
+        );
+        assert_eq!(keys(&found), ["plug:switch_1", "plug:cur_power:above"]);
+        assert_eq!(found[0].title, "Device plug turned on");
+
+        // A switch the app just toggled, or a reading staying above the limit, is quiet
+        let found = device_notifications(
+            &config,
+            &devices,
+            &devices,
+            &plug(false, 1200),
+            &plug(true, 1500),
+            &specs,
+            |_, code| code == "switch_1",
+        );
+        assert!(found.is_empty());
+
+        let offline = [device("plug", false)];
+        let found = device_notifications(
+            &config,
+            &devices,
+            &offline,
+            &plug(true, 0),
+            &HashMap::new(),
+            &specs,
+            |_, _| false,
+        );
+        assert_eq!(found[0].title, "Device plug went offline");
+    }
+
+    #[test]
+    fn test_quiet_hours_and_rate_limits() {
+        let config = config();
+        let mut notifier = Notifier::default();
+        let notification = DeviceNotification::new(
+            "plug:connectivity".to_string(),
+            String::new(),
+            String::new(),
+        );
+        let at = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+
+        let admitted = notifier.admit(&config, vec![notification.clone()], 0, at(23, 30));
+        assert!(admitted.is_empty());
+        let admitted = notifier.admit(&config, vec![notification.clone()], 0, at(7, 0));
+        assert_eq!(admitted.len(), 1);
+        let admitted = notifier.admit(&config, vec![notification.clone()], 200, at(7, 3));
+        assert!(admitted.is_empty());
+        let admitted = notifier.admit(&config, vec![notification], 300, at(7, 5));
+        assert_eq!(admitted.len(), 1);
+    }
+}
@@ -0,0 +1,353 @@
+use std::sync::Arc;
+
+use super::light;
+use super::menu::{parse_value, DeviceSpecCache, DeviceStatusCache};
+use super::remote::{self, RemoteAction, RemoteCache};
+use crate::automation::countdown::{self, CountdownTimers};
+use crate::error::AppError;
+use crate::tuya::{DeviceBackend, TuyaValue};
+
+/// Flips a switch from its cached state. Returns the state it was switched to, which
+/// is also written to the cache so the next click flips it back.
+pub async fn toggle(
+    backend: &dyn DeviceBackend,
+    statuses: &DeviceStatusCache,
+    device_id: &str,
+    code: &str,
+) -> Result<bool, AppError> {
+    let current = statuses
+        .read()
+        .await
+        .get(device_id)
+        .and_then(|status| status.iter().find(|s| s.code == code))
+        .and_then(|s| s.value.as_bool())
+        .unwrap_or(false);
+
+    backend
+        .send_command(device_id, code, TuyaValue::Boolean(!current))
+        .await?;
+
+    if let Some(s) = statuses
+        .write()
+        .await
+        .get_mut(device_id)
+        .and_then(|status| status.iter_mut().find(|s| s.code == code))
+    {
+        s.value = TuyaValue::Boolean(!current);
+    }
+    Ok(!current)
+}
+
+/// Sends the value of a `set:` or `cmd:` item. The spec knows whether "2" is an enum
+/// string or an integer; without one the value is guessed from its text.
+pub async fn send_value(
+    backend: &dyn DeviceBackend,
+    specs: &DeviceSpecCache,
+    device_id: &str,
+    code: &str,
+    value: &str,
+) -> Result<(), AppError> {
+    let value = specs
+        .read()
+        .await
+        .get(device_id)
+        .and_then(|spec| spec.function(code))
+        .and_then(|schema| schema.parse_value(value))
+        .unwrap_or_else(|| parse_value(value));
+
+    backend.send_command(device_id, code, value).await?;
+    Ok(())
+}
+
+/// Switches a light to one of the colour presets.
+pub async fn set_colour(
+    backend: &dyn DeviceBackend,
+    statuses: &DeviceStatusCache,
+    device_id: &str,
+    code: &str,
+    hue: i64,
+) -> Result<(), AppError> {
+    let commands = {
+        let statuses = statuses.read().await;
+        light::colour_commands(
+            code,
+            hue,
+            statuses
+                .get(device_id)
+                .map(Vec::as_slice)
+                .unwrap_or_default(),
+        )
+    };
+
+    backend.send_commands(device_id, commands).await?;
+    Ok(())
+}
+
+/// Sends what an `ir:` item stands for. A scene sent to an air conditioner becomes its
+/// cached state. Returns false when the item means nothing for the remote, such as a
+/// key that has since been deleted.
+pub async fn send_remote(
+    backend: &dyn DeviceBackend,
+    remotes: &RemoteCache,
+    remote_id: &str,
+    field: &str,
+    value: &str,
+) -> Result<bool, AppError> {
+    let (details, current) = remotes
+        .read()
+        .await
+        .as_ref()
+        .and_then(|data| {
+            let details = data.remotes.get(remote_id)?.clone();
+            Some((details, data.scenes.get(remote_id).copied()))
+        })
+        .ok_or_else(|| AppError::Config(format!("Remote {} is not known yet", remote_id)))?;
+
+    match remote::remote_action(&details, current, field, value) {
+        Some(RemoteAction::Scene(scene)) => {
+            backend
+                .send_ac_scene(&details.infrared_id, remote_id, &scene)
+                .await?;
+            remote::store_scene(remotes, remote_id, scene).await;
+        }
+        Some(RemoteAction::Key(code)) => {
+            backend
+                .send_learned_code(&details.infrared_id, remote_id, &code)
+                .await?;
+        }
+        None => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Starts or cancels a "Turn off in…" timer, on the device's own countdown DP when it
+/// can hold the duration. A native countdown is written to the cache so its label
+/// shows the time left straight away.
+pub async fn set_timer(
+    backend: Arc<dyn DeviceBackend>,
+    timers: &CountdownTimers,
+    specs: &DeviceSpecCache,
+    statuses: &DeviceStatusCache,
+    device_id: &str,
+    code: &str,
+    minutes: u32,
+) -> Result<(), AppError> {
+    let seconds = i64::from(minutes) * 60;
+    let native = {
+        let specs = specs.read().await;
+        let statuses = statuses.read().await;
+        countdown::native_countdown(
+            code,
+            specs.get(device_id),
+            statuses
+                .get(device_id)
+                .map(Vec::as_slice)
+                .unwrap_or_default(),
+            seconds,
+        )
+    };
+
+    countdown::set_countdown(backend, timers, device_id, code, native.as_deref(), minutes).await?;
+
+    if let Some(countdown_code) = native {
+        if let Some(s) = statuses
+            .write()
+            .await
+            .get_mut(device_id)
+            .and_then(|status| status.iter_mut().find(|s| s.code == countdown_code))
+        {
+            s.value = TuyaValue::Integer(seconds);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::sync::RwLock;
+
+    use super::*;
+    use crate::automation::countdown::create_countdown_timers;
+    use crate::tray::remote::{create_remote_cache, refresh_remotes};
+    use crate::tuya::backend::fake::{device, status, FakeBackend};
+    use crate::tuya::{DeviceSpecification, LearnedCode, TuyaCommand, TuyaRemote};
+
+    fn status_cache(
+        device_id: &str,
+        statuses: Vec<crate::tuya::TuyaDeviceStatus>,
+    ) -> DeviceStatusCache {
+        Arc::new(RwLock::new(HashMap::from([(
+            device_id.to_string(),
+            statuses,
+        )])))
+    }
+
+    fn spec(functions: serde_json::Value) -> DeviceSpecification {
+        serde_json::from_value(serde_json::json!({
+            "category": "kg",
+            "functions": functions,
+            "status": []
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_toggles_flip_the_cached_state() {
+        let backend = FakeBackend::default();
+        let statuses = status_cache("plug", vec![status("switch_1", TuyaValue::Boolean(true))]);
+
+        assert!(!toggle(&backend, &statuses, "plug", "switch_1")
+            .await
+            .unwrap());
+        assert!(toggle(&backend, &statuses, "plug", "switch_1")
+            .await
+            .unwrap());
+        let sent: Vec<TuyaValue> = backend
+            .sent()
+            .into_iter()
+            .flat_map(|(_, commands)| commands.into_iter().map(|c| c.value))
+            .collect();
+        assert_eq!(sent, [TuyaValue::Boolean(false), TuyaValue::Boolean(true)]);
+
+        // A rejected command leaves the cache alone
+        let mut backend = FakeBackend::default();
+        backend.failing_devices.insert("plug".to_string());
+        assert!(toggle(&backend, &statuses, "plug", "switch_1")
+            .await
+            .is_err());
+        assert_eq!(
+            statuses.read().await["plug"][0].value,
+            TuyaValue::Boolean(true)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_values_are_parsed_by_the_spec() {
+        let backend = FakeBackend::default();
+        let specs: DeviceSpecCache = Arc::new(RwLock::new(HashMap::from([(
+            "fan".to_string(),
+            spec(serde_json::json!([{
+                "code": "fan_speed",
+                "type": "Enum",
+                "values": "{\"range\":[\"1\",\"2\",\"3\"]}"
+            }])),
+        )])));
+
+        send_value(&backend, &specs, "fan", "fan_speed", "2")
+            .await
+            .unwrap();
+        send_value(&backend, &specs, "heater", "level", "2")
+            .await
+            .unwrap();
+        let sent = backend.sent();
+        assert_eq!(sent[0].1[0].value, TuyaValue::String("2".to_string()));
+        assert_eq!(sent[1].1[0].value, TuyaValue::Integer(2));
+    }
+
+    #[tokio::test]
+    async fn test_colours_switch_the_work_mode() {
+        let backend = FakeBackend::default();
+        let statuses = status_cache(
+            "bulb",
+            vec![status("work_mode", TuyaValue::String("white".to_string()))],
+        );
+
+        set_colour(&backend, &statuses, "bulb", "colour_data_v2", 120)
+            .await
+            .unwrap();
+        let codes: Vec<String> = backend.sent()[0]
+            .1
+            .iter()
+            .map(|c: &TuyaCommand| c.code.clone())
+            .collect();
+        assert_eq!(codes, ["work_mode", "colour_data_v2"]);
+    }
+
+    #[tokio::test]
+    async fn test_remote_items_send_scenes_and_keys() {
+        let mut hub = device("hub", true);
+        hub.category = "wnykq".to_string();
+        let remote = |id: &str, category_id| TuyaRemote {
+            remote_id: id.to_string(),
+            remote_name: id.to_string(),
+            category_id,
+            brand_name: String::new(),
+        };
+        let key = LearnedCode {
+            id: 7,
+            key_name: "Power".to_string(),
+            code: "code-7".to_string(),
+        };
+        let backend = FakeBackend::default()
+            .with_remote("hub", remote("ac", 5), None, Vec::new())
+            .with_remote("hub", remote("tv", 2), None, vec![key]);
+        let remotes = create_remote_cache();
+        refresh_remotes(&backend, &[hub], &remotes).await;
+
+        assert!(send_remote(&backend, &remotes, "tv", "key", "7")
+            .await
+            .unwrap());
+        assert!(!send_remote(&backend, &remotes, "tv", "key", "8")
+            .await
+            .unwrap());
+        assert!(send_remote(&backend, &remotes, "ac", "temp", "20")
+            .await
+            .unwrap());
+        // The next setting builds on the scene just sent
+        assert!(send_remote(&backend, &remotes, "ac", "wind", "3")
+            .await
+            .unwrap());
+        assert!(send_remote(&backend, &remotes, "fan", "power", "toggle")
+            .await
+            .is_err());
+
+        assert_eq!(
+            *backend.sent_codes.lock().unwrap(),
+            [("tv".to_string(), "code-7".to_string())]
+        );
+        let scenes = backend.sent_scenes.lock().unwrap();
+        assert_eq!((scenes[0].1.power, scenes[0].1.temp), (1, 20));
+        assert_eq!((scenes[1].1.temp, scenes[1].1.wind), (20, 3));
+    }
+
+    #[tokio::test]
+    async fn test_native_timers_use_the_countdown_dp() {
+        let backend = Arc::new(FakeBackend::default());
+        let specs: DeviceSpecCache = Arc::new(RwLock::new(HashMap::from([(
+            "plug".to_string(),
+            spec(serde_json::json!([{
+                "code": "countdown_1",
+                "type": "Integer",
+                "values": "{\"min\":0,\"max\":86400,\"scale\":0,\"step\":1}"
+            }])),
+        )])));
+        let statuses = status_cache(
+            "plug",
+            vec![
+                status("switch_1", TuyaValue::Boolean(true)),
+                status("countdown_1", TuyaValue::Integer(0)),
+            ],
+        );
+        let timers = create_countdown_timers();
+
+        set_timer(
+            backend.clone(),
+            &timers,
+            &specs,
+            &statuses,
+            "plug",
+            "switch_1",
+            30,
+        )
+        .await
+        .unwrap();
+        assert_eq!(backend.sent()[0].1[0].code, "countdown_1");
+        assert_eq!(
+            statuses.read().await["plug"][1].value,
+            TuyaValue::Integer(1800)
+        );
+        assert!(timers.read().await.is_empty());
+    }
+}
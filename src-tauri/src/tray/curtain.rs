@@ -0,0 +1,88 @@
+use crate::tuya::{DeviceSpecification, FunctionSchema, TuyaDeviceStatus, TuyaValue};
+
+/// Positions offered for curtains and blinds, in percent open.
+pub const POSITIONS: [i64; 5] = [0, 25, 50, 75, 100];
+
+/// The DP positions are sent to.
+pub const POSITION_CONTROL: &str = "percent_control";
+
+const POSITION_CODES: [&str; 2] = ["percent_state", POSITION_CONTROL];
+
+const ACTIONS: [(&str, &str); 3] = [("open", "Open"), ("stop", "Stop"), ("close", "Close")];
+
+pub fn is_curtain(category: &str) -> bool {
+    category == "cl"
+}
+
+/// DPs covered by the curtain controls rather than the generic ones.
+pub fn is_curtain_code(code: &str) -> bool {
+    matches!(code, "control" | "percent_control" | "percent_state")
+}
+
+/// Open/Stop/Close as `control` values with their labels, limited to what the spec
+/// offers when there is one.
+pub fn actions(spec: Option<&DeviceSpecification>) -> Vec<(&'static str, &'static str)> {
+    let range = match spec.map(|spec| spec.function("control")) {
+        Some(Some(FunctionSchema::Enum { range })) => range,
+        Some(_) => return Vec::new(),
+        None => return ACTIONS.to_vec(),
+    };
+    ACTIONS
+        .into_iter()
+        .filter(|(value, _)| range.iter().any(|r| r == value))
+        .collect()
+}
+
+pub fn is_position_code(code: &str) -> bool {
+    POSITION_CODES.contains(&code)
+}
+
+/// The DP the position check mark follows: `percent_state` reports where the curtain
+/// is, `percent_control` only where it was last sent, so it's used as a fallback.
+pub fn position_code(status: &[TuyaDeviceStatus]) -> Option<&'static str> {
+    POSITION_CODES
+        .into_iter()
+        .find(|code| status.iter().any(|s| s.code == *code))
+}
+
+/// The offered position closest to a reported one, as the option value to check.
+pub fn selected_position(value: &TuyaValue) -> String {
+    value
+        .as_i64()
+        .and_then(|percent| POSITIONS.into_iter().min_by_key(|p| (p - percent).abs()))
+        .map(|position| position.to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tuya::backend::fake::status;
+
+    #[test]
+    fn test_positions_follow_the_reported_state() {
+        let statuses = vec![
+            status("control", TuyaValue::String("stop".to_string())),
+            status("percent_control", TuyaValue::Integer(100)),
+            status("percent_state", TuyaValue::Integer(40)),
+        ];
+        assert_eq!(position_code(&statuses), Some("percent_state"));
+        assert_eq!(position_code(&statuses[..2]), Some("percent_control"));
+        assert_eq!(position_code(&statuses[..1]), None);
+        assert_eq!(selected_position(&statuses[2].value), "50");
+        assert_eq!(selected_position(&statuses[0].value), "");
+
+        let spec: DeviceSpecification = serde_json::from_value(serde_json::json!({
+            "category": "cl",
+            "functions": [{
+                "code": "control",
+                "type": "Enum",
+                "values": "{\"range\":[\"open\",\"close\",\"continue\"]}"
+            }],
+            "status": []
+        }))
+        .unwrap();
+        assert_eq!(actions(Some(&spec)), [("open", "Open"), ("close", "Close")]);
+        assert_eq!(actions(None).len(), 3);
+    }
+}
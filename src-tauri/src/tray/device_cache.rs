@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use super::menu::{DeviceSnapshot, DeviceSpecCache, HomeCache, HomeData, HomeDetails};
+use super::remote::RemoteDetails;
+use crate::error::AppError;
+use crate::tuya::{DeviceSpecification, TuyaDevice, TuyaDeviceStatus};
+
+/// Statuses change often; the file is rewritten at most this often.
+const SAVE_INTERVAL_SECS: i64 = 60;
+
+/// The devices the tray last showed, with what is needed to rebuild their controls.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedDevices {
+    pub saved_at: i64,
+    pub devices: Vec<TuyaDevice>,
+    pub statuses: HashMap<String, Vec<TuyaDeviceStatus>>,
+    #[serde(default)]
+    pub specs: HashMap<String, DeviceSpecification>,
+    #[serde(default)]
+    pub homes: Vec<HomeDetails>,
+    #[serde(default)]
+    pub remotes: HashMap<String, RemoteDetails>,
+}
+
+impl CachedDevices {
+    pub fn snapshot(&self) -> DeviceSnapshot {
+        DeviceSnapshot {
+            devices: self.devices.clone(),
+            statuses: self
+                .statuses
+                .iter()
+                .map(|(id, status)| (id.clone(), Ok(status.clone())))
+                .collect(),
+            saved_at: Some(self.saved_at),
+            offline_since: HashMap::new(),
+            remotes: self.remotes.clone(),
+        }
+    }
+
+    pub fn spec_cache(&self) -> DeviceSpecCache {
+        Arc::new(RwLock::new(self.specs.clone()))
+    }
+
+    pub fn home_cache(&self) -> HomeCache {
+        Arc::new(RwLock::new(Some(HomeData {
+            fetched_at: self.saved_at,
+            homes: self.homes.clone(),
+        })))
+    }
+
+    /// Everything but the save time, to tell whether a rewrite is needed.
+    fn contents(&self) -> serde_json::Value {
+        serde_json::to_value(CachedDevices {
+            saved_at: 0,
+            ..self.clone()
+        })
+        .unwrap_or_default()
+    }
+}
+
+#[derive(Default)]
+struct CacheState {
+    latest: Option<CachedDevices>,
+    written: Option<serde_json::Value>,
+    written_at: i64,
+}
+
+/// Keeps the last live snapshot in memory and in a file next to `config.json`, so the
+/// tray can show devices at startup and while the cloud is unreachable.
+pub struct DeviceCacheFile {
+    path: PathBuf,
+    state: RwLock<CacheState>,
+}
+
+impl DeviceCacheFile {
+    pub fn load(path: PathBuf) -> Self {
+        let latest: Option<CachedDevices> = fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok());
+        let state = CacheState {
+            written: latest.as_ref().map(CachedDevices::contents),
+            written_at: latest.as_ref().map(|c| c.saved_at).unwrap_or_default(),
+            latest,
+        };
+        Self {
+            path,
+            state: RwLock::new(state),
+        }
+    }
+
+    pub async fn get(&self) -> Option<CachedDevices> {
+        self.state.read().await.latest.clone()
+    }
+
+    /// Records a live snapshot. Failed status requests keep the device's last known
+    /// status, and a snapshot where every one failed is ignored, so an outage doesn't
+    /// replace the saved devices. Local keys are left out of the file. Returns whether
+    /// the file was rewritten.
+    pub async fn store(
+        &self,
+        snapshot: &DeviceSnapshot,
+        specs: &HashMap<String, DeviceSpecification>,
+        homes: &[HomeDetails],
+        now: i64,
+    ) -> Result<bool, AppError> {
+        if !snapshot.statuses.is_empty() && snapshot.statuses.values().all(Result::is_err) {
+            return Ok(false);
+        }
+        let mut state = self.state.write().await;
+
+        let mut statuses: HashMap<String, Vec<TuyaDeviceStatus>> = HashMap::new();
+        for (device_id, result) in &snapshot.statuses {
+            let status = match result {
+                Ok(status) => Some(status.clone()),
+                Err(_) => state
+                    .latest
+                    .as_ref()
+                    .and_then(|latest| latest.statuses.get(device_id).cloned()),
+            };
+            if let Some(status) = status {
+                statuses.insert(device_id.clone(), status);
+            }
+        }
+        let next = CachedDevices {
+            saved_at: now,
+            devices: snapshot
+                .devices
+                .iter()
+                .cloned()
+                .map(|mut device| {
+                    device.local_key.clear();
+                    device
+                })
+                .collect(),
+            statuses,
+            specs: specs
+                .iter()
+                .filter(|(id, _)| snapshot.devices.iter().any(|d| &d.id == *id))
+                .map(|(id, spec)| (id.clone(), spec.clone()))
+                .collect(),
+            homes: homes.to_vec(),
+            remotes: snapshot.remotes.clone(),
+        };
+
+        let contents = next.contents();
+        let due = now - state.written_at >= SAVE_INTERVAL_SECS;
+        let changed = state.written.as_ref() != Some(&contents);
+        state.latest = Some(next);
+        if !due || !changed {
+            return Ok(false);
+        }
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let content = serde_json::to_string(&state.latest)?;
+        fs::write(&self.path, content)?;
+        state.written = Some(contents);
+        state.written_at = now;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tuya::backend::fake::{device, status};
+    use crate::tuya::TuyaValue;
+
+    fn snapshot(on: bool) -> DeviceSnapshot {
+        let mut plug = device("plug", true);
+        plug.local_key = "secret".to_string();
+        DeviceSnapshot {
+            devices: vec![plug, device("lamp", true)],
+            statuses: HashMap::from([
+                (
+                    "plug".to_string(),
+                    Ok(vec![status("switch_1", TuyaValue::Boolean(on))]),
+                ),
+                (
+                    "lamp".to_string(),
+                    Err(AppError::Api {
+                        code: 2001,
+                        message: "device is offline".to_string(),
+                    }),
+                ),
+            ]),
+            saved_at: None,
+            offline_since: HashMap::new(),
+            remotes: HashMap::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_saves_snapshots_and_reloads_them() {
+        let path =
+            std::env::temp_dir().join(format!("tuya-device-cache-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let cache = DeviceCacheFile::load(path.clone());
+        assert!(cache.get().await.is_none());
+        assert!(cache
+            .store(&snapshot(true), &HashMap::new(), &[], 1_000)
+            .await
+            .unwrap());
+        // Unchanged contents, or a change within the save interval, aren't written
+        assert!(!cache
+            .store(&snapshot(true), &HashMap::new(), &[], 2_000)
+            .await
+            .unwrap());
+        assert!(!cache
+            .store(&snapshot(false), &HashMap::new(), &[], 1_030)
+            .await
+            .unwrap());
+        assert_eq!(cache.get().await.unwrap().saved_at, 1_030);
+
+        let mut outage = snapshot(false);
+        outage.statuses.remove("plug");
+        assert!(!cache
+            .store(&outage, &HashMap::new(), &[], 3_000)
+            .await
+            .unwrap());
+        assert_eq!(cache.get().await.unwrap().saved_at, 1_030);
+
+        let reloaded = DeviceCacheFile::load(path.clone()).get().await.unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(reloaded.saved_at, 1_000);
+        assert_eq!(reloaded.devices.len(), 2);
+        assert!(reloaded.devices[0].local_key.is_empty());
+        assert!(!reloaded.statuses.contains_key("lamp"));
+
+        let restored = reloaded.snapshot();
+        assert_eq!(restored.saved_at, Some(1_000));
+        assert_eq!(
+            restored.statuses["plug"].as_ref().unwrap(),
+            &vec![status("switch_1", TuyaValue::Boolean(true))]
+        );
+    }
+}
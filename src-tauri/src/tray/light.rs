@@ -0,0 +1,132 @@
+use serde_json::{json, Map, Value};
+
+use crate::tuya::{TuyaCommand, TuyaDeviceStatus, TuyaValue};
+
+/// Brightness steps offered for lights, in percent.
+const BRIGHTNESS_STEPS: [i64; 5] = [10, 25, 50, 75, 100];
+
+/// Colour presets offered for lights, by hue in degrees.
+pub const COLOUR_PRESETS: [(i64, &str); 8] = [
+    (0, "Red"),
+    (30, "Orange"),
+    (60, "Yellow"),
+    (120, "Green"),
+    (180, "Cyan"),
+    (240, "Blue"),
+    (280, "Purple"),
+    (320, "Pink"),
+];
+
+/// Lights get a few named levels instead of the whole integer range: brightness in
+/// percent steps, and colour temperature as warm, neutral and cool. Returns raw DP
+/// values with their labels, or None for other DPs.
+pub fn light_levels(code: &str, min: i64, max: i64) -> Option<Vec<(i64, String)>> {
+    match code {
+        "bright_value" | "bright_value_v2" => Some(
+            BRIGHTNESS_STEPS
+                .iter()
+                .map(|percent| ((max * percent / 100).max(min), format!("{}%", percent)))
+                .collect(),
+        ),
+        "temp_value" | "temp_value_v2" => Some(vec![
+            (min, "Warm".to_string()),
+            ((min + max) / 2, "Neutral".to_string()),
+            (max, "Cool".to_string()),
+        ]),
+        _ => None,
+    }
+}
+
+pub fn is_colour(code: &str) -> bool {
+    matches!(code, "colour_data" | "colour_data_v2")
+}
+
+/// `colour_data_v2` ranges saturation and value over 0-1000; the older `colour_data`
+/// over 0-255.
+fn colour_max(code: &str) -> i64 {
+    if code.ends_with("_v2") {
+        1000
+    } else {
+        255
+    }
+}
+
+/// The HSV object of a colour DP. The cloud reports it as a JSON string but takes it
+/// back as an object.
+fn colour_object(value: &TuyaValue) -> Option<Map<String, Value>> {
+    match value {
+        TuyaValue::Json(Value::Object(object)) => Some(object.clone()),
+        TuyaValue::String(text) => serde_json::from_str(text).ok(),
+        _ => None,
+    }
+}
+
+pub fn colour_hue(value: &TuyaValue) -> Option<i64> {
+    colour_object(value)?.get("h")?.as_i64()
+}
+
+/// Commands that switch a light to a fully saturated `hue`, keeping its current
+/// colour brightness. Lights with a `work_mode` DP are put into colour mode first.
+pub fn colour_commands(code: &str, hue: i64, statuses: &[TuyaDeviceStatus]) -> Vec<TuyaCommand> {
+    let max = colour_max(code);
+    let brightness = statuses
+        .iter()
+        .find(|s| s.code == code)
+        .and_then(|s| colour_object(&s.value))
+        .and_then(|colour| colour.get("v")?.as_i64())
+        .filter(|v| *v > 0)
+        .unwrap_or(max);
+
+    let mut commands = Vec::new();
+    if statuses.iter().any(|s| s.code == "work_mode") {
+        commands.push(TuyaCommand {
+            code: "work_mode".to_string(),
+            value: TuyaValue::String("colour".to_string()),
+        });
+    }
+    commands.push(TuyaCommand {
+        code: code.to_string(),
+        value: TuyaValue::Json(json!({ "h": hue, "s": max, "v": brightness.min(max) })),
+    });
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tuya::backend::fake::status;
+
+    #[test]
+    fn test_levels_cover_the_dp_range() {
+        let brightness = light_levels("bright_value_v2", 10, 1000).unwrap();
+        assert_eq!(brightness[0], (100, "10%".to_string()));
+        assert_eq!(brightness[4], (1000, "100%".to_string()));
+
+        let temperature = light_levels("temp_value_v2", 0, 1000).unwrap();
+        assert_eq!(
+            temperature.iter().map(|(raw, _)| *raw).collect::<Vec<_>>(),
+            [0, 500, 1000]
+        );
+        assert!(light_levels("temp_set", 0, 1000).is_none());
+    }
+
+    #[test]
+    fn test_colour_presets_keep_brightness() {
+        let statuses = vec![
+            status("work_mode", TuyaValue::String("white".to_string())),
+            status(
+                "colour_data_v2",
+                TuyaValue::String(r#"{"h":0,"s":1000,"v":400}"#.to_string()),
+            ),
+        ];
+        assert_eq!(colour_hue(&statuses[1].value), Some(0));
+
+        let commands = colour_commands("colour_data_v2", 240, &statuses);
+        assert_eq!(commands[0].value, TuyaValue::String("colour".to_string()));
+        assert_eq!(
+            serde_json::to_value(&commands[1].value).unwrap(),
+            json!({ "h": 240, "s": 1000, "v": 400 })
+        );
+        assert_eq!(colour_hue(&commands[1].value), Some(240));
+    }
+}
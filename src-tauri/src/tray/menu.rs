@@ -1,27 +1,307 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
 
+use chrono::{DateTime, Local, TimeZone, Utc};
 use futures::future::join_all;
+use serde::{Deserialize, Serialize};
 use tauri::{
     menu::{CheckMenuItem, Menu, MenuItem, PredefinedMenuItem, Submenu},
     AppHandle, Wry,
 };
 use tokio::sync::RwLock;
 
-use crate::config::ConfigManager;
+use super::curtain;
+use super::light;
+use super::remote::{self, RemoteCache, RemoteDetails};
+use crate::automation::countdown::{self, COUNTDOWN_MINUTES};
+use crate::automation::{runs_between, solar, DeviceZones, Macro, SolarEvent};
+use crate::config::{AppConfig, DeviceOverride, MenuLayout};
 use crate::error::AppError;
 use crate::tuya::{
-    SharedTuyaClient, TuyaDevice, TuyaDeviceStatus, TuyaValue, AC_FAN_SPEED_LEVELS, AC_MODES,
-    FAN_SPEED_LEVELS, TEMP_MAX, TEMP_MIN,
+    DeviceBackend, DeviceSpecification, FunctionSchema, ScaledValue, StatusResults, TuyaDevice,
+    TuyaDeviceStatus, TuyaHome, TuyaRoom, TuyaScene, TuyaValue,
 };
 use crate::update::SharedUpdateState;
 
 pub type MenuItemRegistry = Arc<RwLock<HashMap<String, CheckMenuItem<Wry>>>>;
 
+pub type DeviceSpecCache = Arc<RwLock<HashMap<String, DeviceSpecification>>>;
+
+/// Last known statuses of the devices in the menu, keyed by device id.
+pub type DeviceStatusCache = Arc<RwLock<HashMap<String, Vec<TuyaDeviceStatus>>>>;
+
+/// Items whose text changes while the menu is open, keyed like `timer:{device}:{code}`.
+pub type LabelRegistry = Arc<RwLock<HashMap<String, Box<dyn LabelItem + Send + Sync>>>>;
+
+const MAX_INTEGER_OPTIONS: i64 = 20;
+
+/// Homes, rooms and scenes rarely change, so they are refetched at most this often.
+const HOME_REFRESH_SECS: i64 = 300;
+
+/// The one thing in-place updates need from a menu item, so the updater can be
+/// exercised without a running tray.
+pub trait CheckItem {
+    fn set_checked(&self, checked: bool);
+}
+
+impl CheckItem for CheckMenuItem<Wry> {
+    fn set_checked(&self, checked: bool) {
+        CheckMenuItem::set_checked(self, checked).ok();
+    }
+}
+
+/// Menu items and submenus whose label can be rewritten in place.
+pub trait LabelItem {
+    fn label(&self) -> String;
+    fn set_label(&self, label: &str);
+}
+
+impl LabelItem for Submenu<Wry> {
+    fn label(&self) -> String {
+        self.text().unwrap_or_default()
+    }
+
+    fn set_label(&self, label: &str) {
+        self.set_text(label).ok();
+    }
+}
+
+impl LabelItem for MenuItem<Wry> {
+    fn label(&self) -> String {
+        self.text().unwrap_or_default()
+    }
+
+    fn set_label(&self, label: &str) {
+        self.set_text(label).ok();
+    }
+}
+
+impl LabelItem for Box<dyn LabelItem + Send + Sync> {
+    fn label(&self) -> String {
+        self.as_ref().label()
+    }
+
+    fn set_label(&self, label: &str) {
+        self.as_ref().set_label(label)
+    }
+}
+
+/// Devices and statuses fetched for one menu build. Statuses are only requested for
+/// online devices, and a failed request stays attached to its device.
+pub struct DeviceSnapshot {
+    pub devices: Vec<TuyaDevice>,
+    pub statuses: StatusResults,
+    /// When the snapshot was saved, if it comes from the disk cache rather than the cloud.
+    pub saved_at: Option<i64>,
+    /// When offline devices went offline, as far as the app has seen (Unix seconds).
+    pub offline_since: HashMap<String, i64>,
+    /// IR remotes among the devices, keyed by device id.
+    pub remotes: HashMap<String, RemoteDetails>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HomeDetails {
+    pub home: TuyaHome,
+    pub rooms: Vec<TuyaRoom>,
+    pub scenes: Vec<TuyaScene>,
+}
+
+/// Devices that share a place in the menu. `path` names the submenus they are nested
+/// under, outermost first; an empty path puts them at the top level.
+#[derive(Debug)]
+pub struct DeviceGroup<'a> {
+    pub path: Vec<String>,
+    pub devices: Vec<&'a TuyaDevice>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HomeData {
+    pub fetched_at: i64,
+    pub homes: Vec<HomeDetails>,
+}
+
+pub type HomeCache = Arc<RwLock<Option<HomeData>>>;
+
+#[derive(Debug, Clone, Default)]
+pub struct DeviceList {
+    pub fetched_at: i64,
+    pub devices: Vec<TuyaDevice>,
+    /// See `track_offline`.
+    pub offline_since: HashMap<String, i64>,
+}
+
+pub type DeviceListCache = Arc<RwLock<Option<DeviceList>>>;
+
 pub fn create_menu_registry() -> MenuItemRegistry {
     Arc::new(RwLock::new(HashMap::new()))
 }
 
+pub fn create_label_registry() -> LabelRegistry {
+    Arc::new(RwLock::new(HashMap::new()))
+}
+
+pub fn create_spec_cache() -> DeviceSpecCache {
+    Arc::new(RwLock::new(HashMap::new()))
+}
+
+pub fn create_home_cache() -> HomeCache {
+    Arc::new(RwLock::new(None))
+}
+
+pub fn create_device_list_cache() -> DeviceListCache {
+    Arc::new(RwLock::new(None))
+}
+
+/// Refetches homes with their rooms and scenes once the cached copy is older than
+/// `HOME_REFRESH_SECS`. Failures keep the previous copy until the next interval, so a
+/// project without home permissions doesn't retry on every refresh.
+pub async fn refresh_homes(backend: &dyn DeviceBackend, user_id: &str, cache: &HomeCache) {
+    let now = chrono::Utc::now().timestamp();
+    if cache
+        .read()
+        .await
+        .as_ref()
+        .is_some_and(|data| now - data.fetched_at < HOME_REFRESH_SECS)
+    {
+        return;
+    }
+
+    let homes = match backend.homes(user_id).await {
+        Ok(homes) => homes,
+        Err(e) => {
+            tracing::warn!("Failed to fetch homes: {}", e);
+            let mut cached = cache.write().await;
+            cached.get_or_insert_with(HomeData::default).fetched_at = now;
+            return;
+        }
+    };
+
+    let (room_results, scene_results) = futures::join!(
+        join_all(homes.iter().map(|home| backend.rooms(home.home_id))),
+        join_all(homes.iter().map(|home| backend.scenes(home.home_id)))
+    );
+    let homes = homes
+        .into_iter()
+        .zip(room_results.into_iter().zip(scene_results))
+        .map(|(home, (rooms, scenes))| {
+            let rooms = rooms.unwrap_or_else(|e| {
+                tracing::warn!("Failed to fetch rooms for home {}: {}", home.home_id, e);
+                Vec::new()
+            });
+            let scenes = scenes.unwrap_or_else(|e| {
+                tracing::warn!("Failed to fetch scenes for home {}: {}", home.home_id, e);
+                Vec::new()
+            });
+            HomeDetails {
+                home,
+                rooms,
+                scenes,
+            }
+        })
+        .collect();
+
+    *cache.write().await = Some(HomeData {
+        fetched_at: now,
+        homes,
+    });
+}
+
+/// Arranges devices for the configured layout. Devices that belong to no room end up
+/// at the top level after the rooms, so a project without home permissions stays flat.
+pub fn group_devices<'a>(
+    devices: &[&'a TuyaDevice],
+    layout: MenuLayout,
+    homes: &[HomeDetails],
+) -> Vec<DeviceGroup<'a>> {
+    match layout {
+        MenuLayout::Flat => vec![DeviceGroup {
+            path: Vec::new(),
+            devices: devices.to_vec(),
+        }],
+        MenuLayout::ByRoom => group_by_room(devices, homes),
+        MenuLayout::ByCategory => {
+            let mut categories: BTreeMap<String, Vec<&TuyaDevice>> = BTreeMap::new();
+            for device in devices {
+                categories
+                    .entry(category_label(&device.category))
+                    .or_default()
+                    .push(device);
+            }
+            categories
+                .into_iter()
+                .map(|(label, devices)| DeviceGroup {
+                    path: vec![label],
+                    devices,
+                })
+                .collect()
+        }
+    }
+}
+
+/// Rooms keep the order the app shows them in. With several homes, rooms are nested
+/// under a submenu per home.
+fn group_by_room<'a>(devices: &[&'a TuyaDevice], homes: &[HomeDetails]) -> Vec<DeviceGroup<'a>> {
+    let mut groups = Vec::new();
+    let mut placed: HashSet<&str> = HashSet::new();
+
+    for details in homes {
+        for room in &details.rooms {
+            let members: Vec<&TuyaDevice> = devices
+                .iter()
+                .filter(|d| room.device_ids.contains(&d.id) && !placed.contains(d.id.as_str()))
+                .copied()
+                .collect();
+            if members.is_empty() {
+                continue;
+            }
+            placed.extend(members.iter().map(|d| d.id.as_str()));
+
+            let mut path = Vec::new();
+            if homes.len() > 1 {
+                path.push(details.home.name.clone());
+            }
+            path.push(room.name.clone());
+            groups.push(DeviceGroup {
+                path,
+                devices: members,
+            });
+        }
+    }
+
+    let unassigned: Vec<&TuyaDevice> = devices
+        .iter()
+        .filter(|d| !placed.contains(d.id.as_str()))
+        .copied()
+        .collect();
+    if !unassigned.is_empty() {
+        groups.push(DeviceGroup {
+            path: Vec::new(),
+            devices: unassigned,
+        });
+    }
+    groups
+}
+
+fn category_label(category: &str) -> String {
+    match category {
+        "kg" | "tdq" => "Switches".to_string(),
+        "cz" | "pc" => "Plugs".to_string(),
+        "dj" | "dd" | "xdd" | "fwd" | "dc" => "Lights".to_string(),
+        "fs" => "Fans".to_string(),
+        "kt" | "infrared_ac" => "Air Conditioners".to_string(),
+        "wk" | "qn" => "Heating".to_string(),
+        "cl" | "clkg" => "Curtains".to_string(),
+        "kj" | "jsq" | "cs" => "Air Quality".to_string(),
+        "wsdcg" | "mcs" | "pir" | "ywbj" | "sj" | "co2bj" => "Sensors".to_string(),
+        "sd" => "Vacuums".to_string(),
+        "sp" => "Cameras".to_string(),
+        "ms" => "Locks".to_string(),
+        "wnykq" | "wgsxj" => "Remotes".to_string(),
+        "" => "Other".to_string(),
+        other => other.to_uppercase(),
+    }
+}
+
 fn format_label(code: &str) -> String {
     code.split('_')
         .map(|word| {
@@ -35,18 +315,315 @@ fn format_label(code: &str) -> String {
         .join(" ")
 }
 
+/// Read-only DPs shown as informational items, with the scale and unit assumed when
+/// the device's spec doesn't give them.
+const READINGS: [(&str, &str, u32, &str); 5] = [
+    ("va_temperature", "Temperature", 1, "°C"),
+    ("va_humidity", "Humidity", 0, "%"),
+    ("cur_power", "Power", 1, "W"),
+    ("cur_voltage", "Voltage", 1, "V"),
+    ("battery_percentage", "Battery", 0, "%"),
+];
+
+fn reading_key(device_id: &str, code: &str) -> String {
+    format!("reading:{}:{}", device_id, code)
+}
+
+/// The label of a sensor reading, such as "Power: 42.3 W". None for DPs that aren't
+/// readings.
+pub fn reading_label(
+    code: &str,
+    value: &TuyaValue,
+    spec: Option<&DeviceSpecification>,
+) -> Option<String> {
+    if code == "doorcontact_state" {
+        let open = value.as_bool()?;
+        return Some(format!("Door: {}", if open { "Open" } else { "Closed" }));
+    }
+
+    let (_, label, scale, unit) = READINGS.iter().find(|(c, ..)| *c == code)?;
+    let scaled = match spec.and_then(|spec| spec.schema(code)) {
+        Some(schema @ FunctionSchema::Integer { .. }) => value.as_scaled(schema)?,
+        _ => ScaledValue {
+            raw: value.as_i64()?,
+            scale: *scale,
+            step: 1,
+            unit: unit.to_string(),
+        },
+    };
+    let number = format!("{:.*}", scaled.scale as usize, scaled.value());
+    let unit = scaled.unit.trim();
+    Some(match unit {
+        "" => format!("{}: {}", label, number),
+        "%" | "℃" | "℉" | "°C" | "°F" => format!("{}: {}{}", label, number, unit),
+        _ => format!("{}: {} {}", label, number, unit),
+    })
+}
+
+fn control_label(code: &str) -> String {
+    match code {
+        "fan_speed_percent" => "Fan Speed".to_string(),
+        "temp_set" => "Temperature".to_string(),
+        "windspeed" => "AC Fan Speed".to_string(),
+        "bright_value" | "bright_value_v2" => "Brightness".to_string(),
+        "temp_value" | "temp_value_v2" => "Colour Temperature".to_string(),
+        "colour_data" | "colour_data_v2" => "Colour".to_string(),
+        "percent_control" => "Position".to_string(),
+        _ => format_label(code),
+    }
+}
+
+/// Expands an integer range into menu options, widening the stride for large ranges
+/// so the submenu stays usable. The maximum is always offered.
+fn integer_options(min: i64, max: i64, step: i64) -> Vec<i64> {
+    if max < min {
+        return Vec::new();
+    }
+
+    let step = step.max(1);
+    let count = (max - min) / step + 1;
+    let stride = if count > MAX_INTEGER_OPTIONS {
+        step * ((count - 1) as u64).div_ceil(MAX_INTEGER_OPTIONS as u64 - 1) as i64
+    } else {
+        step
+    };
+
+    let mut options: Vec<i64> = (min..=max).step_by(stride as usize).collect();
+    if options.last() != Some(&max) {
+        options.push(max);
+    }
+    options
+}
+
+/// Raw values offered for an integer DP: named light levels, or else the range.
+fn offered_integers(code: &str, min: i64, max: i64, step: i64) -> Vec<i64> {
+    match light::light_levels(code, min, max) {
+        Some(levels) => levels.into_iter().map(|(raw, _)| raw).collect(),
+        None => integer_options(min, max, step),
+    }
+}
+
+/// Returns the option value that should carry the check mark for a DP. Integer DPs
+/// snap to the closest offered option, since devices may report values between steps.
+/// Colours are checked by hue, and only when it matches a preset.
+fn selected_option(code: &str, schema: &FunctionSchema, value: &TuyaValue) -> String {
+    match schema {
+        FunctionSchema::Integer { min, max, step, .. } => value
+            .as_scaled(schema)
+            .and_then(|scaled| scaled.nearest(&offered_integers(code, *min, *max, *step)))
+            .map(|raw| raw.to_string())
+            .unwrap_or_default(),
+        FunctionSchema::Json if light::is_colour(code) => light::colour_hue(value)
+            .map(|hue| hue.to_string())
+            .unwrap_or_default(),
+        _ => value.to_string(),
+    }
+}
+
+fn append_option_submenu(
+    app: &AppHandle,
+    parent: &Submenu<Wry>,
+    device_id: &str,
+    code: &str,
+    current: &str,
+    options: Vec<(String, String)>,
+    registry: &mut HashMap<String, CheckMenuItem<Wry>>,
+) -> Result<(), AppError> {
+    if options.is_empty() {
+        return Ok(());
+    }
+
+    let option_submenu =
+        Submenu::new(app, control_label(code), true).map_err(|e| AppError::Tray(e.to_string()))?;
+
+    for (value, label) in options {
+        let id = format!("set:{}:{}:{}", device_id, code, value);
+        let item = CheckMenuItem::with_id(app, &id, &label, true, current == value, None::<&str>)
+            .map_err(|e| AppError::Tray(e.to_string()))?;
+        let registry_key = format!("{}:{}:{}", device_id, code, value);
+        registry.insert(registry_key, item.clone());
+        option_submenu
+            .append(&item)
+            .map_err(|e| AppError::Tray(e.to_string()))?;
+    }
+
+    parent
+        .append(&option_submenu)
+        .map_err(|e| AppError::Tray(e.to_string()))?;
+    Ok(())
+}
+
+/// Colour presets for a light. Items are registered like option submenus, keyed by
+/// hue, so in-place updates move the check mark.
+fn append_colour_submenu(
+    app: &AppHandle,
+    parent: &Submenu<Wry>,
+    device_id: &str,
+    code: &str,
+    current: &str,
+    registry: &mut HashMap<String, CheckMenuItem<Wry>>,
+) -> Result<(), AppError> {
+    let colour_submenu =
+        Submenu::new(app, control_label(code), true).map_err(|e| AppError::Tray(e.to_string()))?;
+
+    for (hue, label) in light::COLOUR_PRESETS {
+        let id = format!("colour:{}:{}:{}", device_id, code, hue);
+        let checked = current == hue.to_string();
+        let item = CheckMenuItem::with_id(app, &id, label, true, checked, None::<&str>)
+            .map_err(|e| AppError::Tray(e.to_string()))?;
+        registry.insert(format!("{}:{}:{}", device_id, code, hue), item.clone());
+        colour_submenu
+            .append(&item)
+            .map_err(|e| AppError::Tray(e.to_string()))?;
+    }
+
+    parent
+        .append(&colour_submenu)
+        .map_err(|e| AppError::Tray(e.to_string()))?;
+    Ok(())
+}
+
+/// Open/Stop/Close and a position submenu for curtains and blinds. Position items are
+/// registered under `percent_control`, the DP they set.
+fn append_curtain_controls(
+    app: &AppHandle,
+    parent: &Submenu<Wry>,
+    device_id: &str,
+    status: &[TuyaDeviceStatus],
+    spec: Option<&DeviceSpecification>,
+    registry: &mut HashMap<String, CheckMenuItem<Wry>>,
+) -> Result<(), AppError> {
+    for (value, label) in curtain::actions(spec) {
+        let id = format!("set:{}:control:{}", device_id, value);
+        let item = MenuItem::with_id(app, &id, label, true, None::<&str>)
+            .map_err(|e| AppError::Tray(e.to_string()))?;
+        parent
+            .append(&item)
+            .map_err(|e| AppError::Tray(e.to_string()))?;
+    }
+
+    if spec.is_some_and(|spec| spec.function(curtain::POSITION_CONTROL).is_none()) {
+        return Ok(());
+    }
+    let current = curtain::position_code(status)
+        .and_then(|code| status.iter().find(|s| s.code == code))
+        .map(|s| curtain::selected_position(&s.value))
+        .unwrap_or_default();
+    let options = curtain::POSITIONS
+        .iter()
+        .map(|percent| (percent.to_string(), format!("{}%", percent)))
+        .collect();
+    append_option_submenu(
+        app,
+        parent,
+        device_id,
+        curtain::POSITION_CONTROL,
+        &current,
+        options,
+        registry,
+    )
+}
+
+/// "Turn off in…" for a switch, labelled with the time left on a running countdown.
+fn append_countdown_submenu(
+    app: &AppHandle,
+    parent: &Submenu<Wry>,
+    device_id: &str,
+    code: &str,
+    remaining: Option<i64>,
+    labels: &mut HashMap<String, Box<dyn LabelItem + Send + Sync>>,
+) -> Result<(), AppError> {
+    let timer_submenu = Submenu::new(app, countdown::countdown_label(remaining), true)
+        .map_err(|e| AppError::Tray(e.to_string()))?;
+
+    for minutes in COUNTDOWN_MINUTES {
+        let id = format!("timer:{}:{}:{}", device_id, code, minutes);
+        let item = MenuItem::with_id(
+            app,
+            &id,
+            countdown::duration_label(minutes),
+            true,
+            None::<&str>,
+        )
+        .map_err(|e| AppError::Tray(e.to_string()))?;
+        timer_submenu
+            .append(&item)
+            .map_err(|e| AppError::Tray(e.to_string()))?;
+    }
+    let cancel = MenuItem::with_id(
+        app,
+        format!("timer:{}:{}:0", device_id, code),
+        "Cancel",
+        remaining.is_some(),
+        None::<&str>,
+    )
+    .map_err(|e| AppError::Tray(e.to_string()))?;
+    timer_submenu
+        .append(&cancel)
+        .map_err(|e| AppError::Tray(e.to_string()))?;
+
+    parent
+        .append(&timer_submenu)
+        .map_err(|e| AppError::Tray(e.to_string()))?;
+    labels.insert(
+        format!("timer:{}:{}", device_id, code),
+        Box::new(timer_submenu),
+    );
+    Ok(())
+}
+
+/// Builds the controls for one device from its specification. Without a specification
+/// only boolean DPs can be inferred, so those are the only controls offered. Switches
+/// also get a "Turn off in…" submenu; `timers` holds the end of each app-side timer.
+/// Sensor readings come first as disabled items, registered in `labels`. Curtains get
+/// their own controls in place of the generic ones for their DPs.
 pub fn build_device_submenu(
     app: &AppHandle,
     device: &TuyaDevice,
     status: &[TuyaDeviceStatus],
+    spec: Option<&DeviceSpecification>,
+    timers: &HashMap<String, i64>,
     registry: &mut HashMap<String, CheckMenuItem<Wry>>,
+    labels: &mut HashMap<String, Box<dyn LabelItem + Send + Sync>>,
 ) -> Result<Submenu<Wry>, AppError> {
+    let now = Utc::now().timestamp();
     let submenu =
         Submenu::new(app, &device.name, true).map_err(|e| AppError::Tray(e.to_string()))?;
 
+    let mut readings = 0;
+    for s in status {
+        let Some(label) = reading_label(&s.code, &s.value, spec) else {
+            continue;
+        };
+        let key = reading_key(&device.id, &s.code);
+        let item = MenuItem::with_id(app, &key, label, false, None::<&str>)
+            .map_err(|e| AppError::Tray(e.to_string()))?;
+        submenu
+            .append(&item)
+            .map_err(|e| AppError::Tray(e.to_string()))?;
+        labels.insert(key, Box::new(item));
+        readings += 1;
+    }
+
+    let is_curtain = curtain::is_curtain(&device.category);
+    if is_curtain {
+        append_curtain_controls(app, &submenu, &device.id, status, spec, registry)?;
+    }
+
     for s in status {
-        match s.code.as_str() {
-            _ if s.value.as_bool().is_some() => {
+        if reading_label(&s.code, &s.value, spec).is_some()
+            || (is_curtain && curtain::is_curtain_code(&s.code))
+        {
+            continue;
+        }
+        let schema = match spec {
+            Some(spec) => spec.function(&s.code),
+            None if s.value.as_bool().is_some() => Some(&FunctionSchema::Boolean),
+            None => None,
+        };
+
+        match schema {
+            Some(FunctionSchema::Boolean) => {
                 let checked = s.value.as_bool().unwrap_or(false);
                 let label = format_label(&s.code);
                 let id = format!("toggle:{}:{}", device.id, s.code);
@@ -58,123 +635,81 @@ pub fn build_device_submenu(
                 submenu
                     .append(&item)
                     .map_err(|e| AppError::Tray(e.to_string()))?;
-            }
-
-            "fan_speed_percent" => {
-                let current = s.value.as_i64().unwrap_or(1) as i32;
-                let speed_submenu = Submenu::new(app, "Fan Speed", true)
-                    .map_err(|e| AppError::Tray(e.to_string()))?;
 
-                for level in 1..=FAN_SPEED_LEVELS {
-                    let id = format!("set:{}:fan_speed_percent:{}", device.id, level);
-                    let item = CheckMenuItem::with_id(
-                        app,
-                        &id,
-                        level.to_string(),
-                        true,
-                        current == level,
-                        None::<&str>,
-                    )
-                    .map_err(|e| AppError::Tray(e.to_string()))?;
-                    let registry_key = format!("{}:fan_speed_percent:{}", device.id, level);
-                    registry.insert(registry_key, item.clone());
-                    speed_submenu
-                        .append(&item)
-                        .map_err(|e| AppError::Tray(e.to_string()))?;
+                if countdown::is_switch(&s.code) {
+                    let remaining =
+                        countdown::remaining_secs(&device.id, &s.code, status, timers, now);
+                    append_countdown_submenu(
+                        app, &submenu, &device.id, &s.code, remaining, labels,
+                    )?;
                 }
-                submenu
-                    .append(&speed_submenu)
-                    .map_err(|e| AppError::Tray(e.to_string()))?;
             }
 
-            "temp_set" => {
-                let current = s.value.as_i64().unwrap_or(20) as i32;
-                let temp_submenu = Submenu::new(app, "Temperature", true)
-                    .map_err(|e| AppError::Tray(e.to_string()))?;
+            // Countdown DPs are set through their switch's "Turn off in…" submenu
+            Some(FunctionSchema::Integer { .. }) if s.code.starts_with("countdown") => {}
 
-                for temp in TEMP_MIN..=TEMP_MAX {
-                    let id = format!("set:{}:temp_set:{}", device.id, temp);
-                    let label = format!("{}°C", temp);
-                    let item = CheckMenuItem::with_id(
-                        app,
-                        &id,
-                        &label,
-                        true,
-                        current == temp,
-                        None::<&str>,
-                    )
-                    .map_err(|e| AppError::Tray(e.to_string()))?;
-                    let registry_key = format!("{}:temp_set:{}", device.id, temp);
-                    registry.insert(registry_key, item.clone());
-                    temp_submenu
-                        .append(&item)
-                        .map_err(|e| AppError::Tray(e.to_string()))?;
-                }
-                submenu
-                    .append(&temp_submenu)
-                    .map_err(|e| AppError::Tray(e.to_string()))?;
+            Some(FunctionSchema::Enum { range }) => {
+                let options = range
+                    .iter()
+                    .map(|value| (value.clone(), format_label(value)))
+                    .collect();
+                append_option_submenu(
+                    app,
+                    &submenu,
+                    &device.id,
+                    &s.code,
+                    &s.value.to_string(),
+                    options,
+                    registry,
+                )?;
             }
 
-            "windspeed" => {
-                let current = s.value.as_i64().unwrap_or(1) as i32;
-                let speed_submenu = Submenu::new(app, "AC Fan Speed", true)
-                    .map_err(|e| AppError::Tray(e.to_string()))?;
-
-                for level in 1..=AC_FAN_SPEED_LEVELS {
-                    let id = format!("set:{}:windspeed:{}", device.id, level);
-                    let item = CheckMenuItem::with_id(
-                        app,
-                        &id,
-                        level.to_string(),
-                        true,
-                        current == level,
-                        None::<&str>,
-                    )
-                    .map_err(|e| AppError::Tray(e.to_string()))?;
-                    let registry_key = format!("{}:windspeed:{}", device.id, level);
-                    registry.insert(registry_key, item.clone());
-                    speed_submenu
-                        .append(&item)
-                        .map_err(|e| AppError::Tray(e.to_string()))?;
-                }
-                submenu
-                    .append(&speed_submenu)
-                    .map_err(|e| AppError::Tray(e.to_string()))?;
+            Some(schema @ FunctionSchema::Integer { min, max, step, .. }) => {
+                let options = match light::light_levels(&s.code, *min, *max) {
+                    Some(levels) => levels
+                        .into_iter()
+                        .map(|(raw, label)| (raw.to_string(), label))
+                        .collect(),
+                    None => integer_options(*min, *max, *step)
+                        .into_iter()
+                        .filter_map(|raw| ScaledValue::new(raw, schema))
+                        .map(|value| (value.raw.to_string(), value.to_string()))
+                        .collect(),
+                };
+                append_option_submenu(
+                    app,
+                    &submenu,
+                    &device.id,
+                    &s.code,
+                    &selected_option(&s.code, schema, &s.value),
+                    options,
+                    registry,
+                )?;
             }
 
-            "mode" => {
-                let current = s.value.as_string().unwrap_or("");
-                let mode_submenu = Submenu::new(app, "AC Mode", true)
-                    .map_err(|e| AppError::Tray(e.to_string()))?;
-
-                for mode in AC_MODES {
-                    let id = format!("set:{}:mode:{}", device.id, mode);
-                    let label = format_label(mode);
-                    let item = CheckMenuItem::with_id(
-                        app,
-                        &id,
-                        &label,
-                        true,
-                        current == *mode,
-                        None::<&str>,
-                    )
-                    .map_err(|e| AppError::Tray(e.to_string()))?;
-                    let registry_key = format!("{}:mode:{}", device.id, mode);
-                    registry.insert(registry_key, item.clone());
-                    mode_submenu
-                        .append(&item)
-                        .map_err(|e| AppError::Tray(e.to_string()))?;
-                }
-                submenu
-                    .append(&mode_submenu)
-                    .map_err(|e| AppError::Tray(e.to_string()))?;
+            Some(schema @ FunctionSchema::Json) if light::is_colour(&s.code) => {
+                append_colour_submenu(
+                    app,
+                    &submenu,
+                    &device.id,
+                    &s.code,
+                    &selected_option(&s.code, schema, &s.value),
+                    registry,
+                )?;
             }
 
-            _ => {}
+            Some(FunctionSchema::String) | Some(FunctionSchema::Json) | None => {}
         }
     }
 
     let items = submenu.items().map_err(|e| AppError::Tray(e.to_string()))?;
+    if readings > 0 && items.len() > readings {
+        let separator =
+            PredefinedMenuItem::separator(app).map_err(|e| AppError::Tray(e.to_string()))?;
+        submenu
+            .insert(&separator, readings)
+            .map_err(|e| AppError::Tray(e.to_string()))?;
+    }
     if items.is_empty() {
         let no_controls = MenuItem::with_id(app, "no_controls", "No controls", false, None::<&str>)
             .map_err(|e| AppError::Tray(e.to_string()))?;
@@ -186,6 +721,280 @@ pub fn build_device_submenu(
     Ok(submenu)
 }
 
+/// Controls for an IR remote: power, mode, setpoint and fan speed for an air
+/// conditioner, the learned keys for anything else. AC items are registered under the
+/// statuses `remote::attach_remotes` gives the remote, so in-place updates move them.
+fn build_remote_submenu(
+    app: &AppHandle,
+    device: &TuyaDevice,
+    details: &RemoteDetails,
+    status: &[TuyaDeviceStatus],
+    registry: &mut HashMap<String, CheckMenuItem<Wry>>,
+) -> Result<Submenu<Wry>, AppError> {
+    let submenu =
+        Submenu::new(app, &device.name, true).map_err(|e| AppError::Tray(e.to_string()))?;
+
+    if !details.remote.is_air_conditioner() {
+        for key in &details.keys {
+            let label = if key.key_name.is_empty() {
+                format!("Key {}", key.id)
+            } else {
+                key.key_name.clone()
+            };
+            let id = format!("ir:{}:key:{}", device.id, key.id);
+            let item = MenuItem::with_id(app, &id, &label, true, None::<&str>)
+                .map_err(|e| AppError::Tray(e.to_string()))?;
+            submenu
+                .append(&item)
+                .map_err(|e| AppError::Tray(e.to_string()))?;
+        }
+        if details.keys.is_empty() {
+            let no_keys = MenuItem::with_id(app, "no_keys", "No learned keys", false, None::<&str>)
+                .map_err(|e| AppError::Tray(e.to_string()))?;
+            submenu
+                .append(&no_keys)
+                .map_err(|e| AppError::Tray(e.to_string()))?;
+        }
+        return Ok(submenu);
+    }
+
+    let scene = remote::ac_scene(status);
+    let power = CheckMenuItem::with_id(
+        app,
+        format!("ir:{}:power:toggle", device.id),
+        "Power",
+        true,
+        scene.is_some_and(|scene| scene.power != 0),
+        None::<&str>,
+    )
+    .map_err(|e| AppError::Tray(e.to_string()))?;
+    registry.insert(format!("{}:power", device.id), power.clone());
+    submenu
+        .append(&power)
+        .map_err(|e| AppError::Tray(e.to_string()))?;
+
+    let named = |options: &[(i64, &str)]| -> Vec<(i64, String)> {
+        options
+            .iter()
+            .map(|(value, label)| (*value, label.to_string()))
+            .collect()
+    };
+    let settings = [
+        (
+            "mode",
+            "Mode",
+            scene.map(|s| s.mode),
+            named(&remote::AC_MODES),
+        ),
+        (
+            "temp",
+            "Temperature",
+            scene.map(|s| s.temp),
+            remote::AC_TEMPERATURES
+                .map(|temp| (temp, format!("{}°C", temp)))
+                .collect(),
+        ),
+        (
+            "wind",
+            "Fan Speed",
+            scene.map(|s| s.wind),
+            named(&remote::AC_WIND_SPEEDS),
+        ),
+    ];
+    for (field, label, current, options) in settings {
+        let setting_submenu =
+            Submenu::new(app, label, true).map_err(|e| AppError::Tray(e.to_string()))?;
+        for (value, option_label) in options {
+            let id = format!("ir:{}:{}:{}", device.id, field, value);
+            let checked = current == Some(value);
+            let item = CheckMenuItem::with_id(app, &id, &option_label, true, checked, None::<&str>)
+                .map_err(|e| AppError::Tray(e.to_string()))?;
+            registry.insert(format!("{}:{}:{}", device.id, field, value), item.clone());
+            setting_submenu
+                .append(&item)
+                .map_err(|e| AppError::Tray(e.to_string()))?;
+        }
+        submenu
+            .append(&setting_submenu)
+            .map_err(|e| AppError::Tray(e.to_string()))?;
+    }
+
+    Ok(submenu)
+}
+
+fn build_error_submenu(app: &AppHandle, device: &TuyaDevice) -> Result<Submenu<Wry>, AppError> {
+    let submenu = Submenu::new(app, format!("{} (error)", device.name), true)
+        .map_err(|e| AppError::Tray(e.to_string()))?;
+    let error_item = MenuItem::with_id(
+        app,
+        format!("error_{}", device.id),
+        "Failed to load status",
+        false,
+        None::<&str>,
+    )
+    .map_err(|e| AppError::Tray(e.to_string()))?;
+    submenu
+        .append(&error_item)
+        .map_err(|e| AppError::Tray(e.to_string()))?;
+    Ok(submenu)
+}
+
+/// Returns the submenu for a group path, creating it and any missing ancestors. None
+/// stands for the top level of the menu.
+fn group_submenu(
+    app: &AppHandle,
+    menu: &Menu<Wry>,
+    submenus: &mut HashMap<Vec<String>, Submenu<Wry>>,
+    path: &[String],
+) -> Result<Option<Submenu<Wry>>, AppError> {
+    let Some((label, ancestors)) = path.split_last() else {
+        return Ok(None);
+    };
+    if let Some(existing) = submenus.get(path) {
+        return Ok(Some(existing.clone()));
+    }
+
+    let submenu = Submenu::new(app, label, true).map_err(|e| AppError::Tray(e.to_string()))?;
+    match group_submenu(app, menu, submenus, ancestors)? {
+        Some(parent) => parent.append(&submenu),
+        None => menu.append(&submenu),
+    }
+    .map_err(|e| AppError::Tray(e.to_string()))?;
+
+    submenus.insert(path.to_vec(), submenu.clone());
+    Ok(Some(submenu))
+}
+
+/// A "Scenes" submenu. With several homes each gets its own submenu; homes
+/// without scenes are left out entirely.
+fn scenes_submenu(
+    app: &AppHandle,
+    homes: &[HomeDetails],
+) -> Result<Option<Submenu<Wry>>, AppError> {
+    let homes: Vec<&HomeDetails> = homes.iter().filter(|h| !h.scenes.is_empty()).collect();
+    if homes.is_empty() {
+        return Ok(None);
+    }
+
+    let scenes_submenu =
+        Submenu::new(app, "Scenes", true).map_err(|e| AppError::Tray(e.to_string()))?;
+
+    for details in &homes {
+        let parent = if homes.len() > 1 {
+            let home_submenu = Submenu::new(app, &details.home.name, true)
+                .map_err(|e| AppError::Tray(e.to_string()))?;
+            scenes_submenu
+                .append(&home_submenu)
+                .map_err(|e| AppError::Tray(e.to_string()))?;
+            home_submenu
+        } else {
+            scenes_submenu.clone()
+        };
+
+        for scene in &details.scenes {
+            let id = format!("scene:{}:{}", details.home.home_id, scene.scene_id);
+            let item = MenuItem::with_id(app, &id, &scene.name, scene.enabled, None::<&str>)
+                .map_err(|e| AppError::Tray(e.to_string()))?;
+            parent
+                .append(&item)
+                .map_err(|e| AppError::Tray(e.to_string()))?;
+        }
+    }
+
+    Ok(Some(scenes_submenu))
+}
+
+/// A "Macros" submenu listing the macros defined in the config.
+fn macros_submenu(app: &AppHandle, macros: &[Macro]) -> Result<Option<Submenu<Wry>>, AppError> {
+    if macros.is_empty() {
+        return Ok(None);
+    }
+
+    let macros_submenu =
+        Submenu::new(app, "Macros", true).map_err(|e| AppError::Tray(e.to_string()))?;
+    for definition in macros {
+        let id = format!("macro:{}", definition.id);
+        let enabled = !definition.steps.is_empty();
+        let item = MenuItem::with_id(app, &id, &definition.name, enabled, None::<&str>)
+            .map_err(|e| AppError::Tray(e.to_string()))?;
+        macros_submenu
+            .append(&item)
+            .map_err(|e| AppError::Tray(e.to_string()))?;
+    }
+
+    Ok(Some(macros_submenu))
+}
+
+/// A "Today's Schedule" submenu with today's sun times and schedule runs, in the
+/// computer's time. The entries are informational, so they are all disabled.
+fn today_submenu(
+    app: &AppHandle,
+    config: &AppConfig,
+    devices: &[TuyaDevice],
+) -> Result<Option<Submenu<Wry>>, AppError> {
+    let today = Local::now().date_naive();
+    let bounds = [today, today + chrono::Days::new(1)].map(|date| {
+        date.and_hms_opt(0, 0, 0)
+            .and_then(|midnight| Local.from_local_datetime(&midnight).earliest())
+            .map(|midnight| midnight.with_timezone(&Utc))
+    });
+    let [Some(start), Some(end)] = bounds else {
+        return Ok(None);
+    };
+
+    let zones = DeviceZones::from_devices(devices);
+    let runs = runs_between(
+        &config.schedules,
+        |schedule| zones.for_schedule(schedule, &config.macros),
+        config.location,
+        start,
+        end,
+    );
+    let sun_times: Vec<(SolarEvent, DateTime<Utc>)> = config
+        .location
+        .map(|location| {
+            SolarEvent::ALL
+                .into_iter()
+                .filter_map(|event| Some((event, solar::event_time(today, location, event)?)))
+                .collect()
+        })
+        .unwrap_or_default();
+    if runs.is_empty() && sun_times.is_empty() {
+        return Ok(None);
+    }
+
+    let submenu =
+        Submenu::new(app, "Today's Schedule", true).map_err(|e| AppError::Tray(e.to_string()))?;
+    let local_time = |at: &DateTime<Utc>| at.with_timezone(&Local).format("%H:%M").to_string();
+    let append_info = |id: String, label: String| -> Result<(), AppError> {
+        let item = MenuItem::with_id(app, id, label, false, None::<&str>)
+            .map_err(|e| AppError::Tray(e.to_string()))?;
+        submenu
+            .append(&item)
+            .map_err(|e| AppError::Tray(e.to_string()))
+    };
+
+    for (event, at) in &sun_times {
+        append_info(
+            format!("today_sun_{:?}", event),
+            format!("{} {}", event.label(), local_time(at)),
+        )?;
+    }
+    if !sun_times.is_empty() && !runs.is_empty() {
+        submenu
+            .append(&PredefinedMenuItem::separator(app).map_err(|e| AppError::Tray(e.to_string()))?)
+            .map_err(|e| AppError::Tray(e.to_string()))?;
+    }
+    for (index, (at, schedule)) in runs.iter().enumerate() {
+        append_info(
+            format!("today_run_{}", index),
+            format!("{}  {}", local_time(at), schedule.name),
+        )?;
+    }
+
+    Ok(Some(submenu))
+}
+
 async fn append_update_item(
     app: &AppHandle,
     menu: &Menu<Wry>,
@@ -280,72 +1089,424 @@ pub async fn build_error_menu(
     Ok(menu)
 }
 
-pub async fn build_device_menu_with_cache(
+/// Returns the cached device list, refetching it once it is older than `max_age_secs`
+/// (so 0 always refetches). Names, online flags and categories change rarely, so the
+/// list is refreshed much less often than statuses.
+pub async fn refresh_device_list(
+    backend: &dyn DeviceBackend,
+    user_id: &str,
+    cache: &DeviceListCache,
+    max_age_secs: i64,
+) -> Result<Vec<TuyaDevice>, AppError> {
+    let now = chrono::Utc::now().timestamp();
+    if let Some(list) = cache.read().await.as_ref() {
+        if now - list.fetched_at < max_age_secs {
+            return Ok(list.devices.clone());
+        }
+    }
+
+    let devices = backend.list_devices(user_id).await?;
+    let mut cached = cache.write().await;
+    let offline_since = track_offline(cached.as_ref(), &devices, now);
+    *cached = Some(DeviceList {
+        fetched_at: now,
+        devices: devices.clone(),
+        offline_since,
+    });
+    Ok(devices)
+}
+
+/// Applies a pushed online/offline change to the cached list, so the menu can follow
+/// it without refetching the list. Returns false when the device isn't listed or
+/// already had that state.
+pub async fn set_device_online(
+    cache: &DeviceListCache,
+    device_id: &str,
+    online: bool,
+    now: i64,
+) -> bool {
+    let mut cached = cache.write().await;
+    let Some(list) = cached.as_mut() else {
+        return false;
+    };
+    let Some(device) = list.devices.iter_mut().find(|d| d.id == device_id) else {
+        return false;
+    };
+    if device.online == online {
+        return false;
+    }
+    device.online = online;
+    if online {
+        list.offline_since.remove(device_id);
+    } else {
+        list.offline_since.insert(device_id.to_string(), now);
+    }
+    true
+}
+
+/// The cloud's last update of a device, which for an offline device is about when it
+/// dropped off.
+fn cloud_offline_time(device: &TuyaDevice) -> Option<i64> {
+    Some(device.update_time.max(device.active_time)).filter(|time| *time > 0)
+}
+
+/// When each offline device in a fresh list went offline. A device the previous list
+/// had online went offline now; one that was already offline keeps its time. For
+/// anything else, the cloud's update time is the best guess, or else now.
+fn track_offline(
+    previous: Option<&DeviceList>,
+    devices: &[TuyaDevice],
+    now: i64,
+) -> HashMap<String, i64> {
+    devices
+        .iter()
+        .filter(|device| !device.online)
+        .map(|device| {
+            let known = previous.and_then(|list| list.offline_since.get(&device.id).copied());
+            let seen_online = previous
+                .is_some_and(|list| list.devices.iter().any(|d| d.id == device.id && d.online));
+            let since = match known {
+                Some(since) => since,
+                None if seen_online => now,
+                None => cloud_offline_time(device).unwrap_or(now),
+            };
+            (device.id.clone(), since)
+        })
+        .collect()
+}
+
+/// Fetches statuses of the online devices in `devices` and any specifications not
+/// cached yet. Specifications rarely change, so each device's is only fetched once.
+/// Devices in `skip` are left out: IR remotes, which have neither and are left to
+/// `remote::attach_remotes`, or devices whose status is already known.
+pub async fn fetch_device_snapshot(
+    backend: &dyn DeviceBackend,
+    devices: Vec<TuyaDevice>,
+    skip: &HashSet<String>,
+    spec_cache: &DeviceSpecCache,
+) -> DeviceSnapshot {
+    let online_devices: Vec<&TuyaDevice> = devices
+        .iter()
+        .filter(|d| d.online && !skip.contains(&d.id))
+        .collect();
+
+    let online_ids: Vec<String> = online_devices.iter().map(|d| d.id.clone()).collect();
+
+    let missing_specs: Vec<&TuyaDevice> = {
+        let specs = spec_cache.read().await;
+        online_devices
+            .iter()
+            .filter(|d| !specs.contains_key(&d.id))
+            .copied()
+            .collect()
+    };
+    let spec_futures: Vec<_> = missing_specs
+        .iter()
+        .map(|d| backend.specification(&d.id))
+        .collect();
+
+    let (statuses, spec_results) =
+        futures::join!(backend.statuses(&online_ids), join_all(spec_futures));
+
+    if !missing_specs.is_empty() {
+        let mut specs = spec_cache.write().await;
+        for (device, spec_result) in missing_specs.iter().zip(spec_results) {
+            match spec_result {
+                Ok(spec) => {
+                    specs.insert(device.id.clone(), spec);
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "Failed to fetch specification for device {}: {}",
+                        device.id,
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    DeviceSnapshot {
+        devices,
+        statuses,
+        saved_at: None,
+        offline_since: HashMap::new(),
+        remotes: HashMap::new(),
+    }
+}
+
+/// Fetches the snapshot for a menu build: the device list once it is older than
+/// `list_max_age_secs`, statuses every time, and homes and IR remotes when they are due.
+/// Fails when every status request failed, as a cached device list doesn't mean the
+/// cloud is reachable.
+pub async fn fetch_menu_snapshot(
+    backend: &dyn DeviceBackend,
+    user_id: &str,
+    spec_cache: &DeviceSpecCache,
+    home_cache: &HomeCache,
+    remote_cache: &RemoteCache,
+    device_list: &DeviceListCache,
+    list_max_age_secs: i64,
+) -> Result<DeviceSnapshot, AppError> {
+    let (devices, ()) = futures::join!(
+        refresh_device_list(backend, user_id, device_list, list_max_age_secs),
+        refresh_homes(backend, user_id, home_cache)
+    );
+    let devices = devices?;
+    remote::refresh_remotes(backend, &devices, remote_cache).await;
+    let remote_ids = remote_ids(remote_cache).await;
+    let mut snapshot = fetch_device_snapshot(backend, devices, &remote_ids, spec_cache).await;
+    if snapshot.statuses.values().all(Result::is_err) {
+        if let Some((_, Err(e))) = snapshot.statuses.drain().next() {
+            return Err(e);
+        }
+    }
+    remote::attach_remotes(&mut snapshot, remote_cache).await;
+    if let Some(list) = device_list.read().await.as_ref() {
+        snapshot.offline_since = list.offline_since.clone();
+    }
+    Ok(snapshot)
+}
+
+/// A snapshot of the cached device list and statuses, for following an online/offline
+/// change without polling everything. Only online devices without a cached status,
+/// such as one that just came back, are asked for theirs. None before the list was
+/// first fetched.
+pub async fn cached_snapshot(
+    backend: &dyn DeviceBackend,
+    device_list: &DeviceListCache,
+    status_cache: &DeviceStatusCache,
+    spec_cache: &DeviceSpecCache,
+    remote_cache: &RemoteCache,
+) -> Option<DeviceSnapshot> {
+    let list = device_list.read().await.clone()?;
+    let cached = status_cache.read().await.clone();
+
+    let mut skip = remote_ids(remote_cache).await;
+    skip.extend(cached.keys().cloned());
+    let mut snapshot = fetch_device_snapshot(backend, list.devices, &skip, spec_cache).await;
+    for device in snapshot.devices.iter().filter(|d| d.online) {
+        if let Some(status) = cached.get(&device.id) {
+            snapshot
+                .statuses
+                .insert(device.id.clone(), Ok(status.clone()));
+        }
+    }
+    remote::attach_remotes(&mut snapshot, remote_cache).await;
+    snapshot.offline_since = list.offline_since;
+    Some(snapshot)
+}
+
+async fn remote_ids(remote_cache: &RemoteCache) -> HashSet<String> {
+    remote_cache
+        .read()
+        .await
+        .as_ref()
+        .map(|data| data.remotes.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// A time of day for today, with the date otherwise.
+fn short_time(timestamp: i64) -> Option<String> {
+    let time = Local.timestamp_opt(timestamp, 0).single()?;
+    Some(if time.date_naive() == Local::now().date_naive() {
+        time.format("%H:%M").to_string()
+    } else {
+        time.format("%b %-d, %H:%M").to_string()
+    })
+}
+
+fn saved_label(saved_at: i64) -> String {
+    let time = short_time(saved_at).unwrap_or_else(|| "an earlier session".to_string());
+    format!("Saved devices from {} (not live)", time)
+}
+
+fn offline_label(device: &TuyaDevice, since: Option<i64>) -> String {
+    match since.and_then(short_time) {
+        Some(time) => format!("{} (offline since {})", device.name, time),
+        None => format!("{} (offline)", device.name),
+    }
+}
+
+/// Gives devices their configured display names and leaves out hidden ones.
+fn apply_overrides(
+    devices: Vec<TuyaDevice>,
+    overrides: &HashMap<String, DeviceOverride>,
+) -> Vec<TuyaDevice> {
+    devices
+        .into_iter()
+        .filter(|d| !overrides.get(&d.id).is_some_and(|o| o.hidden))
+        .map(|mut device| {
+            if let Some(name) = overrides
+                .get(&device.id)
+                .and_then(|o| o.name.as_deref())
+                .filter(|name| !name.trim().is_empty())
+            {
+                device.name = name.trim().to_string();
+            }
+            device
+        })
+        .collect()
+}
+
+/// Splits the devices to show into pinned ones and the rest. Both keep online devices
+/// ahead of offline ones, then go by sort order and finally cloud order.
+fn menu_order<'a>(
+    devices: &'a [TuyaDevice],
+    overrides: &HashMap<String, DeviceOverride>,
+    show_offline: bool,
+) -> (Vec<&'a TuyaDevice>, Vec<&'a TuyaDevice>) {
+    let mut shown: Vec<&TuyaDevice> = devices
+        .iter()
+        .filter(|d| d.online || show_offline)
+        .collect();
+    shown.sort_by_key(|d| {
+        let order = overrides.get(&d.id).and_then(|o| o.sort_order);
+        (!d.online, order.unwrap_or(i32::MAX))
+    });
+    shown
+        .into_iter()
+        .partition(|d| overrides.get(&d.id).is_some_and(|o| o.pinned))
+}
+
+/// Builds the device menu from a snapshot. A snapshot from the disk cache is marked as
+/// such at the top, since its statuses may be out of date.
+pub async fn build_device_menu(
     app: &AppHandle,
-    client: &SharedTuyaClient,
-    config: &ConfigManager,
+    app_config: &AppConfig,
     update_state: &SharedUpdateState,
+    snapshot: DeviceSnapshot,
+    spec_cache: &DeviceSpecCache,
+    home_cache: &HomeCache,
+    timers: &HashMap<String, i64>,
 ) -> Result<
     (
         Menu<Wry>,
         HashMap<String, Vec<TuyaDeviceStatus>>,
         HashMap<String, CheckMenuItem<Wry>>,
+        HashMap<String, Box<dyn LabelItem + Send + Sync>>,
     ),
     AppError,
 > {
     let menu = Menu::new(app).map_err(|e| AppError::Tray(e.to_string()))?;
     let mut device_statuses: HashMap<String, Vec<TuyaDeviceStatus>> = HashMap::new();
     let mut registry: HashMap<String, CheckMenuItem<Wry>> = HashMap::new();
+    let mut labels: HashMap<String, Box<dyn LabelItem + Send + Sync>> = HashMap::new();
 
     append_update_item(app, &menu, update_state).await?;
 
-    let user_id = config
-        .get_user_id()
-        .ok_or(AppError::Config("User ID not configured".to_string()))?;
-
-    let guard = client.read().await;
-    let tuya_client = guard.as_ref().ok_or(AppError::NotConfigured)?;
-
-    let devices = tuya_client.fetch_devices(&user_id).await?;
-    let online_devices: Vec<_> = devices.iter().filter(|d| d.online).collect();
-
-    let status_futures: Vec<_> = online_devices
-        .iter()
-        .map(|d| tuya_client.fetch_device_status(&d.id))
-        .collect();
-
-    let statuses: Vec<Result<Vec<TuyaDeviceStatus>, AppError>> = join_all(status_futures).await;
-
-    for (device, status_result) in online_devices.iter().zip(statuses.into_iter()) {
-        match status_result {
-            Ok(status) => {
-                device_statuses.insert(device.id.clone(), status.clone());
-                let submenu = build_device_submenu(app, device, &status, &mut registry)?;
-                menu.append(&submenu)
-                    .map_err(|e| AppError::Tray(e.to_string()))?;
-            }
-            Err(e) => {
-                tracing::warn!("Failed to fetch status for device {}: {}", device.id, e);
-                let submenu = Submenu::new(app, format!("{} (error)", device.name), true)
-                    .map_err(|e| AppError::Tray(e.to_string()))?;
-                let error_item = MenuItem::with_id(
-                    app,
-                    format!("error_{}", device.id),
-                    "Failed to load status",
-                    false,
-                    None::<&str>,
-                )
+    let DeviceSnapshot {
+        devices,
+        mut statuses,
+        saved_at,
+        offline_since,
+        remotes,
+    } = snapshot;
+    if let Some(saved_at) = saved_at {
+        let saved_item =
+            MenuItem::with_id(app, "saved", saved_label(saved_at), false, None::<&str>)
                 .map_err(|e| AppError::Tray(e.to_string()))?;
-                submenu
-                    .append(&error_item)
-                    .map_err(|e| AppError::Tray(e.to_string()))?;
-                menu.append(&submenu)
+        menu.append(&saved_item)
+            .map_err(|e| AppError::Tray(e.to_string()))?;
+        menu.append(
+            &PredefinedMenuItem::separator(app).map_err(|e| AppError::Tray(e.to_string()))?,
+        )
+        .map_err(|e| AppError::Tray(e.to_string()))?;
+    }
+    let overrides = &app_config.device_overrides;
+    let devices = apply_overrides(devices, overrides);
+    // Offline devices go last, greyed out, unless they are hidden altogether
+    let (pinned, shown_devices) = menu_order(&devices, overrides, app_config.show_offline_devices);
+    let specs = spec_cache.read().await;
+    let home_data = home_cache.read().await;
+    let homes = home_data
+        .as_ref()
+        .map(|data| data.homes.as_slice())
+        .unwrap_or_default();
+
+    let groups_empty = pinned.is_empty() && shown_devices.is_empty();
+    // Favourites sit at the top level, ahead of any room or category
+    let mut groups = Vec::new();
+    if !pinned.is_empty() {
+        groups.push(DeviceGroup {
+            path: Vec::new(),
+            devices: pinned,
+        });
+    }
+    groups.extend(group_devices(&shown_devices, app_config.menu_layout, homes));
+
+    let mut group_submenus = HashMap::new();
+    for group in groups {
+        let parent = group_submenu(app, &menu, &mut group_submenus, &group.path)?;
+
+        for device in group.devices {
+            if !device.online {
+                let since = offline_since
+                    .get(&device.id)
+                    .copied()
+                    .or_else(|| cloud_offline_time(device));
+                let submenu = Submenu::new(app, offline_label(device, since), false)
                     .map_err(|e| AppError::Tray(e.to_string()))?;
+                match &parent {
+                    Some(parent) => parent.append(&submenu),
+                    None => menu.append(&submenu),
+                }
+                .map_err(|e| AppError::Tray(e.to_string()))?;
+                continue;
+            }
+            let Some(status_result) = statuses.remove(&device.id) else {
+                continue;
+            };
+            let submenu = match (status_result, remotes.get(&device.id)) {
+                (Ok(status), Some(details)) => {
+                    let submenu =
+                        build_remote_submenu(app, device, details, &status, &mut registry)?;
+                    device_statuses.insert(device.id.clone(), status);
+                    submenu
+                }
+                (Ok(status), None) => {
+                    let hidden_codes = overrides
+                        .get(&device.id)
+                        .map(|o| o.hidden_codes.as_slice())
+                        .unwrap_or_default();
+                    let visible: Vec<TuyaDeviceStatus> = status
+                        .iter()
+                        .filter(|s| !hidden_codes.contains(&s.code))
+                        .cloned()
+                        .collect();
+                    let submenu = build_device_submenu(
+                        app,
+                        device,
+                        &visible,
+                        specs.get(&device.id),
+                        timers,
+                        &mut registry,
+                        &mut labels,
+                    )?;
+                    device_statuses.insert(device.id.clone(), status);
+                    submenu
+                }
+                (Err(e), _) => {
+                    tracing::warn!("Failed to fetch status for device {}: {}", device.id, e);
+                    build_error_submenu(app, device)?
+                }
+            };
+            match &parent {
+                Some(parent) => parent.append(&submenu),
+                None => menu.append(&submenu),
             }
+            .map_err(|e| AppError::Tray(e.to_string()))?;
         }
     }
 
-    if online_devices.is_empty() {
+    // Hidden devices stay in the status cache, so rules and notifications still see them
+    for (device_id, result) in statuses {
+        if let Ok(status) = result {
+            device_statuses.insert(device_id, status);
+        }
+    }
+
+    if groups_empty {
         let offline_count = devices.len();
         let label = if offline_count == 0 {
             "No devices found".to_string()
@@ -358,6 +1519,25 @@ pub async fn build_device_menu_with_cache(
             .map_err(|e| AppError::Tray(e.to_string()))?;
     }
 
+    let extras: Vec<Submenu<Wry>> = [
+        scenes_submenu(app, homes)?,
+        macros_submenu(app, &app_config.macros)?,
+        today_submenu(app, app_config, &devices)?,
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    if !extras.is_empty() {
+        menu.append(
+            &PredefinedMenuItem::separator(app).map_err(|e| AppError::Tray(e.to_string()))?,
+        )
+        .map_err(|e| AppError::Tray(e.to_string()))?;
+    }
+    for submenu in &extras {
+        menu.append(submenu)
+            .map_err(|e| AppError::Tray(e.to_string()))?;
+    }
+
     menu.append(&PredefinedMenuItem::separator(app).map_err(|e| AppError::Tray(e.to_string()))?)
         .map_err(|e| AppError::Tray(e.to_string()))?;
 
@@ -383,7 +1563,7 @@ pub async fn build_device_menu_with_cache(
     menu.append(&quit_item)
         .map_err(|e| AppError::Tray(e.to_string()))?;
 
-    Ok((menu, device_statuses, registry))
+    Ok((menu, device_statuses, registry, labels))
 }
 
 pub fn parse_command_id(id: &str) -> Option<(String, String, String)> {
@@ -423,12 +1603,116 @@ pub fn parse_command_id(id: &str) -> Option<(String, String, String)> {
     None
 }
 
-/// Returns true if the device set or the status codes changed between old and new caches.
+/// Parses `scene:<home>:<scene>` menu ids.
+pub fn parse_scene_id(id: &str) -> Option<(i64, String)> {
+    let (home_id, scene_id) = id.strip_prefix("scene:")?.split_once(':')?;
+    if scene_id.is_empty() {
+        return None;
+    }
+    Some((home_id.parse().ok()?, scene_id.to_string()))
+}
+
+/// Parses `macro:<id>` menu ids.
+pub fn parse_macro_id(id: &str) -> Option<&str> {
+    id.strip_prefix("macro:")
+        .filter(|macro_id| !macro_id.is_empty())
+}
+
+/// Parses `colour:<device>:<code>:<hue>` menu ids.
+pub fn parse_colour_id(id: &str) -> Option<(String, String, i64)> {
+    let (target, hue) = id.strip_prefix("colour:")?.rsplit_once(':')?;
+    let (device_id, code) = target.split_once(':')?;
+    if device_id.is_empty() || code.is_empty() {
+        return None;
+    }
+    Some((device_id.to_string(), code.to_string(), hue.parse().ok()?))
+}
+
+/// Parses `ir:<remote>:<setting>:<value>` menu ids, where the setting is an AC setting
+/// or `key` for a learned key.
+pub fn parse_remote_id(id: &str) -> Option<(String, String, String)> {
+    let (target, value) = id.strip_prefix("ir:")?.rsplit_once(':')?;
+    let (remote_id, field) = target.split_once(':')?;
+    if remote_id.is_empty() || field.is_empty() || value.is_empty() {
+        return None;
+    }
+    Some((remote_id.to_string(), field.to_string(), value.to_string()))
+}
+
+/// Parses `timer:<device>:<code>:<minutes>` menu ids; 0 minutes cancels the timer.
+pub fn parse_timer_id(id: &str) -> Option<(String, String, u32)> {
+    let (target, minutes) = id.strip_prefix("timer:")?.rsplit_once(':')?;
+    let (device_id, code) = target.split_once(':')?;
+    if device_id.is_empty() || code.is_empty() {
+        return None;
+    }
+    Some((
+        device_id.to_string(),
+        code.to_string(),
+        minutes.parse().ok()?,
+    ))
+}
+
+/// Rewrites "Turn off in…" labels whose time left changed. Returns the number of
+/// labels that were updated.
+pub fn refresh_countdown_labels<L: LabelItem>(
+    labels: &HashMap<String, L>,
+    statuses: &HashMap<String, Vec<TuyaDeviceStatus>>,
+    timers: &HashMap<String, i64>,
+    now: i64,
+) -> usize {
+    let mut updated = 0;
+
+    for (key, item) in labels {
+        let Some((device_id, code)) = key
+            .strip_prefix("timer:")
+            .and_then(|target| target.split_once(':'))
+        else {
+            continue;
+        };
+        let status = statuses
+            .get(device_id)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let label = countdown::countdown_label(countdown::remaining_secs(
+            device_id, code, status, timers, now,
+        ));
+        if item.label() != label {
+            item.set_label(&label);
+            updated += 1;
+        }
+    }
+
+    updated
+}
+
+/// What the menu shows of the device list itself.
+fn device_layout(devices: &[TuyaDevice]) -> Vec<(&str, &str, bool, &str)> {
+    devices
+        .iter()
+        .map(|d| {
+            (
+                d.id.as_str(),
+                d.name.as_str(),
+                d.online,
+                d.category.as_str(),
+            )
+        })
+        .collect()
+}
+
+/// Returns true if either layer changed the menu's structure: the device list (devices,
+/// names, online flags, categories) or the status codes between old and new caches.
 /// Value-only changes return false (those can be updated in-place).
 pub fn is_structural_change(
+    old_devices: &[TuyaDevice],
+    new_devices: &[TuyaDevice],
     old: &HashMap<String, Vec<TuyaDeviceStatus>>,
     new: &HashMap<String, Vec<TuyaDeviceStatus>>,
 ) -> bool {
+    if device_layout(old_devices) != device_layout(new_devices) {
+        return true;
+    }
     if old.len() != new.len() {
         return true;
     }
@@ -452,10 +1736,12 @@ pub fn is_structural_change(
     false
 }
 
-/// Updates check menu items in-place by comparing old and new status caches.
-/// Returns the number of items that were updated.
-pub fn update_menu_items_in_place(
-    registry: &HashMap<String, CheckMenuItem<Wry>>,
+/// Updates check menu items and reading labels in-place by comparing old and new
+/// status caches. Returns the number of items that were updated.
+pub fn update_menu_items_in_place<I: CheckItem, L: LabelItem>(
+    registry: &HashMap<String, I>,
+    labels: &HashMap<String, L>,
+    specs: &HashMap<String, DeviceSpecification>,
     old_statuses: &HashMap<String, Vec<TuyaDeviceStatus>>,
     new_statuses: &HashMap<String, Vec<TuyaDeviceStatus>>,
 ) -> usize {
@@ -472,81 +1758,66 @@ pub fn update_menu_items_in_place(
                 continue;
             }
 
-            match new_s.code.as_str() {
-                _ if new_s.value.as_bool().is_some() => {
-                    let key = format!("{}:{}", device_id, new_s.code);
-                    if let Some(item) = registry.get(&key) {
-                        let checked = new_s.value.as_bool().unwrap_or(false);
-                        item.set_checked(checked).ok();
-                        updated += 1;
-                    }
-                }
-
-                "fan_speed_percent" => {
-                    let old_val = old_s.value.as_i64().unwrap_or(1) as i32;
-                    let new_val = new_s.value.as_i64().unwrap_or(1) as i32;
-                    for level in 1..=FAN_SPEED_LEVELS {
-                        let key = format!("{}:fan_speed_percent:{}", device_id, level);
-                        if let Some(item) = registry.get(&key) {
-                            let was_checked = old_val == level;
-                            let is_checked = new_val == level;
-                            if was_checked != is_checked {
-                                item.set_checked(is_checked).ok();
-                                updated += 1;
-                            }
-                        }
-                    }
+            if let Some(item) = labels.get(&reading_key(device_id, &new_s.code)) {
+                let spec = specs.get(device_id);
+                if let Some(label) = reading_label(&new_s.code, &new_s.value, spec) {
+                    item.set_label(&label);
+                    updated += 1;
                 }
+                continue;
+            }
 
-                "temp_set" => {
-                    let old_val = old_s.value.as_i64().unwrap_or(20) as i32;
-                    let new_val = new_s.value.as_i64().unwrap_or(20) as i32;
-                    for temp in TEMP_MIN..=TEMP_MAX {
-                        let key = format!("{}:temp_set:{}", device_id, temp);
-                        if let Some(item) = registry.get(&key) {
-                            let was_checked = old_val == temp;
-                            let is_checked = new_val == temp;
-                            if was_checked != is_checked {
-                                item.set_checked(is_checked).ok();
-                                updated += 1;
-                            }
-                        }
-                    }
+            if let Some(checked) = new_s.value.as_bool() {
+                let key = format!("{}:{}", device_id, new_s.code);
+                if let Some(item) = registry.get(&key) {
+                    item.set_checked(checked);
+                    updated += 1;
                 }
+                continue;
+            }
 
-                "windspeed" => {
-                    let old_val = old_s.value.as_i64().unwrap_or(1) as i32;
-                    let new_val = new_s.value.as_i64().unwrap_or(1) as i32;
-                    for level in 1..=AC_FAN_SPEED_LEVELS {
-                        let key = format!("{}:windspeed:{}", device_id, level);
-                        if let Some(item) = registry.get(&key) {
-                            let was_checked = old_val == level;
-                            let is_checked = new_val == level;
-                            if was_checked != is_checked {
-                                item.set_checked(is_checked).ok();
-                                updated += 1;
-                            }
-                        }
-                    }
+            // Option submenus register one item per value, so only the previously
+            // and newly selected entries need their check marks flipped
+            let (code, old_selected, new_selected) = if curtain::is_position_code(&new_s.code) {
+                // Curtain positions follow percent_state when the device reports it
+                if curtain::position_code(new_status_list) != Some(new_s.code.as_str()) {
+                    continue;
                 }
-
-                "mode" => {
-                    let old_val = old_s.value.as_string().unwrap_or("");
-                    let new_val = new_s.value.as_string().unwrap_or("");
-                    for mode in AC_MODES {
-                        let key = format!("{}:mode:{}", device_id, mode);
-                        if let Some(item) = registry.get(&key) {
-                            let was_checked = old_val == *mode;
-                            let is_checked = new_val == *mode;
-                            if was_checked != is_checked {
-                                item.set_checked(is_checked).ok();
-                                updated += 1;
-                            }
-                        }
-                    }
+                (
+                    curtain::POSITION_CONTROL,
+                    curtain::selected_position(&old_s.value),
+                    curtain::selected_position(&new_s.value),
+                )
+            } else {
+                match specs
+                    .get(device_id)
+                    .and_then(|spec| spec.function(&new_s.code))
+                {
+                    Some(schema) => (
+                        new_s.code.as_str(),
+                        selected_option(&new_s.code, schema, &old_s.value),
+                        selected_option(&new_s.code, schema, &new_s.value),
+                    ),
+                    None => (
+                        new_s.code.as_str(),
+                        old_s.value.to_string(),
+                        new_s.value.to_string(),
+                    ),
                 }
+            };
+            if old_selected == new_selected {
+                continue;
+            }
 
-                _ => {}
+            let old_key = format!("{}:{}:{}", device_id, code, old_selected);
+            if let Some(item) = registry.get(&old_key) {
+                item.set_checked(false);
+                updated += 1;
+            }
+            let new_key = format!("{}:{}:{}", device_id, code, new_selected);
+            if let Some(item) = registry.get(&new_key) {
+                item.set_checked(true);
+                updated += 1;
             }
         }
     }
@@ -568,3 +1839,603 @@ pub fn parse_value(value_str: &str) -> TuyaValue {
 
     TuyaValue::String(value_str.to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::tuya::backend::fake::{device, status, FakeBackend};
+
+    #[derive(Default)]
+    struct FakeItem {
+        checked: Cell<Option<bool>>,
+    }
+
+    #[derive(Default)]
+    struct FakeLabel {
+        label: std::cell::RefCell<String>,
+    }
+
+    impl LabelItem for FakeLabel {
+        fn label(&self) -> String {
+            self.label.borrow().clone()
+        }
+
+        fn set_label(&self, label: &str) {
+            *self.label.borrow_mut() = label.to_string();
+        }
+    }
+
+    impl CheckItem for FakeItem {
+        fn set_checked(&self, checked: bool) {
+            self.checked.set(Some(checked));
+        }
+    }
+
+    fn mode_spec() -> DeviceSpecification {
+        serde_json::from_value(serde_json::json!({
+            "category": "fs",
+            "functions": [
+                { "code": "switch", "type": "Boolean", "values": "{}" },
+                { "code": "mode", "type": "Enum", "values": "{\"range\":[\"auto\",\"sleep\"]}" }
+            ],
+            "status": []
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_keeps_failures_per_device() {
+        let backend = FakeBackend::default()
+            .with_device(
+                "ok",
+                true,
+                Some(vec![status("switch", TuyaValue::Boolean(true))]),
+            )
+            .with_device("broken", true, None)
+            .with_device("away", false, None)
+            .with_spec("ok", mode_spec());
+        let spec_cache = create_spec_cache();
+
+        let snapshot = fetch_device_snapshot(
+            &backend,
+            backend.devices.clone(),
+            &HashSet::new(),
+            &spec_cache,
+        )
+        .await;
+
+        assert_eq!(snapshot.devices.len(), 3);
+        assert_eq!(snapshot.statuses.len(), 2);
+        assert!(snapshot.statuses["ok"].is_ok());
+        assert!(snapshot.statuses["broken"].is_err());
+        assert!(spec_cache.read().await.contains_key("ok"));
+    }
+
+    #[tokio::test]
+    async fn test_cached_specs_are_fetched_once() {
+        let backend = FakeBackend::default()
+            .with_device("ok", true, Some(Vec::new()))
+            .with_spec("ok", mode_spec());
+        let spec_cache = create_spec_cache();
+
+        fetch_device_snapshot(
+            &backend,
+            backend.devices.clone(),
+            &HashSet::new(),
+            &spec_cache,
+        )
+        .await;
+        fetch_device_snapshot(
+            &backend,
+            backend.devices.clone(),
+            &HashSet::new(),
+            &spec_cache,
+        )
+        .await;
+
+        assert_eq!(*backend.spec_requests.lock().unwrap(), vec!["ok"]);
+    }
+
+    #[tokio::test]
+    async fn test_pushed_online_changes_use_cached_statuses() {
+        let backend = FakeBackend::default()
+            .with_device(
+                "back",
+                false,
+                Some(vec![status("switch", TuyaValue::Boolean(true))]),
+            )
+            .with_device("known", true, None);
+        let device_list = create_device_list_cache();
+        refresh_device_list(&backend, "user", &device_list, 0)
+            .await
+            .unwrap();
+        let status_cache: DeviceStatusCache = Arc::new(RwLock::new(HashMap::from([(
+            "known".to_string(),
+            vec![status("switch", TuyaValue::Boolean(false))],
+        )])));
+
+        assert!(set_device_online(&device_list, "back", true, 100).await);
+        assert!(!set_device_online(&device_list, "back", true, 100).await);
+        assert!(!set_device_online(&device_list, "missing", true, 100).await);
+
+        let snapshot = cached_snapshot(
+            &backend,
+            &device_list,
+            &status_cache,
+            &create_spec_cache(),
+            &crate::tray::remote::create_remote_cache(),
+        )
+        .await
+        .unwrap();
+        // "known" has no status in the backend, so it can only have come from the cache
+        assert!(snapshot.statuses["known"].is_ok());
+        assert!(snapshot.statuses["back"].is_ok());
+        assert!(snapshot.offline_since.is_empty());
+
+        assert!(set_device_online(&device_list, "known", false, 200).await);
+        let list = device_list.read().await.clone().unwrap();
+        assert_eq!(list.offline_since.get("known"), Some(&200));
+    }
+
+    #[tokio::test]
+    async fn test_remotes_are_not_asked_for_status_or_spec() {
+        let backend = FakeBackend::default()
+            .with_device("ok", true, Some(Vec::new()))
+            .with_device("ac", true, None)
+            .with_spec("ok", mode_spec());
+        let spec_cache = create_spec_cache();
+        let remote_ids = HashSet::from(["ac".to_string()]);
+
+        let snapshot =
+            fetch_device_snapshot(&backend, backend.devices.clone(), &remote_ids, &spec_cache)
+                .await;
+
+        assert!(!snapshot.statuses.contains_key("ac"));
+        assert_eq!(*backend.spec_requests.lock().unwrap(), vec!["ok"]);
+    }
+
+    #[tokio::test]
+    async fn test_device_list_is_reused_until_it_expires() {
+        let backend = FakeBackend::default().with_device("ok", true, None);
+        let cache = create_device_list_cache();
+
+        let devices = refresh_device_list(&backend, "user", &cache, 600)
+            .await
+            .unwrap();
+        assert_eq!(devices.len(), 1);
+
+        // A cached list is served without asking the backend again
+        cache.write().await.as_mut().unwrap().devices.clear();
+        let cached = refresh_device_list(&backend, "user", &cache, 600)
+            .await
+            .unwrap();
+        assert!(cached.is_empty());
+        let forced = refresh_device_list(&backend, "user", &cache, 0)
+            .await
+            .unwrap();
+        assert_eq!(forced.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_status_outages_fail_the_snapshot() {
+        let backend = FakeBackend::default()
+            .with_device("plug", true, None)
+            .with_device("lamp", true, None);
+        let device_list = create_device_list_cache();
+        let home_cache = create_home_cache();
+        let remote_cache = remote::create_remote_cache();
+        let spec_cache = create_spec_cache();
+        refresh_device_list(&backend, "user", &device_list, 0)
+            .await
+            .unwrap();
+
+        // The list is fresh enough to reuse, but no status came back
+        let result = fetch_menu_snapshot(
+            &backend,
+            "user",
+            &spec_cache,
+            &home_cache,
+            &remote_cache,
+            &device_list,
+            600,
+        )
+        .await;
+        assert!(matches!(result, Err(AppError::Api { code: 2001, .. })));
+
+        // One answer is enough for a live snapshot
+        let backend = backend.with_device("sensor", true, Some(vec![]));
+        let snapshot = fetch_menu_snapshot(
+            &backend,
+            "user",
+            &spec_cache,
+            &home_cache,
+            &remote_cache,
+            &device_list,
+            0,
+        )
+        .await
+        .unwrap();
+        assert_eq!(snapshot.statuses.len(), 3);
+    }
+
+    #[test]
+    fn test_offline_time_is_tracked_across_lists() {
+        let mut sensor = device("sensor", false);
+        sensor.update_time = 500;
+        let first = DeviceList {
+            fetched_at: 0,
+            devices: vec![device("plug", true), sensor.clone(), device("lamp", false)],
+            offline_since: HashMap::new(),
+        };
+        let since = track_offline(None, &first.devices, 1_000);
+        assert_eq!(
+            since,
+            HashMap::from([("sensor".to_string(), 500), ("lamp".to_string(), 1_000)])
+        );
+
+        // The plug was seen going offline; the others keep their earlier times
+        let first = DeviceList {
+            offline_since: since,
+            ..first
+        };
+        let devices = vec![device("plug", false), sensor, device("lamp", false)];
+        let since = track_offline(Some(&first), &devices, 2_000);
+        assert_eq!(since["plug"], 2_000);
+        assert_eq!(since["sensor"], 500);
+        assert_eq!(since["lamp"], 1_000);
+    }
+
+    #[test]
+    fn test_overrides_rename_hide_and_order_devices() {
+        let overrides: HashMap<String, DeviceOverride> =
+            serde_json::from_value(serde_json::json!({
+                "a": { "name": "Desk lamp", "sortOrder": 2 },
+                "b": { "hidden": true },
+                "c": { "sortOrder": 1 },
+                "e": { "pinned": true }
+            }))
+            .unwrap();
+        let devices = apply_overrides(
+            vec![
+                device("a", true),
+                device("b", true),
+                device("c", true),
+                device("d", false),
+                device("e", true),
+                device("f", true),
+            ],
+            &overrides,
+        );
+        assert_eq!(devices[0].name, "Desk lamp");
+
+        let ids = |list: Vec<&TuyaDevice>| list.iter().map(|d| d.id.clone()).collect::<Vec<_>>();
+        let (pinned, rest) = menu_order(&devices, &overrides, true);
+        assert_eq!(ids(pinned), ["e"]);
+        assert_eq!(ids(rest), ["c", "a", "f", "d"]);
+        let (_, rest) = menu_order(&devices, &overrides, false);
+        assert_eq!(ids(rest), ["c", "a", "f"]);
+    }
+
+    #[test]
+    fn test_device_list_changes_are_structural() {
+        let statuses = HashMap::from([(
+            "dev".to_string(),
+            vec![status("switch", TuyaValue::Boolean(true))],
+        )]);
+        let toggled = HashMap::from([(
+            "dev".to_string(),
+            vec![status("switch", TuyaValue::Boolean(false))],
+        )]);
+        let devices = vec![device("dev", true)];
+        let mut renamed = devices.clone();
+        renamed[0].name = "Desk lamp".to_string();
+
+        assert!(!is_structural_change(
+            &devices, &devices, &statuses, &toggled
+        ));
+        assert!(is_structural_change(
+            &devices, &renamed, &statuses, &statuses
+        ));
+        assert!(is_structural_change(&devices, &[], &statuses, &statuses));
+    }
+
+    #[tokio::test]
+    async fn test_homes_are_cached_with_their_scenes() {
+        let backend = FakeBackend::default()
+            .with_scene(1, "s1", "Movie night")
+            .with_scene(1, "s2", "All off")
+            .with_scene(2, "s3", "Away");
+        let home_cache = create_home_cache();
+
+        refresh_homes(&backend, "user", &home_cache).await;
+
+        let data = home_cache.read().await.clone().unwrap();
+        assert_eq!(data.homes.len(), 2);
+        assert_eq!(data.homes[0].scenes.len(), 2);
+        assert_eq!(data.homes[1].scenes[0].name, "Away");
+    }
+
+    fn group_paths(groups: &[DeviceGroup]) -> Vec<(Vec<String>, Vec<String>)> {
+        groups
+            .iter()
+            .map(|g| {
+                (
+                    g.path.clone(),
+                    g.devices.iter().map(|d| d.id.clone()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_devices_are_grouped_by_room() {
+        let backend = FakeBackend::default()
+            .with_room(1, "Kitchen", &["kettle"])
+            .with_room(1, "Empty", &[])
+            .with_room(2, "Office", &["lamp"]);
+        let home_cache = create_home_cache();
+        refresh_homes(&backend, "user", &home_cache).await;
+        let homes = home_cache.read().await.clone().unwrap().homes;
+
+        let devices = [
+            device("lamp", true),
+            device("kettle", true),
+            device("tv", true),
+        ];
+        let devices: Vec<&TuyaDevice> = devices.iter().collect();
+
+        let groups = group_devices(&devices, MenuLayout::ByRoom, &homes);
+        assert_eq!(
+            group_paths(&groups),
+            vec![
+                (
+                    vec!["Home 1".to_string(), "Kitchen".to_string()],
+                    vec!["kettle".to_string()]
+                ),
+                (
+                    vec!["Home 2".to_string(), "Office".to_string()],
+                    vec!["lamp".to_string()]
+                ),
+                (Vec::new(), vec!["tv".to_string()]),
+            ]
+        );
+
+        // A single home needs no home level
+        let groups = group_devices(&devices, MenuLayout::ByRoom, &homes[..1]);
+        assert_eq!(groups[0].path, vec!["Kitchen".to_string()]);
+        assert_eq!(groups[1].devices.len(), 2);
+    }
+
+    #[test]
+    fn test_devices_are_grouped_by_category() {
+        let mut light = device("light", true);
+        light.category = "dj".to_string();
+        let plug = device("plug", true);
+        let devices = vec![&light, &plug];
+
+        let groups = group_devices(&devices, MenuLayout::ByCategory, &[]);
+        assert_eq!(
+            group_paths(&groups),
+            vec![
+                (vec!["Lights".to_string()], vec!["light".to_string()]),
+                (vec!["Switches".to_string()], vec!["plug".to_string()]),
+            ]
+        );
+        assert_eq!(
+            group_devices(&devices, MenuLayout::Flat, &[])[0]
+                .devices
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_scene_ids_round_trip() {
+        assert_eq!(
+            parse_scene_id("scene:42:abcDEF"),
+            Some((42, "abcDEF".to_string()))
+        );
+        assert_eq!(parse_scene_id("scene:home:abc"), None);
+        assert_eq!(parse_scene_id("scene:42:"), None);
+        assert_eq!(parse_scene_id("toggle:42:abc"), None);
+        assert_eq!(parse_macro_id("macro:desk-on"), Some("desk-on"));
+        assert_eq!(parse_macro_id("macro:"), None);
+    }
+
+    #[test]
+    fn test_timer_ids_round_trip() {
+        assert_eq!(
+            parse_timer_id("timer:dev:switch_1:30"),
+            Some(("dev".to_string(), "switch_1".to_string(), 30))
+        );
+        assert_eq!(parse_timer_id("timer:dev:switch_1"), None);
+        assert_eq!(parse_timer_id("timer:dev:switch_1:soon"), None);
+    }
+
+    #[test]
+    fn test_remote_ids_round_trip() {
+        assert_eq!(
+            parse_remote_id("ir:ac:temp:24"),
+            Some(("ac".to_string(), "temp".to_string(), "24".to_string()))
+        );
+        assert_eq!(
+            parse_remote_id("ir:tv:key:7"),
+            Some(("tv".to_string(), "key".to_string(), "7".to_string()))
+        );
+        assert_eq!(parse_remote_id("ir:ac:power"), None);
+        assert_eq!(parse_remote_id("ir::power:toggle"), None);
+    }
+
+    #[test]
+    fn test_countdown_labels_follow_time_left() {
+        let labels: HashMap<String, FakeLabel> = ["timer:dev:switch_1", "timer:dev:switch_2"]
+            .into_iter()
+            .map(|key| (key.to_string(), FakeLabel::default()))
+            .collect();
+        let statuses = HashMap::from([(
+            "dev".to_string(),
+            vec![
+                status("switch_1", TuyaValue::Boolean(true)),
+                status("countdown_1", TuyaValue::Integer(600)),
+            ],
+        )]);
+        let timers = HashMap::from([("dev:switch_2".to_string(), 4_000)]);
+
+        assert_eq!(refresh_countdown_labels(&labels, &statuses, &timers, 0), 2);
+        assert_eq!(
+            labels["timer:dev:switch_1"].label(),
+            "Turn off in… (10m left)"
+        );
+        assert_eq!(
+            labels["timer:dev:switch_2"].label(),
+            "Turn off in… (1h 07m left)"
+        );
+        assert_eq!(refresh_countdown_labels(&labels, &statuses, &timers, 0), 0);
+
+        assert_eq!(
+            refresh_countdown_labels(&labels, &statuses, &timers, 4_000),
+            1
+        );
+        assert_eq!(labels["timer:dev:switch_2"].label(), "Turn off in…");
+    }
+
+    #[test]
+    fn test_in_place_update_flips_toggles_and_options() {
+        let registry: HashMap<String, FakeItem> = ["dev:switch", "dev:mode:auto", "dev:mode:sleep"]
+            .into_iter()
+            .map(|key| (key.to_string(), FakeItem::default()))
+            .collect();
+        let specs = HashMap::from([("dev".to_string(), mode_spec())]);
+
+        let old = HashMap::from([(
+            "dev".to_string(),
+            vec![
+                status("switch", TuyaValue::Boolean(true)),
+                status("mode", TuyaValue::String("auto".to_string())),
+            ],
+        )]);
+        let new = HashMap::from([(
+            "dev".to_string(),
+            vec![
+                status("switch", TuyaValue::Boolean(false)),
+                status("mode", TuyaValue::String("sleep".to_string())),
+            ],
+        )]);
+
+        let labels: HashMap<String, FakeLabel> = HashMap::new();
+        assert_eq!(
+            update_menu_items_in_place(&registry, &labels, &specs, &old, &new),
+            3
+        );
+        assert_eq!(registry["dev:switch"].checked.get(), Some(false));
+        assert_eq!(registry["dev:mode:auto"].checked.get(), Some(false));
+        assert_eq!(registry["dev:mode:sleep"].checked.get(), Some(true));
+        assert_eq!(
+            update_menu_items_in_place(&registry, &labels, &specs, &new, &new),
+            0
+        );
+    }
+
+    #[test]
+    fn test_curtain_positions_follow_percent_state() {
+        let registry: HashMap<String, FakeItem> = curtain::POSITIONS
+            .iter()
+            .map(|p| (format!("blind:percent_control:{}", p), FakeItem::default()))
+            .collect();
+        let blind = |target, state| {
+            HashMap::from([(
+                "blind".to_string(),
+                vec![
+                    status("percent_control", TuyaValue::Integer(target)),
+                    status("percent_state", TuyaValue::Integer(state)),
+                ],
+            )])
+        };
+        let labels: HashMap<String, FakeLabel> = HashMap::new();
+
+        // The new target alone moves nothing until the blind reports its position
+        let specs = HashMap::new();
+        let updated =
+            update_menu_items_in_place(&registry, &labels, &specs, &blind(0, 0), &blind(75, 0));
+        assert_eq!(updated, 0);
+        let updated =
+            update_menu_items_in_place(&registry, &labels, &specs, &blind(75, 0), &blind(75, 70));
+        assert_eq!(updated, 2);
+        assert_eq!(
+            registry["blind:percent_control:0"].checked.get(),
+            Some(false)
+        );
+        assert_eq!(
+            registry["blind:percent_control:75"].checked.get(),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn test_readings_are_scaled_and_updated_in_place() {
+        let spec: DeviceSpecification = serde_json::from_value(serde_json::json!({
+            "category": "cz",
+            "functions": [],
+            "status": [{
+                "code": "cur_voltage",
+                "type": "Integer",
+                "values": "{\"unit\":\"V\",\"min\":0,\"max\":5000,\"scale\":1,\"step\":1}"
+            }]
+        }))
+        .unwrap();
+        let power = TuyaValue::Integer(423);
+        assert_eq!(
+            reading_label("cur_power", &power, None).as_deref(),
+            Some("Power: 42.3 W")
+        );
+        assert_eq!(
+            reading_label("cur_voltage", &TuyaValue::Integer(2301), Some(&spec)).as_deref(),
+            Some("Voltage: 230.1 V")
+        );
+        assert_eq!(
+            reading_label("va_humidity", &TuyaValue::Integer(48), None).as_deref(),
+            Some("Humidity: 48%")
+        );
+        assert_eq!(
+            reading_label("doorcontact_state", &TuyaValue::Boolean(true), None).as_deref(),
+            Some("Door: Open")
+        );
+        assert_eq!(
+            reading_label("switch_1", &TuyaValue::Boolean(true), None),
+            None
+        );
+
+        let registry: HashMap<String, FakeItem> = HashMap::new();
+        let labels = HashMap::from([
+            ("reading:dev:cur_power".to_string(), FakeLabel::default()),
+            (
+                "reading:dev:doorcontact_state".to_string(),
+                FakeLabel::default(),
+            ),
+        ]);
+        let statuses = |watts, open| {
+            HashMap::from([(
+                "dev".to_string(),
+                vec![
+                    status("cur_power", TuyaValue::Integer(watts)),
+                    status("doorcontact_state", TuyaValue::Boolean(open)),
+                ],
+            )])
+        };
+        let updated = update_menu_items_in_place(
+            &registry,
+            &labels,
+            &HashMap::new(),
+            &statuses(0, false),
+            &statuses(1500, true),
+        );
+        assert_eq!(updated, 2);
+        assert_eq!(labels["reading:dev:cur_power"].label(), "Power: 150.0 W");
+        assert_eq!(
+            labels["reading:dev:doorcontact_state"].label(),
+            "Door: Open"
+        );
+    }
+}
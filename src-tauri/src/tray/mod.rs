@@ -1,7 +1,26 @@
+pub mod actions;
+pub mod curtain;
+pub mod device_cache;
+pub mod light;
 pub mod menu;
+pub mod polling;
+pub mod remote;
+
+pub use device_cache::{CachedDevices, DeviceCacheFile};
 
 pub use menu::{
-    build_device_menu_with_cache, build_error_menu, build_unconfigured_menu, create_menu_registry,
-    is_structural_change, parse_command_id, parse_value, update_menu_items_in_place,
-    MenuItemRegistry,
+    build_device_menu, build_error_menu, build_unconfigured_menu, cached_snapshot,
+    create_device_list_cache, create_home_cache, create_label_registry, create_menu_registry,
+    create_spec_cache, fetch_device_snapshot, fetch_menu_snapshot, group_devices,
+    is_structural_change, parse_colour_id, parse_command_id, parse_macro_id, parse_remote_id,
+    parse_scene_id, parse_timer_id, parse_value, refresh_countdown_labels, refresh_device_list,
+    refresh_homes, set_device_online, update_menu_items_in_place, CheckItem, DeviceGroup,
+    DeviceList, DeviceListCache, DeviceSnapshot, DeviceSpecCache, DeviceStatusCache, HomeCache,
+    HomeData, HomeDetails, LabelItem, LabelRegistry, MenuItemRegistry,
+};
+
+pub use polling::{
+    create_poll_state, PollMode, PollOutcome, PollPlanner, PollState, SharedPollState,
 };
+
+pub use remote::{create_remote_cache, RemoteCache};
@@ -0,0 +1,200 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::{Notify, RwLock};
+
+/// Poll interval when nothing special is going on.
+pub const NORMAL_INTERVAL_SECS: u64 = 10;
+/// Poll interval right after the user touched the menu, so results show up quickly.
+const ACTIVE_INTERVAL_SECS: u64 = 3;
+/// How long polling stays fast after a menu interaction.
+const ACTIVE_WINDOW_SECS: i64 = 60;
+/// Poll interval once statuses have stopped changing, such as overnight.
+const IDLE_INTERVAL_SECS: u64 = 60;
+/// How long statuses have to stay unchanged before polling slows down.
+const IDLE_AFTER_SECS: i64 = 10 * 60;
+const MAX_BACKOFF_SECS: u64 = 300;
+
+/// What a poll found, as far as choosing the next interval is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PollOutcome {
+    /// The poll didn't run, for instance because a menu update was in progress.
+    Skipped,
+    Failed,
+    Unchanged,
+    Changed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PollMode {
+    Active,
+    Normal,
+    Idle,
+    Backoff,
+}
+
+/// The interval polling currently runs at, for the config page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PollState {
+    pub interval_secs: u64,
+    pub mode: PollMode,
+    pub failures: u32,
+}
+
+impl Default for PollState {
+    fn default() -> Self {
+        Self {
+            interval_secs: NORMAL_INTERVAL_SECS,
+            mode: PollMode::Normal,
+            failures: 0,
+        }
+    }
+}
+
+pub type SharedPollState = Arc<RwLock<PollState>>;
+
+pub fn create_poll_state() -> SharedPollState {
+    Arc::new(RwLock::new(PollState::default()))
+}
+
+/// Unix milliseconds of the last device interaction, from the tray or the config page.
+static LAST_INTERACTION_MS: AtomicI64 = AtomicI64::new(0);
+/// Wakes the poll loop early after an interaction.
+static POLL_WAKE: Notify = Notify::const_new();
+
+/// Records that the user just did something with a device, which speeds polling up
+/// so the result shows, and wakes the poll loop if it is waiting.
+pub fn note_interaction() {
+    LAST_INTERACTION_MS.store(chrono::Utc::now().timestamp_millis(), Ordering::SeqCst);
+    POLL_WAKE.notify_one();
+}
+
+/// When `note_interaction` was last called, in Unix milliseconds (0 for never).
+pub fn last_interaction_ms() -> i64 {
+    LAST_INTERACTION_MS.load(Ordering::SeqCst)
+}
+
+/// Completes at the next interaction, or straight away if one happened since the poll
+/// loop last waited.
+pub async fn interaction() {
+    POLL_WAKE.notified().await
+}
+
+/// Picks the next poll interval from recent outcomes and menu activity. Failures take
+/// precedence: each one doubles the interval up to `MAX_BACKOFF_SECS`, with jitter so
+/// several machines behind the same outage don't retry in lockstep.
+#[derive(Debug)]
+pub struct PollPlanner {
+    last_change: i64,
+    failures: u32,
+}
+
+impl PollPlanner {
+    pub fn new(now: i64) -> Self {
+        Self {
+            last_change: now,
+            failures: 0,
+        }
+    }
+
+    pub fn record(&mut self, outcome: PollOutcome, now: i64) {
+        match outcome {
+            PollOutcome::Skipped => {}
+            PollOutcome::Failed => self.failures = self.failures.saturating_add(1),
+            PollOutcome::Unchanged => self.failures = 0,
+            PollOutcome::Changed => {
+                self.failures = 0;
+                self.last_change = now;
+            }
+        }
+    }
+
+    /// `last_interaction` and `now` are Unix seconds; `jitter` is in `[0, 1)` and only
+    /// matters while backing off.
+    pub fn next(&self, last_interaction: i64, now: i64, jitter: f64) -> PollState {
+        let (interval_secs, mode) = if self.failures > 0 {
+            let backoff = NORMAL_INTERVAL_SECS
+                .saturating_mul(1 << self.failures.min(16))
+                .min(MAX_BACKOFF_SECS);
+            // Somewhere between half and all of the backoff
+            let jittered = backoff as f64 * (0.5 + jitter.clamp(0.0, 1.0) / 2.0);
+            (jittered.round() as u64, PollMode::Backoff)
+        } else if now - last_interaction < ACTIVE_WINDOW_SECS {
+            (ACTIVE_INTERVAL_SECS, PollMode::Active)
+        } else if now - self.last_change >= IDLE_AFTER_SECS {
+            (IDLE_INTERVAL_SECS, PollMode::Idle)
+        } else {
+            (NORMAL_INTERVAL_SECS, PollMode::Normal)
+        };
+
+        PollState {
+            interval_secs: interval_secs.max(1),
+            mode,
+            failures: self.failures,
+        }
+    }
+}
+
+/// A value in `[0, 1)` that differs between calls, for backoff jitter.
+pub fn jitter() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default(),
+    );
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interval_follows_activity() {
+        let mut planner = PollPlanner::new(0);
+
+        assert_eq!(planner.next(-1_000, 100, 0.5).mode, PollMode::Normal);
+        assert_eq!(
+            planner.next(90, 100, 0.5).interval_secs,
+            ACTIVE_INTERVAL_SECS
+        );
+        assert_eq!(planner.next(-1_000, 700, 0.5).mode, PollMode::Idle);
+
+        planner.record(PollOutcome::Changed, 650);
+        assert_eq!(planner.next(-1_000, 700, 0.5).mode, PollMode::Normal);
+    }
+
+    #[test]
+    fn test_failures_back_off_with_jitter() {
+        let mut planner = PollPlanner::new(0);
+        for _ in 0..3 {
+            planner.record(PollOutcome::Failed, 0);
+        }
+        planner.record(PollOutcome::Skipped, 0);
+
+        // 10s doubled three times, jittered between half and all of it
+        let low = planner.next(0, 0, 0.0);
+        let high = planner.next(0, 0, 0.999);
+        assert_eq!(
+            (low.mode, low.interval_secs, low.failures),
+            (PollMode::Backoff, 40, 3)
+        );
+        assert_eq!(high.interval_secs, 80);
+
+        for _ in 0..20 {
+            planner.record(PollOutcome::Failed, 0);
+        }
+        assert!(planner.next(0, 0, 0.999).interval_secs <= MAX_BACKOFF_SECS);
+
+        planner.record(PollOutcome::Unchanged, 0);
+        assert_eq!(planner.next(-1_000, 0, 0.0).mode, PollMode::Normal);
+        assert!((0.0..1.0).contains(&jitter()));
+    }
+}
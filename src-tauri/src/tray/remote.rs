@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use super::menu::DeviceSnapshot;
+use crate::tuya::{
+    AcScene, DeviceBackend, LearnedCode, TuyaDevice, TuyaDeviceStatus, TuyaRemote, TuyaValue,
+};
+
+/// Remotes and their learned keys change rarely, so they are refetched this often.
+const REMOTE_REFRESH_SECS: i64 = 300;
+
+pub const AC_MODES: [(i64, &str); 5] = [
+    (0, "Cool"),
+    (1, "Heat"),
+    (2, "Auto"),
+    (3, "Fan"),
+    (4, "Dry"),
+];
+
+pub const AC_WIND_SPEEDS: [(i64, &str); 4] = [(0, "Auto"), (1, "Low"), (2, "Medium"), (3, "High")];
+
+/// Setpoints offered for IR air conditioners, in °C.
+pub const AC_TEMPERATURES: RangeInclusive<i64> = 16..=30;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteDetails {
+    /// The IR hub the remote is set up on.
+    pub infrared_id: String,
+    pub remote: TuyaRemote,
+    /// Empty for air conditioners, which are sent whole scenes instead of keys.
+    pub keys: Vec<LearnedCode>,
+}
+
+/// Remotes of all IR hubs, keyed by remote id.
+#[derive(Debug, Clone, Default)]
+pub struct RemoteData {
+    pub fetched_at: i64,
+    pub remotes: HashMap<String, RemoteDetails>,
+    /// Last known state of the air conditioners: read with the remotes and replaced by
+    /// each scene sent. Kept apart from `remotes` since it changes the menu's checks,
+    /// not its layout.
+    pub scenes: HashMap<String, AcScene>,
+}
+
+pub type RemoteCache = Arc<RwLock<Option<RemoteData>>>;
+
+pub fn create_remote_cache() -> RemoteCache {
+    Arc::new(RwLock::new(None))
+}
+
+pub fn is_ir_hub(category: &str) -> bool {
+    category == "wnykq"
+}
+
+/// Refetches the remotes of online IR hubs, with the learned keys of everything but
+/// air conditioners and the state of air conditioners, once the cached copy is older
+/// than `REMOTE_REFRESH_SECS`. A hub whose remotes fail to load keeps the ones it had,
+/// and an air conditioner whose state fails to load keeps its last one.
+pub async fn refresh_remotes(
+    backend: &dyn DeviceBackend,
+    devices: &[TuyaDevice],
+    cache: &RemoteCache,
+) {
+    let now = chrono::Utc::now().timestamp();
+    let previous = {
+        let cached = cache.read().await;
+        if cached
+            .as_ref()
+            .is_some_and(|data| now - data.fetched_at < REMOTE_REFRESH_SECS)
+        {
+            return;
+        }
+        cached.clone().unwrap_or_default()
+    };
+
+    let hubs: Vec<&TuyaDevice> = devices
+        .iter()
+        .filter(|d| d.online && is_ir_hub(&d.category))
+        .collect();
+    let remote_lists = join_all(hubs.iter().map(|hub| backend.remotes(&hub.id))).await;
+
+    let mut found: Vec<(String, TuyaRemote)> = Vec::new();
+    let mut remotes = HashMap::new();
+    let mut scenes = HashMap::new();
+    for (hub, list) in hubs.iter().zip(remote_lists) {
+        match list {
+            Ok(list) => found.extend(list.into_iter().map(|remote| (hub.id.clone(), remote))),
+            Err(e) => {
+                tracing::warn!("Failed to fetch remotes of IR hub {}: {}", hub.id, e);
+                for (id, details) in &previous.remotes {
+                    if details.infrared_id != hub.id {
+                        continue;
+                    }
+                    remotes.insert(id.clone(), details.clone());
+                    if let Some(scene) = previous.scenes.get(id) {
+                        scenes.insert(id.clone(), *scene);
+                    }
+                }
+            }
+        }
+    }
+
+    let previous = &previous;
+    let details = join_all(found.iter().map(|(hub_id, remote)| async move {
+        if remote.is_air_conditioner() {
+            let scene = match backend.ac_status(hub_id, &remote.remote_id).await {
+                Ok(scene) => Some(scene),
+                Err(e) => {
+                    tracing::warn!(
+                        "Failed to fetch state of remote {}: {}",
+                        remote.remote_id,
+                        e
+                    );
+                    previous.scenes.get(&remote.remote_id).copied()
+                }
+            };
+            return (Vec::new(), scene);
+        }
+        let keys = backend
+            .learned_codes(hub_id, &remote.remote_id)
+            .await
+            .unwrap_or_else(|e| {
+                tracing::warn!(
+                    "Failed to fetch learned keys of remote {}: {}",
+                    remote.remote_id,
+                    e
+                );
+                Vec::new()
+            });
+        (keys, None)
+    }))
+    .await;
+    for ((infrared_id, remote), (keys, scene)) in found.into_iter().zip(details) {
+        if let Some(scene) = scene {
+            scenes.insert(remote.remote_id.clone(), scene);
+        }
+        remotes.insert(
+            remote.remote_id.clone(),
+            RemoteDetails {
+                infrared_id,
+                remote,
+                keys,
+            },
+        );
+    }
+
+    *cache.write().await = Some(RemoteData {
+        fetched_at: now,
+        remotes,
+        scenes,
+    });
+}
+
+/// An air conditioner's state as statuses, so the menu checks and updates it like
+/// device DPs.
+pub fn ac_statuses(scene: &AcScene) -> Vec<TuyaDeviceStatus> {
+    [
+        ("power", TuyaValue::Boolean(scene.power != 0)),
+        ("mode", TuyaValue::Integer(scene.mode)),
+        ("temp", TuyaValue::Integer(scene.temp)),
+        ("wind", TuyaValue::Integer(scene.wind)),
+    ]
+    .into_iter()
+    .map(|(code, value)| TuyaDeviceStatus {
+        code: code.to_string(),
+        value,
+    })
+    .collect()
+}
+
+/// Reads back what `ac_statuses` wrote.
+pub fn ac_scene(status: &[TuyaDeviceStatus]) -> Option<AcScene> {
+    let value = |code: &str| status.iter().find(|s| s.code == code).map(|s| &s.value);
+    Some(AcScene {
+        power: i64::from(value("power")?.as_bool()?),
+        mode: value("mode")?.as_i64()?,
+        temp: value("temp")?.as_i64()?,
+        wind: value("wind")?.as_i64()?,
+    })
+}
+
+/// Records a scene sent to an air conditioner as its state, which the next menu
+/// update shows.
+pub async fn store_scene(cache: &RemoteCache, remote_id: &str, scene: AcScene) {
+    if let Some(data) = cache.write().await.as_mut() {
+        data.scenes.insert(remote_id.to_string(), scene);
+    }
+}
+
+/// Adds the cached remotes of the snapshot's devices to it. IR remotes have no status
+/// DPs of their own: air conditioners get `ac_statuses` of their cached state, empty
+/// when it couldn't be read, and other remotes an empty status.
+pub async fn attach_remotes(snapshot: &mut DeviceSnapshot, cache: &RemoteCache) {
+    let data = cache.read().await.clone().unwrap_or_default();
+    let remotes: HashMap<String, RemoteDetails> = data
+        .remotes
+        .into_iter()
+        .filter(|(id, _)| snapshot.devices.iter().any(|d| d.online && &d.id == id))
+        .collect();
+
+    for id in remotes.keys() {
+        let status = data.scenes.get(id).map(ac_statuses).unwrap_or_default();
+        snapshot.statuses.insert(id.clone(), Ok(status));
+    }
+    snapshot.remotes = remotes;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RemoteAction {
+    Scene(AcScene),
+    Key(String),
+}
+
+/// What a remote menu item sends. AC items change one setting of the last known state
+/// (`current`, or the defaults before anything was sent). Since the scene is sent
+/// whole, picking a mode, setpoint or fan speed also turns the unit on.
+pub fn remote_action(
+    details: &RemoteDetails,
+    current: Option<AcScene>,
+    field: &str,
+    value: &str,
+) -> Option<RemoteAction> {
+    if field == "key" {
+        let id: i64 = value.parse().ok()?;
+        return details
+            .keys
+            .iter()
+            .find(|key| key.id == id)
+            .map(|key| RemoteAction::Key(key.code.clone()));
+    }
+
+    let mut scene = current.unwrap_or_default();
+    match field {
+        "power" => {
+            scene.power = i64::from(scene.power == 0);
+            return Some(RemoteAction::Scene(scene));
+        }
+        "mode" => scene.mode = value.parse().ok()?,
+        "temp" => scene.temp = value.parse().ok()?,
+        "wind" => scene.wind = value.parse().ok()?,
+        _ => return None,
+    }
+    scene.power = 1;
+    Some(RemoteAction::Scene(scene))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tuya::backend::fake::{device, FakeBackend};
+
+    fn remote(id: &str, category_id: i64) -> TuyaRemote {
+        TuyaRemote {
+            remote_id: id.to_string(),
+            remote_name: format!("Remote {}", id),
+            category_id,
+            brand_name: String::new(),
+        }
+    }
+
+    fn learned(id: i64, name: &str) -> LearnedCode {
+        LearnedCode {
+            id,
+            key_name: name.to_string(),
+            code: format!("code-{}", id),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remotes_are_attached_with_their_state() {
+        let scene = AcScene {
+            power: 1,
+            mode: 0,
+            temp: 22,
+            wind: 1,
+        };
+        let mut hub = device("hub", true);
+        hub.category = "wnykq".to_string();
+        let backend = FakeBackend::default()
+            .with_remote("hub", remote("ac", 5), Some(scene), Vec::new())
+            .with_remote("hub", remote("tv", 2), None, vec![learned(7, "Power")]);
+        let devices = vec![hub, device("ac", true), device("tv", true)];
+
+        let cache = create_remote_cache();
+        refresh_remotes(&backend, &devices, &cache).await;
+        assert_eq!(cache.read().await.as_ref().unwrap().remotes.len(), 2);
+        assert_eq!(
+            cache.read().await.as_ref().unwrap().scenes.get("ac"),
+            Some(&scene)
+        );
+
+        let mut snapshot = DeviceSnapshot {
+            devices,
+            statuses: HashMap::from([(
+                "ac".to_string(),
+                Err(crate::error::AppError::Api {
+                    code: 2008,
+                    message: "command or value not support".to_string(),
+                }),
+            )]),
+            saved_at: None,
+            offline_since: HashMap::new(),
+            remotes: HashMap::new(),
+        };
+        attach_remotes(&mut snapshot, &cache).await;
+
+        let ac = snapshot.statuses["ac"].as_ref().unwrap();
+        assert_eq!(ac_scene(ac), Some(scene));
+        assert!(snapshot.statuses["tv"].as_ref().unwrap().is_empty());
+        assert_eq!(snapshot.remotes["tv"].keys[0].key_name, "Power");
+
+        // A sent scene is the state shown next, without asking the hub
+        let off = AcScene { power: 0, ..scene };
+        store_scene(&cache, "ac", off).await;
+        attach_remotes(&mut snapshot, &cache).await;
+        assert_eq!(
+            ac_scene(snapshot.statuses["ac"].as_ref().unwrap()),
+            Some(off)
+        );
+    }
+
+    #[test]
+    fn test_menu_items_become_scenes_and_keys() {
+        let details = RemoteDetails {
+            infrared_id: "hub".to_string(),
+            remote: remote("tv", 2),
+            keys: vec![learned(7, "Power")],
+        };
+        assert_eq!(
+            remote_action(&details, None, "key", "7"),
+            Some(RemoteAction::Key("code-7".to_string()))
+        );
+        assert_eq!(remote_action(&details, None, "key", "8"), None);
+
+        // Nothing sent yet: power turns on with the defaults, a setting also turns on
+        let Some(RemoteAction::Scene(on)) = remote_action(&details, None, "power", "toggle") else {
+            panic!("expected a scene");
+        };
+        assert_eq!((on.power, on.temp), (1, 24));
+        let Some(RemoteAction::Scene(off)) = remote_action(&details, Some(on), "power", "toggle")
+        else {
+            panic!("expected a scene");
+        };
+        assert_eq!(off.power, 0);
+        assert_eq!(
+            remote_action(&details, Some(off), "temp", "19"),
+            Some(RemoteAction::Scene(AcScene {
+                power: 1,
+                temp: 19,
+                ..off
+            }))
+        );
+        assert_eq!(remote_action(&details, None, "swing", "1"), None);
+    }
+}
@@ -0,0 +1,448 @@
+use async_trait::async_trait;
+use futures::future::join_all;
+
+use super::client::{StatusResults, TuyaClient};
+use super::types::{
+    AcScene, DeviceSpecification, LearnedCode, TuyaCommand, TuyaDevice, TuyaDeviceStatus, TuyaHome,
+    TuyaRemote, TuyaRoom, TuyaScene, TuyaValue,
+};
+use crate::error::AppError;
+
+/// Everything the tray and the device commands need from a source of devices. The
+/// cloud client is the real implementation; anything else that can list, read and
+/// control devices (a LAN-only backend, a test fake) can stand in for it.
+#[async_trait]
+pub trait DeviceBackend: Send + Sync {
+    async fn list_devices(&self, user_id: &str) -> Result<Vec<TuyaDevice>, AppError>;
+
+    async fn status(&self, device_id: &str) -> Result<Vec<TuyaDeviceStatus>, AppError>;
+
+    /// Status of several devices, each with its own result. Backends with a batch API
+    /// should override this; the default asks for each device concurrently.
+    async fn statuses(&self, device_ids: &[String]) -> StatusResults {
+        let results = join_all(device_ids.iter().map(|id| self.status(id))).await;
+        device_ids.iter().cloned().zip(results).collect()
+    }
+
+    async fn send_commands(
+        &self,
+        device_id: &str,
+        commands: Vec<TuyaCommand>,
+    ) -> Result<bool, AppError>;
+
+    async fn specification(&self, device_id: &str) -> Result<DeviceSpecification, AppError>;
+
+    async fn send_command(
+        &self,
+        device_id: &str,
+        code: &str,
+        value: TuyaValue,
+    ) -> Result<bool, AppError> {
+        self.send_commands(
+            device_id,
+            vec![TuyaCommand {
+                code: code.to_string(),
+                value,
+            }],
+        )
+        .await
+    }
+
+    /// Homes, rooms and scenes are a cloud concept; backends without them show no
+    /// scenes and leave devices ungrouped.
+    async fn homes(&self, _user_id: &str) -> Result<Vec<TuyaHome>, AppError> {
+        Ok(Vec::new())
+    }
+
+    async fn rooms(&self, _home_id: i64) -> Result<Vec<TuyaRoom>, AppError> {
+        Ok(Vec::new())
+    }
+
+    async fn scenes(&self, _home_id: i64) -> Result<Vec<TuyaScene>, AppError> {
+        Ok(Vec::new())
+    }
+
+    async fn trigger_scene(&self, _home_id: i64, _scene_id: &str) -> Result<bool, AppError> {
+        Err(AppError::Config(
+            "Scenes are not supported by this backend".to_string(),
+        ))
+    }
+
+    /// IR remotes live in the cloud as well; backends without them show IR hubs as
+    /// plain devices.
+    async fn remotes(&self, _infrared_id: &str) -> Result<Vec<TuyaRemote>, AppError> {
+        Ok(Vec::new())
+    }
+
+    async fn ac_status(&self, _infrared_id: &str, _remote_id: &str) -> Result<AcScene, AppError> {
+        Err(remotes_unsupported())
+    }
+
+    async fn send_ac_scene(
+        &self,
+        _infrared_id: &str,
+        _remote_id: &str,
+        _scene: &AcScene,
+    ) -> Result<bool, AppError> {
+        Err(remotes_unsupported())
+    }
+
+    async fn learned_codes(
+        &self,
+        _infrared_id: &str,
+        _remote_id: &str,
+    ) -> Result<Vec<LearnedCode>, AppError> {
+        Ok(Vec::new())
+    }
+
+    async fn send_learned_code(
+        &self,
+        _infrared_id: &str,
+        _remote_id: &str,
+        _code: &str,
+    ) -> Result<bool, AppError> {
+        Err(remotes_unsupported())
+    }
+}
+
+fn remotes_unsupported() -> AppError {
+    AppError::Config("IR remotes are not supported by this backend".to_string())
+}
+
+#[async_trait]
+impl DeviceBackend for TuyaClient {
+    async fn list_devices(&self, user_id: &str) -> Result<Vec<TuyaDevice>, AppError> {
+        self.fetch_devices(user_id).await
+    }
+
+    async fn status(&self, device_id: &str) -> Result<Vec<TuyaDeviceStatus>, AppError> {
+        self.fetch_device_status(device_id).await
+    }
+
+    async fn statuses(&self, device_ids: &[String]) -> StatusResults {
+        self.fetch_device_statuses(device_ids).await
+    }
+
+    async fn send_commands(
+        &self,
+        device_id: &str,
+        commands: Vec<TuyaCommand>,
+    ) -> Result<bool, AppError> {
+        self.send_device_commands(device_id, commands).await
+    }
+
+    async fn specification(&self, device_id: &str) -> Result<DeviceSpecification, AppError> {
+        self.fetch_device_specification(device_id).await
+    }
+
+    async fn homes(&self, user_id: &str) -> Result<Vec<TuyaHome>, AppError> {
+        self.fetch_homes(user_id).await
+    }
+
+    async fn rooms(&self, home_id: i64) -> Result<Vec<TuyaRoom>, AppError> {
+        self.fetch_rooms(home_id).await
+    }
+
+    async fn scenes(&self, home_id: i64) -> Result<Vec<TuyaScene>, AppError> {
+        self.fetch_scenes(home_id).await
+    }
+
+    async fn trigger_scene(&self, home_id: i64, scene_id: &str) -> Result<bool, AppError> {
+        TuyaClient::trigger_scene(self, home_id, scene_id).await
+    }
+
+    async fn remotes(&self, infrared_id: &str) -> Result<Vec<TuyaRemote>, AppError> {
+        self.fetch_remotes(infrared_id).await
+    }
+
+    async fn ac_status(&self, infrared_id: &str, remote_id: &str) -> Result<AcScene, AppError> {
+        self.fetch_ac_status(infrared_id, remote_id).await
+    }
+
+    async fn send_ac_scene(
+        &self,
+        infrared_id: &str,
+        remote_id: &str,
+        scene: &AcScene,
+    ) -> Result<bool, AppError> {
+        TuyaClient::send_ac_scene(self, infrared_id, remote_id, scene).await
+    }
+
+    async fn learned_codes(
+        &self,
+        infrared_id: &str,
+        remote_id: &str,
+    ) -> Result<Vec<LearnedCode>, AppError> {
+        self.fetch_learned_codes(infrared_id, remote_id).await
+    }
+
+    async fn send_learned_code(
+        &self,
+        infrared_id: &str,
+        remote_id: &str,
+        code: &str,
+    ) -> Result<bool, AppError> {
+        TuyaClient::send_learned_code(self, infrared_id, remote_id, code).await
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// In-memory backend for tests. Devices without a status entry fail their status
+    /// request, devices without a spec fail their specification request and devices in
+    /// `failing_devices` reject commands.
+    #[derive(Default)]
+    pub struct FakeBackend {
+        pub devices: Vec<TuyaDevice>,
+        pub statuses: HashMap<String, Vec<TuyaDeviceStatus>>,
+        pub specs: HashMap<String, DeviceSpecification>,
+        pub sent: Mutex<Vec<(String, Vec<TuyaCommand>)>>,
+        pub failing_devices: HashSet<String>,
+        pub spec_requests: Mutex<Vec<String>>,
+        pub homes: Vec<TuyaHome>,
+        pub rooms: HashMap<i64, Vec<TuyaRoom>>,
+        pub scenes: HashMap<i64, Vec<TuyaScene>>,
+        pub triggered: Mutex<Vec<(i64, String)>>,
+        pub remotes: HashMap<String, Vec<TuyaRemote>>,
+        pub ac_statuses: HashMap<String, AcScene>,
+        pub learned_codes: HashMap<String, Vec<LearnedCode>>,
+        /// AC scenes and learned codes sent, by remote id.
+        pub sent_scenes: Mutex<Vec<(String, AcScene)>>,
+        pub sent_codes: Mutex<Vec<(String, String)>>,
+    }
+
+    impl FakeBackend {
+        pub fn with_device(
+            mut self,
+            id: &str,
+            online: bool,
+            status: Option<Vec<TuyaDeviceStatus>>,
+        ) -> Self {
+            self.devices.push(device(id, online));
+            if let Some(status) = status {
+                self.statuses.insert(id.to_string(), status);
+            }
+            self
+        }
+
+        pub fn with_spec(mut self, id: &str, spec: DeviceSpecification) -> Self {
+            self.specs.insert(id.to_string(), spec);
+            self
+        }
+
+        fn ensure_home(&mut self, home_id: i64) {
+            if !self.homes.iter().any(|h| h.home_id == home_id) {
+                self.homes.push(TuyaHome {
+                    home_id,
+                    name: format!("Home {}", home_id),
+                    geo_name: String::new(),
+                    lat: 0.0,
+                    lon: 0.0,
+                });
+            }
+        }
+
+        pub fn with_room(mut self, home_id: i64, name: &str, device_ids: &[&str]) -> Self {
+            self.ensure_home(home_id);
+            let rooms = self.rooms.entry(home_id).or_default();
+            rooms.push(TuyaRoom {
+                room_id: rooms.len() as i64 + 1,
+                name: name.to_string(),
+                device_ids: device_ids.iter().map(|id| id.to_string()).collect(),
+            });
+            self
+        }
+
+        pub fn with_scene(mut self, home_id: i64, scene_id: &str, name: &str) -> Self {
+            self.ensure_home(home_id);
+            self.scenes.entry(home_id).or_default().push(TuyaScene {
+                scene_id: scene_id.to_string(),
+                name: name.to_string(),
+                enabled: true,
+            });
+            self
+        }
+
+        /// Adds a remote to an IR hub; air conditioners get `ac_status` as their state,
+        /// other remotes `learned` as their keys.
+        pub fn with_remote(
+            mut self,
+            infrared_id: &str,
+            remote: TuyaRemote,
+            ac_status: Option<AcScene>,
+            learned: Vec<LearnedCode>,
+        ) -> Self {
+            if let Some(status) = ac_status {
+                self.ac_statuses.insert(remote.remote_id.clone(), status);
+            }
+            self.learned_codes.insert(remote.remote_id.clone(), learned);
+            self.remotes
+                .entry(infrared_id.to_string())
+                .or_default()
+                .push(remote);
+            self
+        }
+
+        pub fn sent(&self) -> Vec<(String, Vec<TuyaCommand>)> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    pub fn device(id: &str, online: bool) -> TuyaDevice {
+        TuyaDevice {
+            id: id.to_string(),
+            name: format!("Device {}", id),
+            online,
+            category: "kg".to_string(),
+            product_id: String::new(),
+            product_name: String::new(),
+            local_key: String::new(),
+            sub: false,
+            uuid: String::new(),
+            owner_id: String::new(),
+            ip: String::new(),
+            time_zone: "+00:00".to_string(),
+            create_time: 0,
+            update_time: 0,
+            active_time: 0,
+            icon: String::new(),
+        }
+    }
+
+    pub fn status(code: &str, value: TuyaValue) -> TuyaDeviceStatus {
+        TuyaDeviceStatus {
+            code: code.to_string(),
+            value,
+        }
+    }
+
+    #[async_trait]
+    impl DeviceBackend for FakeBackend {
+        async fn list_devices(&self, _user_id: &str) -> Result<Vec<TuyaDevice>, AppError> {
+            Ok(self.devices.clone())
+        }
+
+        async fn status(&self, device_id: &str) -> Result<Vec<TuyaDeviceStatus>, AppError> {
+            self.statuses
+                .get(device_id)
+                .cloned()
+                .ok_or_else(|| AppError::Api {
+                    code: 2001,
+                    message: "device is offline".to_string(),
+                })
+        }
+
+        async fn send_commands(
+            &self,
+            device_id: &str,
+            commands: Vec<TuyaCommand>,
+        ) -> Result<bool, AppError> {
+            if self.failing_devices.contains(device_id) {
+                return Err(AppError::Api {
+                    code: 2001,
+                    message: "device is offline".to_string(),
+                });
+            }
+            self.sent
+                .lock()
+                .unwrap()
+                .push((device_id.to_string(), commands));
+            Ok(true)
+        }
+
+        async fn specification(&self, device_id: &str) -> Result<DeviceSpecification, AppError> {
+            self.spec_requests
+                .lock()
+                .unwrap()
+                .push(device_id.to_string());
+            self.specs
+                .get(device_id)
+                .cloned()
+                .ok_or_else(|| AppError::Api {
+                    code: 1106,
+                    message: "permission deny".to_string(),
+                })
+        }
+
+        async fn homes(&self, _user_id: &str) -> Result<Vec<TuyaHome>, AppError> {
+            Ok(self.homes.clone())
+        }
+
+        async fn rooms(&self, home_id: i64) -> Result<Vec<TuyaRoom>, AppError> {
+            Ok(self.rooms.get(&home_id).cloned().unwrap_or_default())
+        }
+
+        async fn scenes(&self, home_id: i64) -> Result<Vec<TuyaScene>, AppError> {
+            Ok(self.scenes.get(&home_id).cloned().unwrap_or_default())
+        }
+
+        async fn trigger_scene(&self, home_id: i64, scene_id: &str) -> Result<bool, AppError> {
+            self.triggered
+                .lock()
+                .unwrap()
+                .push((home_id, scene_id.to_string()));
+            Ok(true)
+        }
+
+        async fn remotes(&self, infrared_id: &str) -> Result<Vec<TuyaRemote>, AppError> {
+            Ok(self.remotes.get(infrared_id).cloned().unwrap_or_default())
+        }
+
+        async fn ac_status(
+            &self,
+            _infrared_id: &str,
+            remote_id: &str,
+        ) -> Result<AcScene, AppError> {
+            self.ac_statuses
+                .get(remote_id)
+                .copied()
+                .ok_or_else(|| AppError::Api {
+                    code: 2001,
+                    message: "device is offline".to_string(),
+                })
+        }
+
+        async fn send_ac_scene(
+            &self,
+            _infrared_id: &str,
+            remote_id: &str,
+            scene: &AcScene,
+        ) -> Result<bool, AppError> {
+            self.sent_scenes
+                .lock()
+                .unwrap()
+                .push((remote_id.to_string(), *scene));
+            Ok(true)
+        }
+
+        async fn learned_codes(
+            &self,
+            _infrared_id: &str,
+            remote_id: &str,
+        ) -> Result<Vec<LearnedCode>, AppError> {
+            Ok(self
+                .learned_codes
+                .get(remote_id)
+                .cloned()
+                .unwrap_or_default())
+        }
+
+        async fn send_learned_code(
+            &self,
+            _infrared_id: &str,
+            remote_id: &str,
+            code: &str,
+        ) -> Result<bool, AppError> {
+            self.sent_codes
+                .lock()
+                .unwrap()
+                .push((remote_id.to_string(), code.to_string()));
+            Ok(true)
+        }
+    }
+}
@@ -1,11 +1,18 @@
+use std::collections::HashMap;
+use std::future::Future;
 use std::sync::Arc;
 use std::time::Duration;
+
+use futures::future::join_all;
 use tokio::sync::RwLock;
 
 use super::auth::SignedHeaders;
+use super::local::{self, DiscoveredDevices, LocalDevices, LocalEndpoint};
 use super::token::TokenManager;
 use super::types::{
-    TuyaApiResponse, TuyaCommand, TuyaCommandPayload, TuyaDevice, TuyaDeviceStatus, TuyaValue,
+    AcScene, DeviceSpecification, DeviceStatusEntry, HomeRooms, LearnedCode, RoomDevice,
+    ShadowProperties, TuyaApiResponse, TuyaCommand, TuyaCommandPayload, TuyaDevice,
+    TuyaDeviceStatus, TuyaHome, TuyaRemote, TuyaRoom, TuyaScene, TuyaValue,
 };
 use crate::error::AppError;
 
@@ -13,6 +20,10 @@ const REQUEST_TIMEOUT_SECS: u64 = 30;
 const CONNECT_TIMEOUT_SECS: u64 = 10;
 const MAX_RETRIES: u32 = 3;
 const INITIAL_RETRY_DELAY_MS: u64 = 500;
+/// The batch status endpoint accepts at most this many device ids per request.
+const STATUS_BATCH_SIZE: usize = 20;
+
+pub type StatusResults = HashMap<String, Result<Vec<TuyaDeviceStatus>, AppError>>;
 
 pub struct TuyaClient {
     token_manager: TokenManager,
@@ -20,10 +31,19 @@ pub struct TuyaClient {
     base_url: String,
     client_id: String,
     secret: String,
+    local_devices: LocalDevices,
+    /// When the app last sent each DP a command, keyed `{device}:{code}`, so changes
+    /// it made can be told from ones made elsewhere.
+    sent_commands: std::sync::Mutex<HashMap<String, i64>>,
 }
 
 impl TuyaClient {
-    pub fn new(client_id: String, secret: String, base_url: String) -> Self {
+    pub fn new(
+        client_id: String,
+        secret: String,
+        base_url: String,
+        discovered: DiscoveredDevices,
+    ) -> Self {
         let http_client = reqwest::Client::builder()
             .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS))
             .connect_timeout(Duration::from_secs(CONNECT_TIMEOUT_SECS))
@@ -41,13 +61,37 @@ impl TuyaClient {
             base_url,
             client_id,
             secret,
+            local_devices: LocalDevices::with_discovered(discovered),
+            sent_commands: std::sync::Mutex::new(HashMap::new()),
         }
     }
 
+    /// When the app last sent `code` of a device a command (Unix seconds).
+    pub fn last_command_at(&self, device_id: &str, code: &str) -> Option<i64> {
+        self.sent_commands
+            .lock()
+            .ok()?
+            .get(&format!("{}:{}", device_id, code))
+            .copied()
+    }
+
+    pub fn local_devices(&self) -> &LocalDevices {
+        &self.local_devices
+    }
+
     async fn get<T: serde::de::DeserializeOwned>(&self, path: &str) -> Result<T, AppError> {
         self.request::<T>("GET", path, None, None).await
     }
 
+    async fn get_with_query<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+        query_params: &[(&str, &str)],
+    ) -> Result<T, AppError> {
+        self.request::<T>("GET", path, Some(query_params), None)
+            .await
+    }
+
     async fn post<T: serde::de::DeserializeOwned, B: serde::Serialize>(
         &self,
         path: &str,
@@ -203,25 +247,188 @@ impl TuyaClient {
 
     pub async fn fetch_devices(&self, user_id: &str) -> Result<Vec<TuyaDevice>, AppError> {
         let path = format!("/v1.0/users/{}/devices", user_id);
-        self.get(&path).await
+        let mut devices: Vec<TuyaDevice> = self.get(&path).await?;
+        self.local_devices.register_devices(&mut devices).await;
+        Ok(devices)
     }
 
+    /// Reads status over the LAN when the device is reachable there, otherwise from the cloud.
     pub async fn fetch_device_status(
         &self,
         device_id: &str,
     ) -> Result<Vec<TuyaDeviceStatus>, AppError> {
+        if let Some(endpoint) = self.local_devices.reachable(device_id).await {
+            match self.fetch_local_status(device_id, &endpoint).await {
+                Ok(status) => return Ok(status),
+                Err(e) if local::is_unreachable(&e) => {
+                    tracing::warn!(
+                        "Local status for {} failed, falling back to cloud: {}",
+                        device_id,
+                        e
+                    );
+                    self.local_devices.mark_unreachable(device_id).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.fetch_cloud_status(device_id).await
+    }
+
+    /// Reads the status of many devices at once. LAN-reachable devices are queried
+    /// directly; the rest go through the batch endpoint, 20 ids per request.
+    pub async fn fetch_device_statuses(&self, device_ids: &[String]) -> StatusResults {
+        let mut results = StatusResults::new();
+
+        let mut local = Vec::new();
+        let mut cloud_ids = Vec::new();
+        for device_id in device_ids {
+            match self.local_devices.reachable(device_id).await {
+                Some(endpoint) => local.push((device_id, endpoint)),
+                None => cloud_ids.push(device_id.clone()),
+            }
+        }
+
+        let local_results = join_all(
+            local
+                .iter()
+                .map(|(device_id, endpoint)| self.fetch_local_status(device_id, endpoint)),
+        )
+        .await;
+        for ((device_id, _), result) in local.iter().zip(local_results) {
+            match result {
+                Ok(status) => {
+                    results.insert(device_id.to_string(), Ok(status));
+                }
+                Err(e) if local::is_unreachable(&e) => {
+                    tracing::warn!(
+                        "Local status for {} failed, falling back to cloud: {}",
+                        device_id,
+                        e
+                    );
+                    self.local_devices.mark_unreachable(device_id).await;
+                    cloud_ids.push(device_id.to_string());
+                }
+                Err(e) => {
+                    results.insert(device_id.to_string(), Err(e));
+                }
+            }
+        }
+
+        let cloud_results = batch_statuses(
+            &cloud_ids,
+            |ids| async move {
+                self.get_with_query("/v1.0/iot-03/devices/status", &[("device_ids", &ids)])
+                    .await
+            },
+            |id| async move { self.fetch_cloud_status(&id).await },
+        )
+        .await;
+        results.extend(cloud_results);
+        results
+    }
+
+    async fn fetch_cloud_status(&self, device_id: &str) -> Result<Vec<TuyaDeviceStatus>, AppError> {
         let path = format!("/v1.0/devices/{}/status", device_id);
         self.get(&path).await
     }
 
+    async fn fetch_local_status(
+        &self,
+        device_id: &str,
+        endpoint: &LocalEndpoint,
+    ) -> Result<Vec<TuyaDeviceStatus>, AppError> {
+        let dp_ids = self.local_dp_ids(device_id).await?;
+        let dps = local::fetch_dps(endpoint, device_id).await?;
+        Ok(local::dps_to_status(&dps, &dp_ids))
+    }
+
+    async fn send_local_commands(
+        &self,
+        device_id: &str,
+        endpoint: &LocalEndpoint,
+        commands: &[TuyaCommand],
+    ) -> Result<(), AppError> {
+        let dp_ids = self.local_dp_ids(device_id).await?;
+        let dps = local::commands_to_dps(commands, &dp_ids)?;
+        local::set_dps(endpoint, device_id, dps).await
+    }
+
+    /// The LAN protocol addresses DPs by numeric id rather than code. The mapping comes
+    /// from the cloud once and is cached, so local control keeps working offline.
+    async fn local_dp_ids(&self, device_id: &str) -> Result<HashMap<String, u32>, AppError> {
+        if let Some(dp_ids) = self.local_devices.dp_ids(device_id).await {
+            return Ok(dp_ids);
+        }
+
+        let dp_ids = self.fetch_dp_ids(device_id).await?;
+        self.local_devices
+            .set_dp_ids(device_id, dp_ids.clone())
+            .await;
+        Ok(dp_ids)
+    }
+
+    pub async fn fetch_dp_ids(&self, device_id: &str) -> Result<HashMap<String, u32>, AppError> {
+        let path = format!("/v2.0/cloud/thing/{}/shadow/properties", device_id);
+        let shadow: ShadowProperties = self.get(&path).await?;
+        Ok(shadow
+            .properties
+            .into_iter()
+            .map(|p| (p.code, p.dp_id))
+            .collect())
+    }
+
+    pub async fn fetch_device_specification(
+        &self,
+        device_id: &str,
+    ) -> Result<DeviceSpecification, AppError> {
+        let path = format!("/v1.0/devices/{}/specifications", device_id);
+        self.get(&path).await
+    }
+
     pub async fn send_device_commands(
         &self,
         device_id: &str,
         commands: Vec<TuyaCommand>,
     ) -> Result<bool, AppError> {
+        if let Some(endpoint) = self.local_devices.reachable(device_id).await {
+            match self
+                .send_local_commands(device_id, &endpoint, &commands)
+                .await
+            {
+                Ok(()) => {
+                    self.note_sent(device_id, &commands);
+                    return Ok(true);
+                }
+                Err(e) if local::is_unreachable(&e) => {
+                    tracing::warn!(
+                        "Local control of {} failed, falling back to cloud: {}",
+                        device_id,
+                        e
+                    );
+                    self.local_devices.mark_unreachable(device_id).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
         let path = format!("/v1.0/devices/{}/commands", device_id);
         let payload = TuyaCommandPayload { commands };
-        self.post(&path, &payload).await
+        let result = self.post(&path, &payload).await;
+        if result.is_ok() {
+            self.note_sent(device_id, &payload.commands);
+        }
+        result
+    }
+
+    /// Stamps the DPs of commands the device or cloud accepted, for `last_command_at`.
+    fn note_sent(&self, device_id: &str, commands: &[TuyaCommand]) {
+        if let Ok(mut sent) = self.sent_commands.lock() {
+            let now = chrono::Utc::now().timestamp();
+            for command in commands {
+                sent.insert(format!("{}:{}", device_id, command.code), now);
+            }
+        }
     }
 
     pub async fn send_device_command(
@@ -240,6 +447,99 @@ impl TuyaClient {
         .await
     }
 
+    pub async fn fetch_homes(&self, user_id: &str) -> Result<Vec<TuyaHome>, AppError> {
+        let path = format!("/v1.0/users/{}/homes", user_id);
+        self.get(&path).await
+    }
+
+    pub async fn fetch_scenes(&self, home_id: i64) -> Result<Vec<TuyaScene>, AppError> {
+        let path = format!("/v1.0/homes/{}/scenes", home_id);
+        self.get(&path).await
+    }
+
+    /// Rooms of a home with the ids of the devices in each. A room whose device list
+    /// fails to load is kept empty, so its devices fall back to the ungrouped list.
+    pub async fn fetch_rooms(&self, home_id: i64) -> Result<Vec<TuyaRoom>, AppError> {
+        let path = format!("/v1.0/homes/{}/rooms", home_id);
+        let HomeRooms { mut rooms } = self.get(&path).await?;
+
+        let device_lists = join_all(rooms.iter().map(|room| {
+            let path = format!("/v1.0/homes/{}/rooms/{}/devices", home_id, room.room_id);
+            async move { self.get::<Vec<RoomDevice>>(&path).await }
+        }))
+        .await;
+
+        for (room, devices) in rooms.iter_mut().zip(device_lists) {
+            match devices {
+                Ok(devices) => room.device_ids = devices.into_iter().map(|d| d.id).collect(),
+                Err(e) => tracing::warn!("Failed to fetch devices of room {}: {}", room.room_id, e),
+            }
+        }
+        Ok(rooms)
+    }
+
+    pub async fn trigger_scene(&self, home_id: i64, scene_id: &str) -> Result<bool, AppError> {
+        let path = format!("/v1.0/homes/{}/scenes/{}/trigger", home_id, scene_id);
+        self.post(&path, &serde_json::json!({})).await
+    }
+
+    /// Remotes set up on an IR hub.
+    pub async fn fetch_remotes(&self, infrared_id: &str) -> Result<Vec<TuyaRemote>, AppError> {
+        let path = format!("/v2.0/infrareds/{}/remotes", infrared_id);
+        self.get(&path).await
+    }
+
+    /// The state last sent to an IR air conditioner.
+    pub async fn fetch_ac_status(
+        &self,
+        infrared_id: &str,
+        remote_id: &str,
+    ) -> Result<AcScene, AppError> {
+        let path = format!(
+            "/v2.0/infrareds/{}/remotes/{}/ac/status",
+            infrared_id, remote_id
+        );
+        self.get(&path).await
+    }
+
+    pub async fn send_ac_scene(
+        &self,
+        infrared_id: &str,
+        remote_id: &str,
+        scene: &AcScene,
+    ) -> Result<bool, AppError> {
+        let path = format!(
+            "/v2.0/infrareds/{}/air-conditioners/{}/scenes/command",
+            infrared_id, remote_id
+        );
+        self.post(&path, scene).await
+    }
+
+    pub async fn fetch_learned_codes(
+        &self,
+        infrared_id: &str,
+        remote_id: &str,
+    ) -> Result<Vec<LearnedCode>, AppError> {
+        let path = format!(
+            "/v2.0/infrareds/{}/remotes/{}/learning-codes",
+            infrared_id, remote_id
+        );
+        self.get(&path).await
+    }
+
+    pub async fn send_learned_code(
+        &self,
+        infrared_id: &str,
+        remote_id: &str,
+        code: &str,
+    ) -> Result<bool, AppError> {
+        let path = format!(
+            "/v2.0/infrareds/{}/remotes/{}/learning-codes",
+            infrared_id, remote_id
+        );
+        self.post(&path, &serde_json::json!({ "code": code })).await
+    }
+
     pub async fn toggle_device_state(
         &self,
         device_id: &str,
@@ -251,7 +551,7 @@ impl TuyaClient {
     }
 }
 
-pub type SharedTuyaClient = Arc<RwLock<Option<TuyaClient>>>;
+pub type SharedTuyaClient = Arc<RwLock<Option<Arc<TuyaClient>>>>;
 
 pub fn create_shared_client() -> SharedTuyaClient {
     Arc::new(RwLock::new(None))
@@ -259,11 +559,174 @@ pub fn create_shared_client() -> SharedTuyaClient {
 
 pub async fn initialize_client(
     shared: &SharedTuyaClient,
+    discovered: &DiscoveredDevices,
     client_id: String,
     secret: String,
     base_url: String,
 ) {
-    let client = TuyaClient::new(client_id, secret, base_url);
+    let client = TuyaClient::new(client_id, secret, base_url, discovered.clone());
     let mut guard = shared.write().await;
-    *guard = Some(client);
+    *guard = Some(Arc::new(client));
+}
+
+/// A handle to the current client. Long-running work should hold this rather than the
+/// lock, which a config save has to wait on.
+pub async fn current_client(shared: &SharedTuyaClient) -> Option<Arc<TuyaClient>> {
+    shared.read().await.clone()
+}
+
+/// Fetches statuses `STATUS_BATCH_SIZE` devices per request, `fetch_batch` taking the
+/// comma-separated ids, and maps the entries back to their devices. Devices a batch
+/// response leaves out are fetched on their own with `fetch_one`; a failed batch
+/// request fails each of its devices with its error.
+async fn batch_statuses<B, BF, S, SF>(
+    device_ids: &[String],
+    fetch_batch: B,
+    fetch_one: S,
+) -> StatusResults
+where
+    B: Fn(String) -> BF,
+    BF: Future<Output = Result<Vec<DeviceStatusEntry>, AppError>>,
+    S: Fn(String) -> SF,
+    SF: Future<Output = Result<Vec<TuyaDeviceStatus>, AppError>>,
+{
+    let batches = join_all(
+        device_ids
+            .chunks(STATUS_BATCH_SIZE)
+            .map(|chunk| fetch_batch(chunk.join(","))),
+    )
+    .await;
+
+    let mut results = StatusResults::new();
+    let mut missing = Vec::new();
+    for (chunk, batch) in device_ids.chunks(STATUS_BATCH_SIZE).zip(batches) {
+        match batch {
+            Ok(entries) => {
+                let mut statuses: HashMap<String, Vec<TuyaDeviceStatus>> = entries
+                    .into_iter()
+                    .map(|entry| (entry.id, entry.status))
+                    .collect();
+                for id in chunk {
+                    match statuses.remove(id) {
+                        Some(status) => {
+                            results.insert(id.clone(), Ok(status));
+                        }
+                        None => missing.push(id.clone()),
+                    }
+                }
+            }
+            Err(e) => {
+                tracing::warn!("Batch status request failed: {}", e);
+                results.extend(chunk.iter().map(|id| (id.clone(), Err(copy_error(&e)))));
+            }
+        }
+    }
+
+    let individual = join_all(missing.iter().cloned().map(&fetch_one)).await;
+    results.extend(missing.into_iter().zip(individual));
+    results
+}
+
+/// `AppError` isn't `Clone`; network and API errors keep their variant, anything else
+/// is carried as text.
+fn copy_error(error: &AppError) -> AppError {
+    match error {
+        AppError::Network(message) => AppError::Network(message.clone()),
+        AppError::Api { code, message } => AppError::Api {
+            code: *code,
+            message: message.clone(),
+        },
+        other => AppError::Parse(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::tuya::backend::fake::status;
+
+    fn ids(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("dev{}", i)).collect()
+    }
+
+    fn entry(id: &str) -> DeviceStatusEntry {
+        DeviceStatusEntry {
+            id: id.to_string(),
+            status: vec![status("switch_1", TuyaValue::Boolean(id.ends_with('1')))],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_statuses_are_fetched_in_batches() {
+        let requested = Mutex::new(Vec::new());
+        let results = batch_statuses(
+            &ids(45),
+            |ids| {
+                let batch: Vec<String> = ids.split(',').map(str::to_string).collect();
+                requested.lock().unwrap().push(batch.len());
+                async move { Ok(batch.iter().map(|id| entry(id)).collect()) }
+            },
+            |_| async { panic!("every device was in its batch") },
+        )
+        .await;
+
+        assert_eq!(*requested.lock().unwrap(), [20, 20, 5]);
+        assert_eq!(results.len(), 45);
+        assert!(results.values().all(Result::is_ok));
+    }
+
+    #[tokio::test]
+    async fn test_entries_are_mapped_to_their_devices() {
+        let fetched_alone = Mutex::new(Vec::new());
+        let results = batch_statuses(
+            &ids(3),
+            // Out of order, missing dev2 and with a device nobody asked for
+            |_| async { Ok(vec![entry("dev1"), entry("other"), entry("dev0")]) },
+            |id| {
+                fetched_alone.lock().unwrap().push(id);
+                async { Ok(Vec::new()) }
+            },
+        )
+        .await;
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(
+            results["dev1"].as_ref().unwrap()[0].value,
+            TuyaValue::Boolean(true)
+        );
+        assert_eq!(
+            results["dev0"].as_ref().unwrap()[0].value,
+            TuyaValue::Boolean(false)
+        );
+        assert!(results["dev2"].as_ref().unwrap().is_empty());
+        assert_eq!(*fetched_alone.lock().unwrap(), ["dev2"]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_batches_fail_their_devices() {
+        let results = batch_statuses(
+            &ids(25),
+            |ids| async move {
+                if ids.starts_with("dev0,") {
+                    Err(AppError::Api {
+                        code: 1010,
+                        message: "token invalid".to_string(),
+                    })
+                } else {
+                    Ok(ids.split(',').map(entry).collect())
+                }
+            },
+            |_| async { panic!("a failed batch is not retried per device") },
+        )
+        .await;
+
+        let failed = results
+            .values()
+            .filter(|result| matches!(result, Err(AppError::Api { code: 1010, .. })))
+            .count();
+        assert_eq!(failed, 20);
+        assert!(results["dev24"].is_ok());
+    }
 }
@@ -0,0 +1,485 @@
+use std::time::{Duration, Instant};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::{SinkExt, StreamExt};
+use md5::{Digest, Md5};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, http::HeaderValue, Message};
+
+use super::local::protocol::{ecb_decrypt, gcm_decrypt, LocalKey};
+use super::types::TuyaDeviceStatus;
+use crate::error::AppError;
+
+const MQ_PORT: u16 = 8285;
+const PING_INTERVAL_SECS: u64 = 30;
+const SETTINGS_CHECK_SECS: u64 = 30;
+const RECONNECT_DELAY_SECS: u64 = 5;
+const MAX_RECONNECT_DELAY_SECS: u64 = 300;
+/// A connection that stayed up this long counts as healthy and resets the backoff.
+const STABLE_CONNECTION_SECS: u64 = 60;
+const GCM_IV_LEN: usize = 12;
+
+const PROTOCOL_STATUS_REPORT: u32 = 4;
+const PROTOCOL_DEVICE_EVENT: u32 = 20;
+
+/// What the message queue subscriber reports back to the app.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueueEvent {
+    Connected,
+    Disconnected,
+    Status {
+        device_id: String,
+        status: Vec<TuyaDeviceStatus>,
+    },
+    Online {
+        device_id: String,
+    },
+    Offline {
+        device_id: String,
+    },
+}
+
+/// Connection details for the Pulsar-over-WebSocket message service of a cloud project.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageQueueConfig {
+    pub url: String,
+    pub access_id: String,
+    pub access_key: String,
+}
+
+impl MessageQueueConfig {
+    /// The message service sits next to the OpenAPI host of the same region, e.g.
+    /// openapi.tuyaeu.com is served by mqe.tuyaeu.com.
+    pub fn for_region(base_url: &str, access_id: &str, access_key: &str) -> Option<Self> {
+        let url = url::Url::parse(base_url).ok()?;
+        let (_, domain) = url.host_str()?.split_once('.')?;
+        Some(Self {
+            url: format!("wss://mqe.{}:{}", domain, MQ_PORT),
+            access_id: access_id.to_string(),
+            access_key: access_key.to_string(),
+        })
+    }
+
+    fn topic_url(&self) -> String {
+        format!(
+            "{}/ws/v2/consumer/persistent/{id}/out/event/{id}-sub?ackTimeoutMillis=3000&subscriptionType=Failover",
+            self.url.trim_end_matches('/'),
+            id = self.access_id
+        )
+    }
+
+    fn password(&self) -> String {
+        let key_hash = md5_hex(self.access_key.as_bytes());
+        md5_hex(format!("{}{}", self.access_id, key_hash).as_bytes())[8..24].to_string()
+    }
+
+    fn payload_key(&self) -> Result<LocalKey, AppError> {
+        self.access_key
+            .as_bytes()
+            .get(8..24)
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| AppError::Config("Access secret is too short".to_string()))
+    }
+}
+
+fn md5_hex(data: &[u8]) -> String {
+    hex::encode(Md5::digest(data))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QueueMessage {
+    message_id: String,
+    payload: String,
+    #[serde(default)]
+    properties: MessageProperties,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct MessageProperties {
+    /// Encryption model of `data`; "aes_gcm" on newer projects, ECB otherwise.
+    #[serde(default)]
+    em: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Envelope {
+    protocol: u32,
+    data: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StatusReport {
+    dev_id: String,
+    status: Vec<TuyaDeviceStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DeviceEvent {
+    dev_id: String,
+    biz_code: String,
+}
+
+fn decrypt_data(data: &str, encryption: &str, key: &LocalKey) -> Result<Vec<u8>, AppError> {
+    let raw = STANDARD
+        .decode(data)
+        .map_err(|e| AppError::Parse(format!("Invalid message data: {}", e)))?;
+
+    if encryption == "aes_gcm" {
+        if raw.len() < GCM_IV_LEN {
+            return Err(AppError::Parse("Message data too short".to_string()));
+        }
+        let (iv, ciphertext) = raw.split_at(GCM_IV_LEN);
+        gcm_decrypt(key, iv, &[], ciphertext)
+    } else {
+        ecb_decrypt(key, &raw, true)
+    }
+}
+
+fn decode_event(message: &QueueMessage, key: &LocalKey) -> Result<Option<QueueEvent>, AppError> {
+    let payload = STANDARD
+        .decode(&message.payload)
+        .map_err(|e| AppError::Parse(format!("Invalid message payload: {}", e)))?;
+    let envelope: Envelope = serde_json::from_slice(&payload)?;
+
+    match envelope.protocol {
+        PROTOCOL_STATUS_REPORT => {
+            let data = decrypt_data(&envelope.data, &message.properties.em, key)?;
+            let report: StatusReport = serde_json::from_slice(&data)?;
+            Ok(Some(QueueEvent::Status {
+                device_id: report.dev_id,
+                status: report.status,
+            }))
+        }
+        PROTOCOL_DEVICE_EVENT => {
+            let data = decrypt_data(&envelope.data, &message.properties.em, key)?;
+            let event: DeviceEvent = serde_json::from_slice(&data)?;
+            Ok(match event.biz_code.as_str() {
+                "online" => Some(QueueEvent::Online {
+                    device_id: event.dev_id,
+                }),
+                "offline" => Some(QueueEvent::Offline {
+                    device_id: event.dev_id,
+                }),
+                _ => None,
+            })
+        }
+        _ => Ok(None),
+    }
+}
+
+fn ws_error(e: impl std::fmt::Display) -> AppError {
+    AppError::Network(format!("Message queue: {}", e))
+}
+
+/// Subscribes once and forwards events until the server closes the connection or the
+/// receiving side goes away. Every message is acknowledged, even ones we ignore, so
+/// the server doesn't redeliver them.
+pub async fn subscribe(
+    config: &MessageQueueConfig,
+    sender: &UnboundedSender<QueueEvent>,
+) -> Result<(), AppError> {
+    let key = config.payload_key()?;
+
+    let mut request = config.topic_url().into_client_request().map_err(ws_error)?;
+    let headers = request.headers_mut();
+    headers.insert(
+        "username",
+        HeaderValue::from_str(&config.access_id).map_err(ws_error)?,
+    );
+    headers.insert(
+        "password",
+        HeaderValue::from_str(&config.password()).map_err(ws_error)?,
+    );
+
+    let (stream, _) = tokio_tungstenite::connect_async(request)
+        .await
+        .map_err(ws_error)?;
+    let (mut write, mut read) = stream.split();
+    tracing::info!("Connected to the message queue");
+    let _ = sender.send(QueueEvent::Connected);
+
+    let mut ping = tokio::time::interval(Duration::from_secs(PING_INTERVAL_SECS));
+    ping.tick().await;
+
+    loop {
+        let message = tokio::select! {
+            _ = ping.tick() => {
+                write.send(Message::Ping(Vec::new())).await.map_err(ws_error)?;
+                continue;
+            }
+            message = read.next() => message,
+        };
+
+        let text = match message {
+            Some(Ok(Message::Text(text))) => text,
+            Some(Ok(Message::Close(_))) | None => return Ok(()),
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(ws_error(e)),
+        };
+
+        let message: QueueMessage = match serde_json::from_str(&text) {
+            Ok(message) => message,
+            Err(e) => {
+                tracing::debug!("Ignoring unrecognised queue frame: {}", e);
+                continue;
+            }
+        };
+        let ack = json!({ "messageId": message.message_id }).to_string();
+        write.send(Message::Text(ack)).await.map_err(ws_error)?;
+
+        match decode_event(&message, &key) {
+            Ok(Some(event)) => {
+                if sender.send(event).is_err() {
+                    return Ok(());
+                }
+            }
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Failed to decode queue message: {}", e),
+        }
+    }
+}
+
+/// Keeps a subscription alive for as long as `sender` has a receiver, reconnecting with
+/// exponential backoff. `settings` is polled so that turning the option off or changing
+/// credentials drops the current connection; None means the subscriber should idle.
+pub async fn run_subscriber<F>(settings: F, sender: UnboundedSender<QueueEvent>)
+where
+    F: Fn() -> Option<MessageQueueConfig>,
+{
+    let mut delay = RECONNECT_DELAY_SECS;
+
+    while !sender.is_closed() {
+        let Some(config) = settings() else {
+            tokio::time::sleep(Duration::from_secs(SETTINGS_CHECK_SECS)).await;
+            continue;
+        };
+
+        let started = Instant::now();
+        tokio::select! {
+            result = subscribe(&config, &sender) => match result {
+                Ok(()) => tracing::info!("Message queue connection closed"),
+                Err(e) => tracing::warn!("Message queue connection failed: {}", e),
+            },
+            _ = settings_changed(&settings, &config) => {
+                tracing::info!("Message queue settings changed, reconnecting");
+                delay = 0;
+            }
+        }
+        let _ = sender.send(QueueEvent::Disconnected);
+
+        if started.elapsed() >= Duration::from_secs(STABLE_CONNECTION_SECS) {
+            delay = RECONNECT_DELAY_SECS;
+        }
+        tokio::time::sleep(Duration::from_secs(delay)).await;
+        delay = (delay * 2).clamp(RECONNECT_DELAY_SECS, MAX_RECONNECT_DELAY_SECS);
+    }
+}
+
+async fn settings_changed<F>(settings: &F, current: &MessageQueueConfig)
+where
+    F: Fn() -> Option<MessageQueueConfig>,
+{
+    loop {
+        tokio::time::sleep(Duration::from_secs(SETTINGS_CHECK_SECS)).await;
+        if settings().as_ref() != Some(current) {
+            return;
+        }
+    }
+}
+
+/// Applies a status report on top of a device's cached status. Reports usually carry
+/// only the DPs that changed, and codes the cache doesn't know are left for the next
+/// full refresh since showing them needs a menu rebuild anyway.
+pub fn merge_status(
+    current: &[TuyaDeviceStatus],
+    report: &[TuyaDeviceStatus],
+) -> Vec<TuyaDeviceStatus> {
+    current
+        .iter()
+        .map(|status| {
+            report
+                .iter()
+                .rev()
+                .find(|reported| reported.code == status.code)
+                .unwrap_or(status)
+                .clone()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::local::protocol::{ecb_encrypt, gcm_encrypt};
+    use super::super::types::TuyaValue;
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+    const ACCESS_ID: &str = "accessid123";
+    const ACCESS_KEY: &str = "0123456789abcdef0123456789abcdef";
+
+    fn key() -> LocalKey {
+        ACCESS_KEY.as_bytes()[8..24].try_into().unwrap()
+    }
+
+    fn queue_message(id: &str, protocol: u32, data: serde_json::Value, gcm: bool) -> String {
+        let plaintext = data.to_string();
+        let encrypted = if gcm {
+            let iv = [3u8; GCM_IV_LEN];
+            [
+                iv.to_vec(),
+                gcm_encrypt(&key(), &iv, &[], plaintext.as_bytes()).unwrap(),
+            ]
+            .concat()
+        } else {
+            ecb_encrypt(&key(), plaintext.as_bytes(), true)
+        };
+        let envelope = json!({
+            "protocol": protocol,
+            "pv": "2.0",
+            "t": 1700000000000i64,
+            "data": STANDARD.encode(encrypted),
+        });
+        json!({
+            "messageId": id,
+            "payload": STANDARD.encode(envelope.to_string()),
+            "properties": { "em": if gcm { "aes_gcm" } else { "" } },
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_region_endpoints_follow_the_api_host() {
+        let config = MessageQueueConfig::for_region(
+            "https://openapi-weaz.tuyaeu.com",
+            ACCESS_ID,
+            ACCESS_KEY,
+        )
+        .unwrap();
+        assert_eq!(config.url, "wss://mqe.tuyaeu.com:8285");
+        assert!(config
+            .topic_url()
+            .ends_with("/persistent/accessid123/out/event/accessid123-sub?ackTimeoutMillis=3000&subscriptionType=Failover"));
+        assert_eq!(config.password().len(), 16);
+    }
+
+    #[test]
+    fn test_merge_keeps_unreported_codes() {
+        let current = vec![
+            TuyaDeviceStatus {
+                code: "switch_1".to_string(),
+                value: TuyaValue::Boolean(false),
+            },
+            TuyaDeviceStatus {
+                code: "countdown_1".to_string(),
+                value: TuyaValue::Integer(0),
+            },
+        ];
+        let report = vec![
+            TuyaDeviceStatus {
+                code: "switch_1".to_string(),
+                value: TuyaValue::Boolean(true),
+            },
+            TuyaDeviceStatus {
+                code: "unknown".to_string(),
+                value: TuyaValue::Integer(1),
+            },
+        ];
+
+        let merged = merge_status(&current, &report);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].value, TuyaValue::Boolean(true));
+        assert_eq!(merged[1].value, TuyaValue::Integer(0));
+    }
+
+    #[tokio::test]
+    #[allow(clippy::result_large_err)] // the handshake callback signature is fixed by tungstenite
+    async fn subscriber_decodes_and_acknowledges_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut headers = None;
+            let mut ws = tokio_tungstenite::accept_hdr_async(
+                stream,
+                |request: &Request, response: Response| {
+                    headers = Some((
+                        request.uri().path().to_string(),
+                        request.headers()["username"].to_str().unwrap().to_string(),
+                    ));
+                    Ok(response)
+                },
+            )
+            .await
+            .unwrap();
+
+            let messages = [
+                queue_message(
+                    "m1",
+                    PROTOCOL_STATUS_REPORT,
+                    json!({
+                        "devId": "dev1",
+                        "status": [{ "code": "switch_1", "value": true, "t": 1, "1": "true" }],
+                    }),
+                    true,
+                ),
+                queue_message(
+                    "m2",
+                    PROTOCOL_DEVICE_EVENT,
+                    json!({ "devId": "dev2", "bizCode": "offline", "bizData": {} }),
+                    false,
+                ),
+            ];
+            let mut acks = Vec::new();
+            for message in messages {
+                ws.send(Message::Text(message)).await.unwrap();
+                if let Some(Ok(Message::Text(ack))) = ws.next().await {
+                    acks.push(ack);
+                }
+            }
+            ws.close(None).await.unwrap();
+            (headers.unwrap(), acks)
+        });
+
+        let config = MessageQueueConfig {
+            url: format!("ws://127.0.0.1:{}", port),
+            access_id: ACCESS_ID.to_string(),
+            access_key: ACCESS_KEY.to_string(),
+        };
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        subscribe(&config, &sender).await.unwrap();
+
+        let ((path, username), acks) = server.await.unwrap();
+        assert_eq!(
+            path,
+            "/ws/v2/consumer/persistent/accessid123/out/event/accessid123-sub"
+        );
+        assert_eq!(username, ACCESS_ID);
+        assert_eq!(acks, vec![r#"{"messageId":"m1"}"#, r#"{"messageId":"m2"}"#]);
+
+        assert_eq!(receiver.recv().await, Some(QueueEvent::Connected));
+        assert_eq!(
+            receiver.recv().await,
+            Some(QueueEvent::Status {
+                device_id: "dev1".to_string(),
+                status: vec![TuyaDeviceStatus {
+                    code: "switch_1".to_string(),
+                    value: TuyaValue::Boolean(true),
+                }],
+            })
+        );
+        assert_eq!(
+            receiver.recv().await,
+            Some(QueueEvent::Offline {
+                device_id: "dev2".to_string()
+            })
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::time::{timeout_at, Instant};
+
+use super::protocol::{decode_message, LocalKey, ProtocolVersion};
+use crate::error::AppError;
+
+/// Plaintext broadcasts from old 3.1 firmware arrive on 6666, encrypted ones on 6667.
+pub const DISCOVERY_PORTS: [u16; 2] = [6666, 6667];
+
+/// Devices broadcast roughly every five seconds, so listening a little longer
+/// catches each of them at least once.
+pub const DISCOVERY_WINDOW_SECS: u64 = 6;
+
+/// md5("yGAdlopoPVldABfn"), the fixed key all devices encrypt their broadcasts with.
+const UDP_KEY: LocalKey = [
+    0x6c, 0x1e, 0xc8, 0xe2, 0xbb, 0x9b, 0xb5, 0x9a, 0xb5, 0x0b, 0x0d, 0xaf, 0x64, 0x9b, 0x41, 0x0a,
+];
+
+const MAX_DATAGRAM_LEN: usize = 2048;
+
+/// The announcement a device broadcasts on the LAN.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiscoveredDevice {
+    pub gw_id: String,
+    pub ip: String,
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub product_key: String,
+}
+
+impl DiscoveredDevice {
+    /// None for protocol versions this app cannot speak (3.1, 3.2).
+    pub fn protocol(&self) -> Option<ProtocolVersion> {
+        ProtocolVersion::parse(&self.version)
+    }
+}
+
+pub fn parse_broadcast(datagram: &[u8]) -> Result<DiscoveredDevice, AppError> {
+    let payload = decode_message(ProtocolVersion::V35, &UDP_KEY, datagram, true)
+        .or_else(|_| decode_message(ProtocolVersion::V33, &UDP_KEY, datagram, true))
+        .map(|message| message.payload)
+        .or_else(|_| plaintext_payload(datagram))?;
+
+    serde_json::from_slice(&payload).map_err(|e| {
+        AppError::Local(format!(
+            "Invalid broadcast ({}): {}",
+            e,
+            String::from_utf8_lossy(&payload)
+        ))
+    })
+}
+
+/// 3.1 devices send their announcement unencrypted inside the frame.
+fn plaintext_payload(datagram: &[u8]) -> Result<Vec<u8>, AppError> {
+    let start = datagram.iter().position(|b| *b == b'{');
+    let end = datagram.iter().rposition(|b| *b == b'}');
+    match (start, end) {
+        (Some(start), Some(end)) if start < end => Ok(datagram[start..=end].to_vec()),
+        _ => Err(AppError::Local("Unrecognised broadcast".to_string())),
+    }
+}
+
+/// Listens on the discovery ports for `window` and returns every device heard, keyed
+/// by device id. Ports already taken by another app are skipped.
+pub async fn discover(window: Duration) -> Result<HashMap<String, DiscoveredDevice>, AppError> {
+    let mut sockets = Vec::new();
+    for port in DISCOVERY_PORTS {
+        match UdpSocket::bind(("0.0.0.0", port)).await {
+            Ok(socket) => sockets.push(socket),
+            Err(e) => tracing::warn!("Cannot listen for broadcasts on port {}: {}", port, e),
+        }
+    }
+    if sockets.is_empty() {
+        return Err(AppError::Local(
+            "No discovery port could be opened".to_string(),
+        ));
+    }
+
+    let deadline = Instant::now() + window;
+    let results =
+        futures::future::join_all(sockets.iter().map(|socket| listen(socket, deadline))).await;
+
+    let discovered: HashMap<String, DiscoveredDevice> = results
+        .into_iter()
+        .flatten()
+        .map(|device| (device.gw_id.clone(), device))
+        .collect();
+    tracing::info!("Discovered {} device(s) on the LAN", discovered.len());
+    Ok(discovered)
+}
+
+async fn listen(socket: &UdpSocket, deadline: Instant) -> Vec<DiscoveredDevice> {
+    let mut devices = Vec::new();
+    let mut buffer = [0u8; MAX_DATAGRAM_LEN];
+
+    while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+        let (len, from) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                tracing::debug!("Discovery receive failed: {}", e);
+                continue;
+            }
+        };
+        match parse_broadcast(&buffer[..len]) {
+            Ok(device) => devices.push(device),
+            Err(e) => tracing::debug!("Ignoring broadcast from {}: {}", from, e),
+        }
+    }
+    devices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::protocol::{command, encode_message, Message};
+    use super::*;
+
+    const ANNOUNCEMENT: &str = r#"{"ip":"192.168.1.20","gwId":"bf0123456789abcdef","active":2,"ability":0,"mode":0,"encrypt":true,"productKey":"keyabc","version":"3.4"}"#;
+
+    fn broadcast(version: ProtocolVersion) -> Vec<u8> {
+        encode_message(
+            version,
+            &UDP_KEY,
+            &Message {
+                seq: 0,
+                cmd: command::UDP_NEW,
+                retcode: Some(0),
+                payload: ANNOUNCEMENT.as_bytes().to_vec(),
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parses_encrypted_broadcasts() {
+        for version in [ProtocolVersion::V33, ProtocolVersion::V35] {
+            let device = parse_broadcast(&broadcast(version)).unwrap();
+            assert_eq!(device.gw_id, "bf0123456789abcdef");
+            assert_eq!(device.ip, "192.168.1.20");
+            assert_eq!(device.protocol(), Some(ProtocolVersion::V34));
+            assert_eq!(device.product_key, "keyabc");
+        }
+    }
+
+    #[test]
+    fn test_parses_plaintext_broadcast() {
+        let mut frame = vec![0, 0, 0x55, 0xaa, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        frame.extend_from_slice(&[0, 0, 0, 0]);
+        frame.extend_from_slice(br#"{"ip":"10.0.0.5","gwId":"abc","version":"3.1"}"#);
+        frame.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0xaa, 0x55]);
+
+        let device = parse_broadcast(&frame).unwrap();
+        assert_eq!(device.gw_id, "abc");
+        assert_eq!(device.ip, "10.0.0.5");
+        assert_eq!(device.protocol(), None);
+    }
+
+    #[test]
+    fn test_rejects_garbage() {
+        assert!(parse_broadcast(b"hello").is_err());
+    }
+}
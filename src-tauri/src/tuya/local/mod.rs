@@ -0,0 +1,644 @@
+pub mod discovery;
+pub mod protocol;
+pub mod session;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use tokio::sync::RwLock;
+
+use super::types::{TuyaCommand, TuyaDevice, TuyaDeviceStatus, TuyaValue};
+use crate::error::AppError;
+pub use discovery::{discover, DiscoveredDevice, DISCOVERY_WINDOW_SECS};
+use protocol::command;
+pub use protocol::ProtocolVersion;
+pub use session::LocalSession;
+
+pub const LOCAL_PORT: u16 = 6668;
+
+const UNREACHABLE_BACKOFF_SECS: i64 = 60;
+
+/// Devices heard on the LAN, by id. Kept outside the client, which a config save
+/// replaces, and shared with each new one.
+pub type DiscoveredDevices = Arc<RwLock<HashMap<String, DiscoveredDevice>>>;
+
+pub fn create_discovered_devices() -> DiscoveredDevices {
+    Arc::new(RwLock::new(HashMap::new()))
+}
+
+/// Where and how to reach a device on the LAN.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalEndpoint {
+    pub ip: String,
+    pub port: u16,
+    pub local_key: String,
+    pub version: ProtocolVersion,
+}
+
+struct LocalDeviceEntry {
+    endpoint: LocalEndpoint,
+    dp_ids: Option<HashMap<String, u32>>,
+    unreachable_until: i64,
+}
+
+/// How a device can be reached locally, as shown on the config page.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocalDeviceInfo {
+    pub id: String,
+    pub name: String,
+    pub ip: String,
+    pub version: Option<String>,
+    pub discovered: bool,
+    pub controllable: bool,
+}
+
+/// Devices that can be controlled over the LAN, along with the code to DP id mapping
+/// the local protocol needs. Devices that can't be reached are skipped for a while so an
+/// unreachable device doesn't add a connect timeout to every request.
+#[derive(Default)]
+pub struct LocalDevices {
+    devices: RwLock<HashMap<String, LocalDeviceEntry>>,
+    discovered: DiscoveredDevices,
+}
+
+impl LocalDevices {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts from the devices already heard on the LAN.
+    pub fn with_discovered(discovered: DiscoveredDevices) -> Self {
+        Self {
+            devices: RwLock::default(),
+            discovered,
+        }
+    }
+
+    pub async fn register(&self, device_id: &str, endpoint: LocalEndpoint) {
+        let mut devices = self.devices.write().await;
+        match devices.get_mut(device_id) {
+            Some(entry) if entry.endpoint == endpoint => {}
+            Some(entry) => {
+                entry.endpoint = endpoint;
+                entry.unreachable_until = 0;
+            }
+            None => {
+                devices.insert(
+                    device_id.to_string(),
+                    LocalDeviceEntry {
+                        endpoint,
+                        dp_ids: None,
+                        unreachable_until: 0,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Remembers devices heard on the LAN; the next `register_devices` call picks them up.
+    pub async fn merge_discovered(&self, discovered: HashMap<String, DiscoveredDevice>) {
+        self.discovered.write().await.extend(discovered);
+    }
+
+    /// Fills in the LAN address of discovered devices and registers those the cloud
+    /// gave a local key. Only discovery reports the protocol version, and a wrong guess
+    /// costs a failed handshake per request, so devices not heard on the LAN yet stay
+    /// on the cloud.
+    pub async fn register_devices(&self, devices: &mut [TuyaDevice]) {
+        let discovered = self.discovered.read().await.clone();
+
+        for device in devices.iter_mut() {
+            let Some(found) = discovered.get(&device.id) else {
+                continue;
+            };
+            device.ip = found.ip.clone();
+            if device.local_key.is_empty() || !is_lan_address(&device.ip) {
+                continue;
+            }
+            let Some(version) = found.protocol() else {
+                continue;
+            };
+            self.register(
+                &device.id,
+                LocalEndpoint {
+                    ip: device.ip.clone(),
+                    port: LOCAL_PORT,
+                    local_key: device.local_key.clone(),
+                    version,
+                },
+            )
+            .await;
+        }
+    }
+
+    pub async fn describe(&self, devices: &[TuyaDevice]) -> Vec<LocalDeviceInfo> {
+        let registered = self.devices.read().await;
+        let discovered = self.discovered.read().await;
+
+        devices
+            .iter()
+            .map(|device| {
+                let endpoint = registered.get(&device.id).map(|entry| &entry.endpoint);
+                let found = discovered.get(&device.id);
+                LocalDeviceInfo {
+                    id: device.id.clone(),
+                    name: device.name.clone(),
+                    ip: device.ip.clone(),
+                    version: found
+                        .map(|found| found.version.clone())
+                        .or_else(|| endpoint.map(|endpoint| endpoint.version.to_string())),
+                    discovered: found.is_some(),
+                    controllable: endpoint.is_some(),
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the endpoint for a device unless it recently failed.
+    pub async fn reachable(&self, device_id: &str) -> Option<LocalEndpoint> {
+        let now = chrono::Utc::now().timestamp();
+        self.devices
+            .read()
+            .await
+            .get(device_id)
+            .filter(|entry| entry.unreachable_until <= now)
+            .map(|entry| entry.endpoint.clone())
+    }
+
+    pub async fn mark_unreachable(&self, device_id: &str) {
+        if let Some(entry) = self.devices.write().await.get_mut(device_id) {
+            entry.unreachable_until = chrono::Utc::now().timestamp() + UNREACHABLE_BACKOFF_SECS;
+        }
+    }
+
+    pub async fn dp_ids(&self, device_id: &str) -> Option<HashMap<String, u32>> {
+        self.devices
+            .read()
+            .await
+            .get(device_id)
+            .and_then(|entry| entry.dp_ids.clone())
+    }
+
+    pub async fn set_dp_ids(&self, device_id: &str, dp_ids: HashMap<String, u32>) {
+        if let Some(entry) = self.devices.write().await.get_mut(device_id) {
+            entry.dp_ids = Some(dp_ids);
+        }
+    }
+}
+
+/// Whether a local request failed to reach the device: a connect, timeout or other
+/// I/O failure. A device that answered and rejected the request is still reachable.
+pub fn is_unreachable(error: &AppError) -> bool {
+    matches!(error, AppError::Io(_))
+}
+
+/// True for private, link-local and loopback IPv4 addresses. The cloud often reports
+/// the public address of the user's router, which is useless for local control.
+pub fn is_lan_address(ip: &str) -> bool {
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V4(v4)) => v4.is_private() || v4.is_link_local() || v4.is_loopback(),
+        _ => false,
+    }
+}
+
+fn timestamp() -> String {
+    chrono::Utc::now().timestamp().to_string()
+}
+
+fn extract_dps(payload: &[u8]) -> Result<Map<String, Value>, AppError> {
+    let body: Value = serde_json::from_slice(payload).map_err(|e| {
+        AppError::Local(format!(
+            "Invalid status payload ({}): {}",
+            e,
+            String::from_utf8_lossy(payload)
+        ))
+    })?;
+
+    // 3.3 replies carry "dps" at the top level, 3.4/3.5 nest it under "data"
+    body.get("dps")
+        .or_else(|| body.get("data").and_then(|data| data.get("dps")))
+        .and_then(|dps| dps.as_object())
+        .cloned()
+        .ok_or_else(|| AppError::Local("Status payload has no dps".to_string()))
+}
+
+/// Queries all DP values, keyed by DP id.
+pub async fn fetch_dps(
+    endpoint: &LocalEndpoint,
+    device_id: &str,
+) -> Result<Map<String, Value>, AppError> {
+    let mut session = LocalSession::connect(endpoint).await?;
+
+    let (cmd, payload) = match endpoint.version {
+        ProtocolVersion::V33 => (
+            command::DP_QUERY,
+            json!({
+                "gwId": device_id,
+                "devId": device_id,
+                "uid": device_id,
+                "t": timestamp(),
+            }),
+        ),
+        _ => (command::DP_QUERY_NEW, json!({})),
+    };
+
+    let response = session
+        .exchange(
+            cmd,
+            payload.to_string().into_bytes(),
+            &[cmd, command::STATUS],
+        )
+        .await?;
+    extract_dps(&response.payload)
+}
+
+/// Sets DP values, keyed by DP id.
+pub async fn set_dps(
+    endpoint: &LocalEndpoint,
+    device_id: &str,
+    dps: Map<String, Value>,
+) -> Result<(), AppError> {
+    let mut session = LocalSession::connect(endpoint).await?;
+
+    let (cmd, payload) = match endpoint.version {
+        ProtocolVersion::V33 => (
+            command::CONTROL,
+            json!({
+                "devId": device_id,
+                "uid": device_id,
+                "t": timestamp(),
+                "dps": dps,
+            }),
+        ),
+        _ => (
+            command::CONTROL_NEW,
+            json!({
+                "protocol": 5,
+                "t": chrono::Utc::now().timestamp(),
+                "data": { "dps": dps },
+            }),
+        ),
+    };
+
+    session
+        .exchange(cmd, payload.to_string().into_bytes(), &[cmd])
+        .await?;
+    Ok(())
+}
+
+/// Maps DP-id keyed values back to status codes, ordered by DP id like the cloud API.
+pub fn dps_to_status(
+    dps: &Map<String, Value>,
+    dp_ids: &HashMap<String, u32>,
+) -> Vec<TuyaDeviceStatus> {
+    let mut statuses: Vec<(u32, TuyaDeviceStatus)> = dp_ids
+        .iter()
+        .filter_map(|(code, dp_id)| {
+            let value = dps.get(&dp_id.to_string())?;
+            let value = serde_json::from_value::<TuyaValue>(value.clone()).ok()?;
+            Some((
+                *dp_id,
+                TuyaDeviceStatus {
+                    code: code.clone(),
+                    value,
+                },
+            ))
+        })
+        .collect();
+
+    statuses.sort_by_key(|(dp_id, _)| *dp_id);
+    statuses.into_iter().map(|(_, status)| status).collect()
+}
+
+pub fn commands_to_dps(
+    commands: &[TuyaCommand],
+    dp_ids: &HashMap<String, u32>,
+) -> Result<Map<String, Value>, AppError> {
+    let mut dps = Map::new();
+    for cmd in commands {
+        let dp_id = dp_ids
+            .get(&cmd.code)
+            .ok_or_else(|| AppError::Local(format!("No DP id known for {}", cmd.code)))?;
+        dps.insert(dp_id.to_string(), local_value(&cmd.code, &cmd.value)?);
+    }
+    Ok(dps)
+}
+
+/// Devices take structured DPs as strings over the LAN. `colour_data_v2` is hue,
+/// saturation and value as 4 hex digits each; the older `colour_data` is the RGB
+/// equivalent followed by a 4-digit hue and 2-digit saturation and value. Anything
+/// else goes as JSON text.
+fn local_value(code: &str, value: &TuyaValue) -> Result<Value, AppError> {
+    let TuyaValue::Json(json) = value else {
+        return Ok(serde_json::to_value(value)?);
+    };
+    let channel = |key: &str| json.get(key).and_then(Value::as_u64);
+    let hsv = (channel("h"), channel("s"), channel("v"));
+    let text = match (code, hsv) {
+        ("colour_data_v2", (Some(h), Some(s), Some(v))) => format!("{:04x}{:04x}{:04x}", h, s, v),
+        ("colour_data", (Some(h), Some(s), Some(v))) => {
+            let (r, g, b) = hsv_to_rgb(h, s, v);
+            format!("{:02x}{:02x}{:02x}{:04x}{:02x}{:02x}", r, g, b, h, s, v)
+        }
+        _ => json.to_string(),
+    };
+    Ok(Value::String(text))
+}
+
+/// `h` in degrees, `s` and `v` over 0-255 as `colour_data` ranges them.
+fn hsv_to_rgb(h: u64, s: u64, v: u64) -> (u8, u8, u8) {
+    let s = s.min(255) as f64 / 255.0;
+    let v = v.min(255) as f64 / 255.0;
+    let chroma = v * s;
+    let sector = (h % 360) as f64 / 60.0;
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+    let (r, g, b) = match sector as u64 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = v - chroma;
+    let byte = |c: f64| ((c + m) * 255.0).round() as u8;
+    (byte(r), byte(g), byte(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::protocol::{
+        decode_message, encode_message, frame_len, hmac_sha256, parse_local_key, session_key,
+        Message,
+    };
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    const LOCAL_KEY: &str = "0123456789abcdef";
+    const DEVICE_ID: &str = "bf0123456789abcdef";
+
+    /// A scripted stand-in for a device: serves one connection, answers queries with
+    /// `dps` and returns the dps of every control request it received.
+    async fn spawn_device(
+        version: ProtocolVersion,
+        dps: Value,
+    ) -> (LocalEndpoint, JoinHandle<Vec<Value>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let local_key = parse_local_key(LOCAL_KEY).unwrap();
+            let mut key = local_key;
+            let remote_nonce = [7u8; 16];
+            let mut local_nonce = [0u8; 16];
+            let mut buffer = Vec::new();
+            let mut controls = Vec::new();
+
+            loop {
+                let total = match frame_len(&buffer).unwrap() {
+                    Some(total) if buffer.len() >= total => total,
+                    _ => {
+                        let mut chunk = [0u8; 1024];
+                        let read = stream.read(&mut chunk).await.unwrap();
+                        if read == 0 {
+                            return controls;
+                        }
+                        buffer.extend_from_slice(&chunk[..read]);
+                        continue;
+                    }
+                };
+                let frame: Vec<u8> = buffer.drain(..total).collect();
+                let request = decode_message(version, &key, &frame, false).unwrap();
+
+                let reply_payload = match request.cmd {
+                    command::SESS_KEY_NEG_START => {
+                        local_nonce.copy_from_slice(&request.payload);
+                        [
+                            remote_nonce.to_vec(),
+                            hmac_sha256(&local_key, &local_nonce).to_vec(),
+                        ]
+                        .concat()
+                    }
+                    command::SESS_KEY_NEG_FINISH => {
+                        assert_eq!(request.payload, hmac_sha256(&local_key, &remote_nonce));
+                        key =
+                            session_key(version, &local_key, &local_nonce, &remote_nonce).unwrap();
+                        continue;
+                    }
+                    command::DP_QUERY | command::DP_QUERY_NEW => {
+                        json!({ "devId": DEVICE_ID, "dps": dps })
+                            .to_string()
+                            .into_bytes()
+                    }
+                    command::CONTROL | command::CONTROL_NEW => {
+                        let body: Value = serde_json::from_slice(&request.payload).unwrap();
+                        let set = body
+                            .get("dps")
+                            .or_else(|| body["data"].get("dps"))
+                            .cloned()
+                            .unwrap();
+                        controls.push(set);
+                        Vec::new()
+                    }
+                    other => panic!("Unexpected command {}", other),
+                };
+
+                let reply_cmd = match request.cmd {
+                    command::SESS_KEY_NEG_START => command::SESS_KEY_NEG_RESP,
+                    cmd => cmd,
+                };
+                let reply = Message {
+                    seq: request.seq,
+                    cmd: reply_cmd,
+                    retcode: Some(0),
+                    payload: reply_payload,
+                };
+                let frame = encode_message(version, &key, &reply).unwrap();
+                stream.write_all(&frame).await.unwrap();
+            }
+        });
+
+        let endpoint = LocalEndpoint {
+            ip: "127.0.0.1".to_string(),
+            port,
+            local_key: LOCAL_KEY.to_string(),
+            version,
+        };
+        (endpoint, handle)
+    }
+
+    fn dp_ids() -> HashMap<String, u32> {
+        HashMap::from([
+            ("switch_1".to_string(), 1),
+            ("countdown_1".to_string(), 9),
+            ("colour_data".to_string(), 5),
+            ("colour_data_v2".to_string(), 24),
+        ])
+    }
+
+    #[test]
+    fn test_colours_are_sent_as_hex() {
+        let commands = vec![
+            TuyaCommand {
+                code: "colour_data_v2".to_string(),
+                value: TuyaValue::Json(json!({ "h": 240, "s": 1000, "v": 500 })),
+            },
+            TuyaCommand {
+                code: "colour_data".to_string(),
+                value: TuyaValue::Json(json!({ "h": 120, "s": 255, "v": 128 })),
+            },
+        ];
+        let dps = commands_to_dps(&commands, &dp_ids()).unwrap();
+        assert_eq!(dps["24"], json!("00f003e801f4"));
+        assert_eq!(dps["5"], json!("0080000078ff80"));
+    }
+
+    #[tokio::test]
+    async fn test_status_against_stand_in_device() {
+        for version in [
+            ProtocolVersion::V33,
+            ProtocolVersion::V34,
+            ProtocolVersion::V35,
+        ] {
+            let (endpoint, device) =
+                spawn_device(version, json!({ "1": true, "9": 120, "38": "memory" })).await;
+
+            let dps = fetch_dps(&endpoint, DEVICE_ID).await.unwrap();
+            let status = dps_to_status(&dps, &dp_ids());
+
+            assert_eq!(status.len(), 2, "protocol {}", version);
+            assert_eq!(status[0].code, "switch_1");
+            assert_eq!(status[0].value, TuyaValue::Boolean(true));
+            assert_eq!(status[1].value, TuyaValue::Integer(120));
+            device.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_control_against_stand_in_device() {
+        for version in [
+            ProtocolVersion::V33,
+            ProtocolVersion::V34,
+            ProtocolVersion::V35,
+        ] {
+            let (endpoint, device) = spawn_device(version, json!({})).await;
+
+            let commands = vec![TuyaCommand {
+                code: "switch_1".to_string(),
+                value: TuyaValue::Boolean(false),
+            }];
+            let dps = commands_to_dps(&commands, &dp_ids()).unwrap();
+            set_dps(&endpoint, DEVICE_ID, dps).await.unwrap();
+
+            assert_eq!(device.await.unwrap(), vec![json!({ "1": false })]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wrong_local_key_fails_negotiation() {
+        let (mut endpoint, device) = spawn_device(ProtocolVersion::V34, json!({})).await;
+        endpoint.local_key = "fedcba9876543210".to_string();
+
+        assert!(fetch_dps(&endpoint, DEVICE_ID).await.is_err());
+        device.abort();
+    }
+
+    #[tokio::test]
+    async fn test_only_connection_failures_make_a_device_unreachable() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = LocalEndpoint {
+            ip: "127.0.0.1".to_string(),
+            port: closed.local_addr().unwrap().port(),
+            local_key: LOCAL_KEY.to_string(),
+            version: ProtocolVersion::V33,
+        };
+        drop(closed);
+        let refused = fetch_dps(&endpoint, DEVICE_ID).await.unwrap_err();
+        assert!(is_unreachable(&refused));
+
+        // A device answering with something other than a Tuya frame is there
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let device = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(&[0xde, 0xad, 0xbe, 0xef]).await.unwrap();
+            let _ = stream.read(&mut [0u8; 1024]).await;
+        });
+        let rejected = fetch_dps(&LocalEndpoint { port, ..endpoint }, DEVICE_ID)
+            .await
+            .unwrap_err();
+        assert!(!is_unreachable(&rejected));
+        device.abort();
+    }
+
+    #[tokio::test]
+    async fn test_only_discovered_devices_are_registered() {
+        let discovered = create_discovered_devices();
+        let local = LocalDevices::with_discovered(discovered.clone());
+        let mut devices: Vec<TuyaDevice> = ["heard", "silent", "old"]
+            .into_iter()
+            .map(|id| {
+                serde_json::from_value(json!({
+                    "id": id,
+                    "name": id,
+                    "online": true,
+                    "category": "cz",
+                    "product_id": "",
+                    "product_name": "",
+                    "local_key": LOCAL_KEY,
+                    "sub": false,
+                    "uuid": "",
+                    "owner_id": "",
+                    "ip": "192.168.1.20",
+                    "time_zone": "",
+                    "create_time": 0,
+                    "update_time": 0,
+                    "active_time": 0
+                }))
+                .unwrap()
+            })
+            .collect();
+        let heard = |id: &str, version: &str| DiscoveredDevice {
+            gw_id: id.to_string(),
+            ip: "192.168.1.30".to_string(),
+            version: version.to_string(),
+            product_key: String::new(),
+        };
+        local
+            .merge_discovered(HashMap::from([
+                ("heard".to_string(), heard("heard", "3.4")),
+                ("old".to_string(), heard("old", "3.1")),
+            ]))
+            .await;
+
+        local.register_devices(&mut devices).await;
+
+        let endpoint = local.reachable("heard").await.unwrap();
+        assert_eq!(
+            (endpoint.ip.as_str(), endpoint.version),
+            ("192.168.1.30", ProtocolVersion::V34)
+        );
+        assert!(local.reachable("silent").await.is_none());
+        assert!(local.reachable("old").await.is_none());
+
+        // A replacement client keeps what was heard before it
+        let replacement = LocalDevices::with_discovered(discovered);
+        replacement.register_devices(&mut devices).await;
+        assert!(replacement.reachable("heard").await.is_some());
+    }
+
+    #[test]
+    fn test_lan_address_detection() {
+        assert!(is_lan_address("192.168.1.20"));
+        assert!(is_lan_address("10.0.0.4"));
+        assert!(!is_lan_address("203.0.113.9"));
+        assert!(!is_lan_address(""));
+    }
+}
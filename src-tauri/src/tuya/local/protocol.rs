@@ -0,0 +1,498 @@
+use aes::cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes128;
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes128Gcm, Nonce};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::error::AppError;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub type LocalKey = [u8; 16];
+
+const PREFIX_55AA: u32 = 0x0000_55AA;
+const SUFFIX_55AA: u32 = 0x0000_AA55;
+const PREFIX_6699: u32 = 0x0000_6699;
+const SUFFIX_6699: u32 = 0x0000_9966;
+
+const HEADER_LEN_55AA: usize = 16;
+const HEADER_LEN_6699: usize = 18;
+const CRC_LEN: usize = 4;
+const HMAC_LEN: usize = 32;
+const SUFFIX_LEN: usize = 4;
+const GCM_IV_LEN: usize = 12;
+const GCM_TAG_LEN: usize = 16;
+const VERSION_HEADER_LEN: usize = 15;
+const MAX_FRAME_LEN: usize = 64 * 1024;
+
+pub mod command {
+    pub const UDP: u32 = 0x00;
+    pub const SESS_KEY_NEG_START: u32 = 0x03;
+    pub const SESS_KEY_NEG_RESP: u32 = 0x04;
+    pub const SESS_KEY_NEG_FINISH: u32 = 0x05;
+    pub const CONTROL: u32 = 0x07;
+    pub const STATUS: u32 = 0x08;
+    pub const HEART_BEAT: u32 = 0x09;
+    pub const DP_QUERY: u32 = 0x0a;
+    pub const CONTROL_NEW: u32 = 0x0d;
+    pub const DP_QUERY_NEW: u32 = 0x10;
+    pub const UPDATEDPS: u32 = 0x12;
+    pub const UDP_NEW: u32 = 0x13;
+}
+
+/// Commands whose payload is sent without the "3.x" version header.
+const NO_HEADER_COMMANDS: &[u32] = &[
+    command::UDP,
+    command::UDP_NEW,
+    command::DP_QUERY,
+    command::DP_QUERY_NEW,
+    command::UPDATEDPS,
+    command::HEART_BEAT,
+    command::SESS_KEY_NEG_START,
+    command::SESS_KEY_NEG_RESP,
+    command::SESS_KEY_NEG_FINISH,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ProtocolVersion {
+    #[serde(rename = "3.3")]
+    V33,
+    #[serde(rename = "3.4")]
+    V34,
+    #[serde(rename = "3.5")]
+    V35,
+}
+
+impl ProtocolVersion {
+    pub fn parse(version: &str) -> Option<Self> {
+        match version.trim() {
+            "3.3" => Some(Self::V33),
+            "3.4" => Some(Self::V34),
+            "3.5" => Some(Self::V35),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::V33 => "3.3",
+            Self::V34 => "3.4",
+            Self::V35 => "3.5",
+        }
+    }
+
+    /// 3.4 and 3.5 negotiate a per-connection session key before any DP traffic.
+    pub fn needs_session_key(&self) -> bool {
+        !matches!(self, Self::V33)
+    }
+
+    fn header(&self) -> Vec<u8> {
+        let mut header = self.as_str().as_bytes().to_vec();
+        header.resize(VERSION_HEADER_LEN, 0);
+        header
+    }
+
+    fn strip_header<'a>(&self, data: &'a [u8]) -> &'a [u8] {
+        if data.len() >= VERSION_HEADER_LEN && data.starts_with(self.as_str().as_bytes()) {
+            &data[VERSION_HEADER_LEN..]
+        } else {
+            data
+        }
+    }
+}
+
+impl std::fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// A decoded LAN message. `payload` is always plaintext; framing and encryption are
+/// handled by `encode_message` and `decode_message`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub seq: u32,
+    pub cmd: u32,
+    pub retcode: Option<u32>,
+    pub payload: Vec<u8>,
+}
+
+pub fn parse_local_key(local_key: &str) -> Result<LocalKey, AppError> {
+    local_key
+        .as_bytes()
+        .try_into()
+        .map_err(|_| AppError::Local("Local key must be 16 characters".to_string()))
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC can take key of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+pub fn ecb_encrypt(key: &LocalKey, data: &[u8], pad: bool) -> Vec<u8> {
+    let cipher = Aes128::new(GenericArray::from_slice(key));
+    let mut buffer = data.to_vec();
+    if pad {
+        let pad_len = 16 - buffer.len() % 16;
+        buffer.extend(std::iter::repeat_n(pad_len as u8, pad_len));
+    }
+    for block in buffer.chunks_exact_mut(16) {
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+    }
+    buffer
+}
+
+pub fn ecb_decrypt(key: &LocalKey, data: &[u8], unpad: bool) -> Result<Vec<u8>, AppError> {
+    if !data.len().is_multiple_of(16) {
+        return Err(AppError::Local(format!(
+            "Encrypted payload length {} is not a multiple of 16",
+            data.len()
+        )));
+    }
+
+    let cipher = Aes128::new(GenericArray::from_slice(key));
+    let mut buffer = data.to_vec();
+    for block in buffer.chunks_exact_mut(16) {
+        cipher.decrypt_block(GenericArray::from_mut_slice(block));
+    }
+
+    if unpad {
+        let pad_len = buffer.last().copied().unwrap_or(0) as usize;
+        if pad_len == 0 || pad_len > 16 || pad_len > buffer.len() {
+            return Err(AppError::Local(
+                "Invalid padding, wrong local key?".to_string(),
+            ));
+        }
+        buffer.truncate(buffer.len() - pad_len);
+    }
+    Ok(buffer)
+}
+
+pub fn gcm_encrypt(
+    key: &LocalKey,
+    iv: &[u8],
+    aad: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>, AppError> {
+    let cipher = Aes128Gcm::new(GenericArray::from_slice(key));
+    cipher
+        .encrypt(Nonce::from_slice(iv), Payload { msg: data, aad })
+        .map_err(|_| AppError::Local("GCM encryption failed".to_string()))
+}
+
+pub fn gcm_decrypt(
+    key: &LocalKey,
+    iv: &[u8],
+    aad: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>, AppError> {
+    let cipher = Aes128Gcm::new(GenericArray::from_slice(key));
+    cipher
+        .decrypt(Nonce::from_slice(iv), Payload { msg: data, aad })
+        .map_err(|_| AppError::Local("GCM tag mismatch, wrong local key?".to_string()))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().expect("4-byte slice"))
+}
+
+/// Device replies carry a 4-byte return code ahead of the data. Tuya never documents
+/// when it is present, so like other implementations we treat a value that fits in
+/// one byte as a return code and anything else as the start of the data.
+fn split_retcode(data: &[u8], from_device: bool) -> (Option<u32>, &[u8]) {
+    if from_device && data.len() >= 4 {
+        let retcode = read_u32(data, 0);
+        if retcode & 0xFFFF_FF00 == 0 {
+            return (Some(retcode), &data[4..]);
+        }
+    }
+    (None, data)
+}
+
+/// Derives the session key once both nonces have been exchanged.
+pub fn session_key(
+    version: ProtocolVersion,
+    local_key: &LocalKey,
+    local_nonce: &[u8; 16],
+    remote_nonce: &[u8; 16],
+) -> Result<LocalKey, AppError> {
+    let mut mixed = [0u8; 16];
+    for (i, byte) in mixed.iter_mut().enumerate() {
+        *byte = local_nonce[i] ^ remote_nonce[i];
+    }
+
+    let encrypted = match version {
+        ProtocolVersion::V33 => {
+            return Err(AppError::Local(
+                "Protocol 3.3 does not use session keys".to_string(),
+            ))
+        }
+        ProtocolVersion::V34 => ecb_encrypt(local_key, &mixed, false),
+        ProtocolVersion::V35 => gcm_encrypt(local_key, &local_nonce[..GCM_IV_LEN], &[], &mixed)?,
+    };
+
+    let mut key = [0u8; 16];
+    key.copy_from_slice(&encrypted[..16]);
+    Ok(key)
+}
+
+pub fn encode_message(
+    version: ProtocolVersion,
+    key: &LocalKey,
+    message: &Message,
+) -> Result<Vec<u8>, AppError> {
+    let with_header = !NO_HEADER_COMMANDS.contains(&message.cmd);
+
+    if version == ProtocolVersion::V35 {
+        let mut plaintext = Vec::new();
+        if let Some(retcode) = message.retcode {
+            plaintext.extend_from_slice(&retcode.to_be_bytes());
+        }
+        if with_header {
+            plaintext.extend_from_slice(&version.header());
+        }
+        plaintext.extend_from_slice(&message.payload);
+
+        let iv = &uuid::Uuid::new_v4().into_bytes()[..GCM_IV_LEN];
+        let length = GCM_IV_LEN + plaintext.len() + GCM_TAG_LEN;
+
+        let mut frame = Vec::with_capacity(HEADER_LEN_6699 + length + SUFFIX_LEN);
+        frame.extend_from_slice(&PREFIX_6699.to_be_bytes());
+        frame.extend_from_slice(&0u16.to_be_bytes());
+        frame.extend_from_slice(&message.seq.to_be_bytes());
+        frame.extend_from_slice(&message.cmd.to_be_bytes());
+        frame.extend_from_slice(&(length as u32).to_be_bytes());
+        let ciphertext = gcm_encrypt(key, iv, &frame[4..HEADER_LEN_6699], &plaintext)?;
+        frame.extend_from_slice(iv);
+        frame.extend_from_slice(&ciphertext);
+        frame.extend_from_slice(&SUFFIX_6699.to_be_bytes());
+        return Ok(frame);
+    }
+
+    // Empty payloads (e.g. control acknowledgements) are sent as-is
+    let body = match version {
+        _ if message.payload.is_empty() => Vec::new(),
+        ProtocolVersion::V33 => {
+            let encrypted = ecb_encrypt(key, &message.payload, true);
+            if with_header {
+                [version.header(), encrypted].concat()
+            } else {
+                encrypted
+            }
+        }
+        _ => {
+            let plaintext = if with_header {
+                [version.header(), message.payload.clone()].concat()
+            } else {
+                message.payload.clone()
+            };
+            ecb_encrypt(key, &plaintext, true)
+        }
+    };
+
+    let trailer_len = if version == ProtocolVersion::V33 {
+        CRC_LEN
+    } else {
+        HMAC_LEN
+    };
+    let retcode_len = if message.retcode.is_some() { 4 } else { 0 };
+    let length = retcode_len + body.len() + trailer_len + SUFFIX_LEN;
+
+    let mut frame = Vec::with_capacity(HEADER_LEN_55AA + length);
+    frame.extend_from_slice(&PREFIX_55AA.to_be_bytes());
+    frame.extend_from_slice(&message.seq.to_be_bytes());
+    frame.extend_from_slice(&message.cmd.to_be_bytes());
+    frame.extend_from_slice(&(length as u32).to_be_bytes());
+    if let Some(retcode) = message.retcode {
+        frame.extend_from_slice(&retcode.to_be_bytes());
+    }
+    frame.extend_from_slice(&body);
+    if version == ProtocolVersion::V33 {
+        frame.extend_from_slice(&crc32fast::hash(&frame).to_be_bytes());
+    } else {
+        frame.extend_from_slice(&hmac_sha256(key, &frame));
+    }
+    frame.extend_from_slice(&SUFFIX_55AA.to_be_bytes());
+    Ok(frame)
+}
+
+/// Returns the total length of the frame at the start of `bytes`, or None if more
+/// bytes are needed to tell.
+pub fn frame_len(bytes: &[u8]) -> Result<Option<usize>, AppError> {
+    if bytes.len() < 4 {
+        return Ok(None);
+    }
+
+    let (header_len, length_offset, extra) = match read_u32(bytes, 0) {
+        PREFIX_55AA => (HEADER_LEN_55AA, 12, 0),
+        PREFIX_6699 => (HEADER_LEN_6699, 14, SUFFIX_LEN),
+        prefix => {
+            return Err(AppError::Local(format!(
+                "Unexpected frame prefix {:#010x}",
+                prefix
+            )))
+        }
+    };
+
+    if bytes.len() < header_len {
+        return Ok(None);
+    }
+
+    let total = header_len + read_u32(bytes, length_offset) as usize + extra;
+    if total > MAX_FRAME_LEN {
+        return Err(AppError::Local(format!("Frame too large: {} bytes", total)));
+    }
+    Ok(Some(total))
+}
+
+/// Decodes one complete frame. `from_device` enables return code detection, which only
+/// device-originated messages carry.
+pub fn decode_message(
+    version: ProtocolVersion,
+    key: &LocalKey,
+    frame: &[u8],
+    from_device: bool,
+) -> Result<Message, AppError> {
+    let total = frame_len(frame)?
+        .filter(|total| *total == frame.len())
+        .ok_or_else(|| AppError::Local("Truncated frame".to_string()))?;
+
+    if version == ProtocolVersion::V35 {
+        if read_u32(frame, 0) != PREFIX_6699 || read_u32(frame, total - SUFFIX_LEN) != SUFFIX_6699 {
+            return Err(AppError::Local("Expected a 3.5 frame".to_string()));
+        }
+        if total < HEADER_LEN_6699 + GCM_IV_LEN + GCM_TAG_LEN + SUFFIX_LEN {
+            return Err(AppError::Local("Frame too short".to_string()));
+        }
+
+        let iv = &frame[HEADER_LEN_6699..HEADER_LEN_6699 + GCM_IV_LEN];
+        let ciphertext = &frame[HEADER_LEN_6699 + GCM_IV_LEN..total - SUFFIX_LEN];
+        let plaintext = gcm_decrypt(key, iv, &frame[4..HEADER_LEN_6699], ciphertext)?;
+        let (retcode, data) = split_retcode(&plaintext, from_device);
+
+        return Ok(Message {
+            seq: read_u32(frame, 6),
+            cmd: read_u32(frame, 10),
+            retcode,
+            payload: version.strip_header(data).to_vec(),
+        });
+    }
+
+    if read_u32(frame, 0) != PREFIX_55AA || read_u32(frame, total - SUFFIX_LEN) != SUFFIX_55AA {
+        return Err(AppError::Local(format!("Expected a {} frame", version)));
+    }
+
+    let trailer_len = if version == ProtocolVersion::V33 {
+        CRC_LEN
+    } else {
+        HMAC_LEN
+    };
+    if total < HEADER_LEN_55AA + trailer_len + SUFFIX_LEN {
+        return Err(AppError::Local("Frame too short".to_string()));
+    }
+
+    let signed_end = total - trailer_len - SUFFIX_LEN;
+    let trailer = &frame[signed_end..total - SUFFIX_LEN];
+    let valid = if version == ProtocolVersion::V33 {
+        crc32fast::hash(&frame[..signed_end]).to_be_bytes() == trailer
+    } else {
+        hmac_sha256(key, &frame[..signed_end]) == trailer
+    };
+    if !valid {
+        return Err(AppError::Local(
+            "Frame checksum mismatch, wrong local key?".to_string(),
+        ));
+    }
+
+    let (retcode, data) = split_retcode(&frame[HEADER_LEN_55AA..signed_end], from_device);
+    let payload = match version {
+        _ if data.is_empty() => Vec::new(),
+        ProtocolVersion::V33 => {
+            let encrypted = version.strip_header(data);
+            if encrypted.is_empty() {
+                Vec::new()
+            } else {
+                ecb_decrypt(key, encrypted, true)?
+            }
+        }
+        _ => version
+            .strip_header(&ecb_decrypt(key, data, true)?)
+            .to_vec(),
+    };
+
+    Ok(Message {
+        seq: read_u32(frame, 4),
+        cmd: read_u32(frame, 8),
+        retcode,
+        payload,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &LocalKey = b"0123456789abcdef";
+
+    fn control_message() -> Message {
+        Message {
+            seq: 7,
+            cmd: command::CONTROL,
+            retcode: None,
+            payload: br#"{"dps":{"1":true}}"#.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_round_trip_all_versions() {
+        for version in [
+            ProtocolVersion::V33,
+            ProtocolVersion::V34,
+            ProtocolVersion::V35,
+        ] {
+            let message = control_message();
+            let frame = encode_message(version, KEY, &message).unwrap();
+            assert_eq!(frame_len(&frame).unwrap(), Some(frame.len()));
+            assert_eq!(
+                decode_message(version, KEY, &frame, false).unwrap(),
+                message
+            );
+
+            let reply = Message {
+                retcode: Some(0),
+                ..message
+            };
+            let frame = encode_message(version, KEY, &reply).unwrap();
+            assert_eq!(decode_message(version, KEY, &frame, true).unwrap(), reply);
+        }
+    }
+
+    #[test]
+    fn test_v33_control_has_cleartext_version_header() {
+        let frame = encode_message(ProtocolVersion::V33, KEY, &control_message()).unwrap();
+        assert_eq!(&frame[16..19], b"3.3");
+    }
+
+    #[test]
+    fn test_wrong_key_is_rejected() {
+        let other: &LocalKey = b"fedcba9876543210";
+        for version in [ProtocolVersion::V34, ProtocolVersion::V35] {
+            let frame = encode_message(version, KEY, &control_message()).unwrap();
+            assert!(decode_message(version, other, &frame, false).is_err());
+        }
+    }
+
+    #[test]
+    fn test_session_key_derivation() {
+        let local_nonce = [1u8; 16];
+        let remote_nonce = [2u8; 16];
+        let v34 = session_key(ProtocolVersion::V34, KEY, &local_nonce, &remote_nonce).unwrap();
+        let v35 = session_key(ProtocolVersion::V35, KEY, &local_nonce, &remote_nonce).unwrap();
+        assert_ne!(v34, v35);
+        assert_ne!(&v34, KEY);
+        assert!(session_key(ProtocolVersion::V33, KEY, &local_nonce, &remote_nonce).is_err());
+    }
+}
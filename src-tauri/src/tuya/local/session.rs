@@ -0,0 +1,179 @@
+use std::io::{self, ErrorKind};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use super::protocol::{
+    command, decode_message, encode_message, frame_len, hmac_sha256, parse_local_key, session_key,
+    LocalKey, Message, ProtocolVersion,
+};
+use super::LocalEndpoint;
+use crate::error::AppError;
+
+const CONNECT_TIMEOUT_MS: u64 = 2000;
+const RESPONSE_TIMEOUT_MS: u64 = 3000;
+
+/// One TCP connection to a device, with the session key already negotiated for 3.4/3.5.
+pub struct LocalSession {
+    stream: TcpStream,
+    version: ProtocolVersion,
+    key: LocalKey,
+    seq: u32,
+    buffer: Vec<u8>,
+}
+
+impl LocalSession {
+    pub async fn connect(endpoint: &LocalEndpoint) -> Result<Self, AppError> {
+        let local_key = parse_local_key(&endpoint.local_key)?;
+
+        let stream = timeout(
+            Duration::from_millis(CONNECT_TIMEOUT_MS),
+            TcpStream::connect((endpoint.ip.as_str(), endpoint.port)),
+        )
+        .await
+        .map_err(|_| {
+            transport_error(
+                ErrorKind::TimedOut,
+                format!("Timed out connecting to {}", endpoint.ip),
+            )
+        })?
+        .map_err(|e| {
+            transport_error(
+                e.kind(),
+                format!("Failed to connect to {}: {}", endpoint.ip, e),
+            )
+        })?;
+        stream.set_nodelay(true).ok();
+
+        let mut session = Self {
+            stream,
+            version: endpoint.version,
+            key: local_key,
+            seq: 0,
+            buffer: Vec::new(),
+        };
+
+        if endpoint.version.needs_session_key() {
+            session.negotiate().await?;
+        }
+
+        Ok(session)
+    }
+
+    async fn negotiate(&mut self) -> Result<(), AppError> {
+        let local_key = self.key;
+        let local_nonce = uuid::Uuid::new_v4().into_bytes();
+
+        let response = self
+            .exchange(
+                command::SESS_KEY_NEG_START,
+                local_nonce.to_vec(),
+                &[command::SESS_KEY_NEG_RESP],
+            )
+            .await?;
+
+        if response.payload.len() < 48 {
+            return Err(AppError::Local(
+                "Session key negotiation response too short".to_string(),
+            ));
+        }
+        let mut remote_nonce = [0u8; 16];
+        remote_nonce.copy_from_slice(&response.payload[..16]);
+        if response.payload[16..48] != hmac_sha256(&local_key, &local_nonce) {
+            return Err(AppError::Local(
+                "Session key negotiation failed, wrong local key?".to_string(),
+            ));
+        }
+
+        self.send(
+            command::SESS_KEY_NEG_FINISH,
+            hmac_sha256(&local_key, &remote_nonce).to_vec(),
+        )
+        .await?;
+
+        self.key = session_key(self.version, &local_key, &local_nonce, &remote_nonce)?;
+        tracing::debug!("Negotiated {} session key", self.version);
+        Ok(())
+    }
+
+    pub async fn send(&mut self, cmd: u32, payload: Vec<u8>) -> Result<(), AppError> {
+        self.seq += 1;
+        let frame = encode_message(
+            self.version,
+            &self.key,
+            &Message {
+                seq: self.seq,
+                cmd,
+                retcode: None,
+                payload,
+            },
+        )?;
+
+        self.stream
+            .write_all(&frame)
+            .await
+            .map_err(|e| transport_error(e.kind(), format!("Failed to send to device: {}", e)))
+    }
+
+    /// Reads frames until one with an expected command arrives. Heartbeats and
+    /// unsolicited status pushes in between are skipped.
+    pub async fn receive(&mut self, expected: &[u32]) -> Result<Message, AppError> {
+        loop {
+            if let Some(total) = frame_len(&self.buffer)? {
+                if self.buffer.len() >= total {
+                    let frame: Vec<u8> = self.buffer.drain(..total).collect();
+                    let message = decode_message(self.version, &self.key, &frame, true)?;
+                    if expected.contains(&message.cmd) {
+                        return Ok(message);
+                    }
+                    tracing::debug!("Skipping local message with command {}", message.cmd);
+                    continue;
+                }
+            }
+
+            let mut chunk = [0u8; 1024];
+            let read = timeout(
+                Duration::from_millis(RESPONSE_TIMEOUT_MS),
+                self.stream.read(&mut chunk),
+            )
+            .await
+            .map_err(|_| transport_error(ErrorKind::TimedOut, "Timed out waiting for device"))?
+            .map_err(|e| transport_error(e.kind(), format!("Failed to read from device: {}", e)))?;
+
+            if read == 0 {
+                return Err(transport_error(
+                    ErrorKind::UnexpectedEof,
+                    "Connection closed by device",
+                ));
+            }
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    pub async fn exchange(
+        &mut self,
+        cmd: u32,
+        payload: Vec<u8>,
+        expected: &[u32],
+    ) -> Result<Message, AppError> {
+        self.send(cmd, payload).await?;
+        let response = self.receive(expected).await?;
+
+        match response.retcode {
+            Some(code) if code != 0 => Err(AppError::Local(format!(
+                "Device returned error {}: {}",
+                code,
+                String::from_utf8_lossy(&response.payload)
+            ))),
+            _ => Ok(response),
+        }
+    }
+}
+
+/// Connection failures are I/O errors, so they can be told from a device that answered
+/// and rejected the request.
+fn transport_error(kind: ErrorKind, message: impl Into<String>) -> AppError {
+    AppError::Io(io::Error::new(kind, message.into()))
+}
@@ -1,7 +1,14 @@
 pub mod auth;
+pub mod backend;
 pub mod client;
+pub mod events;
+pub mod local;
 pub mod token;
 pub mod types;
 
-pub use client::{create_shared_client, initialize_client, SharedTuyaClient};
+pub use backend::DeviceBackend;
+pub use client::{
+    create_shared_client, current_client, initialize_client, SharedTuyaClient, StatusResults,
+    TuyaClient,
+};
 pub use types::*;
@@ -1,4 +1,4 @@
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
 
 pub const TOKEN_REFRESH_BUFFER_SECS: i64 = 300;
 
@@ -37,6 +37,10 @@ pub enum TuyaValue {
     String(String),
     Integer(i64),
     Float(f64),
+    /// Structured DPs such as `colour_data_v2` (`{"h": 240, "s": 1000, "v": 1000}`),
+    /// `scene_data` or `cycle_timing`. Also catches anything else a device reports, so
+    /// one odd DP doesn't fail the whole status response.
+    Json(serde_json::Value),
 }
 
 impl TuyaValue {
@@ -70,6 +74,11 @@ impl TuyaValue {
             _ => None,
         }
     }
+
+    /// Pairs a raw integer DP with its spec, so 235 with scale 1 reads as 23.5.
+    pub fn as_scaled(&self, schema: &FunctionSchema) -> Option<ScaledValue> {
+        ScaledValue::new(self.as_i64()?, schema)
+    }
 }
 
 impl std::fmt::Display for TuyaValue {
@@ -79,11 +88,12 @@ impl std::fmt::Display for TuyaValue {
             TuyaValue::String(v) => write!(f, "{}", v),
             TuyaValue::Integer(v) => write!(f, "{}", v),
             TuyaValue::Float(v) => write!(f, "{}", v),
+            TuyaValue::Json(v) => write!(f, "{}", v),
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TuyaCommand {
     pub code: String,
     pub value: TuyaValue,
@@ -94,6 +104,137 @@ pub struct TuyaCommandPayload {
     pub commands: Vec<TuyaCommand>,
 }
 
+/// One device's entry in the batch status response.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceStatusEntry {
+    pub id: String,
+    pub status: Vec<TuyaDeviceStatus>,
+}
+
+/// Thing-model shadow properties, used for the code to DP id mapping of local control.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShadowProperties {
+    pub properties: Vec<ShadowProperty>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShadowProperty {
+    pub code: String,
+    pub dp_id: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TuyaHome {
+    pub home_id: i64,
+    pub name: String,
+    #[serde(default)]
+    pub geo_name: String,
+    #[serde(default)]
+    pub lat: f64,
+    #[serde(default)]
+    pub lon: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TuyaScene {
+    pub scene_id: String,
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TuyaRoom {
+    pub room_id: i64,
+    pub name: String,
+    /// Not part of the rooms response; filled in from the room's device list.
+    #[serde(default)]
+    pub device_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HomeRooms {
+    #[serde(default)]
+    pub rooms: Vec<TuyaRoom>,
+}
+
+/// Entries of a room's device list; only the id is needed to place devices.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoomDevice {
+    pub id: String,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Tuya's IR category id for air conditioners.
+const IR_AC_CATEGORY_ID: i64 = 5;
+
+/// A remote set up on an IR hub. Remotes also appear in the device list as
+/// sub-devices, with `remote_id` as their device id, but without status DPs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TuyaRemote {
+    pub remote_id: String,
+    pub remote_name: String,
+    pub category_id: i64,
+    #[serde(default)]
+    pub brand_name: String,
+}
+
+impl TuyaRemote {
+    pub fn is_air_conditioner(&self) -> bool {
+        self.category_id == IR_AC_CATEGORY_ID
+    }
+}
+
+/// The state of an IR air conditioner. IR is one-way, so this is what was last sent
+/// rather than what the unit does, and it is always sent whole, as a remote would.
+/// `mode` is 0 cool, 1 heat, 2 auto, 3 fan and 4 dry; `wind` is 0 auto, then 1-3 from
+/// low to high.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AcScene {
+    #[serde(deserialize_with = "lenient_integer")]
+    pub power: i64,
+    #[serde(deserialize_with = "lenient_integer")]
+    pub mode: i64,
+    #[serde(deserialize_with = "lenient_integer")]
+    pub temp: i64,
+    #[serde(deserialize_with = "lenient_integer")]
+    pub wind: i64,
+}
+
+impl Default for AcScene {
+    fn default() -> Self {
+        Self {
+            power: 0,
+            mode: 2,
+            temp: 24,
+            wind: 0,
+        }
+    }
+}
+
+/// The AC status endpoint reports numbers as strings.
+fn lenient_integer<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Number(n) => n
+            .as_i64()
+            .ok_or_else(|| de::Error::custom(format!("{} is not an integer", n))),
+        serde_json::Value::String(s) => s.trim().parse().map_err(de::Error::custom),
+        other => Err(de::Error::custom(format!("{} is not an integer", other))),
+    }
+}
+
+/// A key an IR hub learned from the original remote, sent back as its raw code.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LearnedCode {
+    pub id: i64,
+    #[serde(default)]
+    pub key_name: String,
+    pub code: String,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct TuyaApiResponse<T> {
     pub success: bool,
@@ -123,11 +264,377 @@ impl TokenState {
     }
 }
 
-pub const AC_MODES: &[&str] = &["auto", "cold", "dry", "wind"];
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeviceSpecification {
+    #[serde(default)]
+    pub category: String,
+    #[serde(default)]
+    pub functions: Vec<DeviceFunction>,
+    #[serde(default)]
+    pub status: Vec<DeviceFunction>,
+}
 
-pub const FAN_SPEED_LEVELS: i32 = 5;
+impl DeviceSpecification {
+    /// Returns the schema of a writable DP, or None if the code is read-only or unknown.
+    pub fn function(&self, code: &str) -> Option<&FunctionSchema> {
+        self.functions
+            .iter()
+            .find(|f| f.code == code)
+            .map(|f| &f.schema)
+    }
 
-pub const AC_FAN_SPEED_LEVELS: i32 = 4;
+    /// Returns the schema of any DP the device has, including read-only status DPs.
+    pub fn schema(&self, code: &str) -> Option<&FunctionSchema> {
+        self.function(code).or_else(|| {
+            self.status
+                .iter()
+                .find(|f| f.code == code)
+                .map(|f| &f.schema)
+        })
+    }
+}
 
-pub const TEMP_MIN: i32 = 16;
-pub const TEMP_MAX: i32 = 30;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "RawDeviceFunction", into = "RawDeviceFunction")]
+pub struct DeviceFunction {
+    pub code: String,
+    pub schema: FunctionSchema,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type")]
+pub enum FunctionSchema {
+    Boolean,
+    Enum {
+        range: Vec<String>,
+    },
+    Integer {
+        min: i64,
+        max: i64,
+        step: i64,
+        scale: u32,
+        unit: String,
+    },
+    String,
+    Json,
+}
+
+/// Wire format of a spec entry: `values` is a JSON document encoded as a string. Specs
+/// are written back with `values` as an object, which reads back the same way.
+#[derive(Debug, Serialize, Deserialize)]
+struct RawDeviceFunction {
+    code: String,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    values: serde_json::Value,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RawFunctionValues {
+    #[serde(default)]
+    range: Vec<String>,
+    #[serde(default)]
+    min: i64,
+    #[serde(default)]
+    max: i64,
+    #[serde(default = "default_step")]
+    step: i64,
+    #[serde(default)]
+    scale: u32,
+    #[serde(default)]
+    unit: String,
+}
+
+fn default_step() -> i64 {
+    1
+}
+
+impl TryFrom<RawDeviceFunction> for DeviceFunction {
+    type Error = String;
+
+    fn try_from(raw: RawDeviceFunction) -> Result<Self, Self::Error> {
+        let values: RawFunctionValues = match raw.values {
+            serde_json::Value::String(ref s) if !s.is_empty() => serde_json::from_str(s)
+                .map_err(|e| format!("Invalid values for {}: {}", raw.code, e))?,
+            serde_json::Value::Object(_) => serde_json::from_value(raw.values.clone())
+                .map_err(|e| format!("Invalid values for {}: {}", raw.code, e))?,
+            _ => RawFunctionValues::default(),
+        };
+
+        let schema = match raw.kind.as_str() {
+            "Boolean" => FunctionSchema::Boolean,
+            "Enum" => FunctionSchema::Enum {
+                range: values.range,
+            },
+            "Integer" => FunctionSchema::Integer {
+                min: values.min,
+                max: values.max,
+                step: values.step.max(1),
+                scale: values.scale,
+                unit: values.unit,
+            },
+            "String" | "Raw" => FunctionSchema::String,
+            _ => FunctionSchema::Json,
+        };
+
+        Ok(DeviceFunction {
+            code: raw.code,
+            schema,
+        })
+    }
+}
+
+impl From<DeviceFunction> for RawDeviceFunction {
+    fn from(function: DeviceFunction) -> Self {
+        let (kind, values) = match function.schema {
+            FunctionSchema::Boolean => ("Boolean", serde_json::json!({})),
+            FunctionSchema::Enum { range } => ("Enum", serde_json::json!({ "range": range })),
+            FunctionSchema::Integer {
+                min,
+                max,
+                step,
+                scale,
+                unit,
+            } => (
+                "Integer",
+                serde_json::json!({
+                    "min": min,
+                    "max": max,
+                    "step": step,
+                    "scale": scale,
+                    "unit": unit,
+                }),
+            ),
+            FunctionSchema::String => ("String", serde_json::json!({})),
+            FunctionSchema::Json => ("Json", serde_json::json!({})),
+        };
+        RawDeviceFunction {
+            code: function.code,
+            kind: kind.to_string(),
+            values,
+        }
+    }
+}
+
+impl FunctionSchema {
+    /// Converts a menu value string back into the DP type the device expects.
+    pub fn parse_value(&self, value_str: &str) -> Option<TuyaValue> {
+        match self {
+            FunctionSchema::Boolean => value_str.parse().ok().map(TuyaValue::Boolean),
+            FunctionSchema::Enum { .. } | FunctionSchema::String => {
+                Some(TuyaValue::String(value_str.to_string()))
+            }
+            FunctionSchema::Integer { .. } => value_str.parse().ok().map(TuyaValue::Integer),
+            FunctionSchema::Json => None,
+        }
+    }
+}
+
+/// A raw integer DP value with the scale, step and unit of its Integer schema.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScaledValue {
+    pub raw: i64,
+    pub scale: u32,
+    pub step: i64,
+    pub unit: String,
+}
+
+impl ScaledValue {
+    pub fn new(raw: i64, schema: &FunctionSchema) -> Option<Self> {
+        match schema {
+            FunctionSchema::Integer {
+                step, scale, unit, ..
+            } => Some(Self {
+                raw,
+                scale: *scale,
+                step: *step,
+                unit: unit.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Converts a human-readable value (e.g. 23.5) into the raw DP integer, snapped
+    /// to the schema's step and clamped to its range.
+    pub fn from_display(value: f64, schema: &FunctionSchema) -> Option<Self> {
+        let FunctionSchema::Integer {
+            min,
+            max,
+            step,
+            scale,
+            ..
+        } = schema
+        else {
+            return None;
+        };
+
+        let raw = (value * 10f64.powi(*scale as i32)).round() as i64;
+        let snapped = min + ((raw - min) as f64 / *step as f64).round() as i64 * step;
+        Self::new(snapped.clamp(*min, *max), schema)
+    }
+
+    pub fn value(&self) -> f64 {
+        self.raw as f64 / 10f64.powi(self.scale as i32)
+    }
+
+    /// Returns whichever of the given raw options lies closest to this value.
+    pub fn nearest(&self, options: &[i64]) -> Option<i64> {
+        options
+            .iter()
+            .copied()
+            .min_by_key(|option| (option - self.raw).abs())
+    }
+}
+
+impl std::fmt::Display for ScaledValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.*}{}", self.scale as usize, self.value(), self.unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_specification_parses_string_encoded_values() {
+        let json = r#"{
+            "category": "kt",
+            "functions": [
+                {"code": "switch", "type": "Boolean", "values": "{}"},
+                {"code": "mode", "type": "Enum", "values": "{\"range\":[\"cold\",\"wind\"]}"},
+                {"code": "temp_set", "type": "Integer", "values": "{\"unit\":\"℃\",\"min\":160,\"max\":300,\"scale\":1,\"step\":5}"},
+                {"code": "fault", "type": "Bitmap", "values": "{\"label\":[\"e1\"]}"}
+            ],
+            "status": []
+        }"#;
+
+        let spec: DeviceSpecification = serde_json::from_str(json).unwrap();
+        assert_eq!(spec.function("switch"), Some(&FunctionSchema::Boolean));
+        assert_eq!(
+            spec.function("mode"),
+            Some(&FunctionSchema::Enum {
+                range: vec!["cold".to_string(), "wind".to_string()]
+            })
+        );
+        assert_eq!(
+            spec.function("temp_set"),
+            Some(&FunctionSchema::Integer {
+                min: 160,
+                max: 300,
+                step: 5,
+                scale: 1,
+                unit: "℃".to_string()
+            })
+        );
+        assert_eq!(spec.function("fault"), Some(&FunctionSchema::Json));
+        assert_eq!(spec.function("missing"), None);
+
+        let reparsed: DeviceSpecification =
+            serde_json::from_value(serde_json::to_value(&spec).unwrap()).unwrap();
+        for code in ["switch", "mode", "temp_set", "fault"] {
+            assert_eq!(reparsed.function(code), spec.function(code));
+        }
+    }
+
+    #[test]
+    fn test_schema_parse_value_uses_dp_type() {
+        let enum_schema = FunctionSchema::Enum {
+            range: vec!["1".to_string(), "2".to_string()],
+        };
+        assert_eq!(
+            enum_schema.parse_value("2"),
+            Some(TuyaValue::String("2".to_string()))
+        );
+        assert_eq!(
+            FunctionSchema::Boolean.parse_value("true"),
+            Some(TuyaValue::Boolean(true))
+        );
+        assert_eq!(FunctionSchema::Json.parse_value("{}"), None);
+    }
+
+    fn thermostat_schema() -> FunctionSchema {
+        FunctionSchema::Integer {
+            min: 50,
+            max: 350,
+            step: 5,
+            scale: 1,
+            unit: "℃".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_scaled_value_formats_with_scale_and_unit() {
+        let value = TuyaValue::Integer(235)
+            .as_scaled(&thermostat_schema())
+            .unwrap();
+        assert_eq!(value.value(), 23.5);
+        assert_eq!(value.to_string(), "23.5℃");
+        assert_eq!(value.nearest(&[200, 230, 240]), Some(230));
+    }
+
+    #[test]
+    fn test_scaled_value_from_display_snaps_and_clamps() {
+        let schema = thermostat_schema();
+        assert_eq!(ScaledValue::from_display(23.5, &schema).unwrap().raw, 235);
+        assert_eq!(ScaledValue::from_display(23.4, &schema).unwrap().raw, 235);
+        assert_eq!(ScaledValue::from_display(99.0, &schema).unwrap().raw, 350);
+        assert!(ScaledValue::from_display(1.0, &FunctionSchema::Boolean).is_none());
+    }
+
+    #[test]
+    fn test_ac_status_parses_string_numbers() {
+        let scene: AcScene =
+            serde_json::from_str(r#"{"power":"1","mode":"0","temp":"26","wind":2}"#).unwrap();
+        assert_eq!(
+            scene,
+            AcScene {
+                power: 1,
+                mode: 0,
+                temp: 26,
+                wind: 2
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&scene).unwrap(),
+            r#"{"power":1,"mode":0,"temp":26,"wind":2}"#
+        );
+        assert!(
+            serde_json::from_str::<AcScene>(r#"{"power":"on","mode":0,"temp":26,"wind":0}"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_structured_values_round_trip() {
+        let json = r#"[
+            {"code": "switch_led", "value": true},
+            {"code": "colour_data_v2", "value": {"h": 240, "s": 1000, "v": 500}},
+            {"code": "cycle_timing", "value": [{"start": "08:00", "end": "18:00"}]},
+            {"code": "scene_data", "value": null}
+        ]"#;
+
+        let status: Vec<TuyaDeviceStatus> = serde_json::from_str(json).unwrap();
+        assert_eq!(status[0].value, TuyaValue::Boolean(true));
+        assert_eq!(
+            status[1].value,
+            TuyaValue::Json(serde_json::json!({"h": 240, "s": 1000, "v": 500}))
+        );
+        assert_eq!(
+            status[2].value.to_string(),
+            r#"[{"end":"18:00","start":"08:00"}]"#
+        );
+
+        let command = TuyaCommand {
+            code: status[1].code.clone(),
+            value: status[1].value.clone(),
+        };
+        let sent = serde_json::to_string(&command).unwrap();
+        assert_eq!(
+            sent,
+            r#"{"code":"colour_data_v2","value":{"h":240,"s":1000,"v":500}}"#
+        );
+        assert_eq!(serde_json::from_str::<TuyaCommand>(&sent).unwrap(), command);
+    }
+}